@@ -0,0 +1,219 @@
+//! An aggregated DM call tree built from every exec_proc call, for exports that don't need Tracy.
+//!
+//! Each node is a callstack as procdef indices, outermost first. A proc that sleeps is timed up to the
+//! point it sleeps, and the resumed part is recorded under the shorter stack it resumes on.
+//!
+//! Every thread builds its own tree, so timing a call only takes that thread's uncontended lock. The
+//! trees are merged when they're read.
+
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+/// The parent of outermost calls.
+const ROOT: usize = usize::MAX;
+
+static TREES: Mutex<Vec<Arc<Mutex<ThreadTree>>>> = Mutex::new(Vec::new());
+
+thread_local! {
+    static TREE: Arc<Mutex<ThreadTree>> = {
+        let tree = Arc::new(Mutex::new(ThreadTree::default()));
+        lock(&TREES).push(tree.clone());
+        tree
+    };
+
+    // Node and time spent in callees of each call being timed on this thread, innermost last
+    static OPEN_CALLS: RefCell<Vec<(usize, Duration)>> = const { RefCell::new(Vec::new()) };
+}
+
+#[derive(Clone, Copy, Default)]
+pub(crate) struct NodeStats {
+    pub calls: u64,
+    /// Time spent in the node's proc itself, excluding the procs it called
+    pub self_time: Duration,
+}
+
+#[derive(Clone)]
+struct Node {
+    parent: usize,
+    procdef: usize,
+    stats: NodeStats,
+}
+
+/// One thread's calls. Nodes are found by parent and procdef, so a call only allocates the first
+/// time its stack is seen. Parents always come before their children.
+#[derive(Default)]
+struct ThreadTree {
+    nodes: Vec<Node>,
+    children: HashMap<(usize, usize), usize>,
+}
+
+impl ThreadTree {
+    fn child(&mut self, parent: usize, procdef: usize) -> usize {
+        *self.children.entry((parent, procdef)).or_insert_with(|| {
+            self.nodes.push(Node {
+                parent,
+                procdef,
+                stats: NodeStats::default(),
+            });
+            self.nodes.len() - 1
+        })
+    }
+}
+
+pub(crate) struct CallTree {
+    /// When recording began, for exports that carry a timestamp
+    pub started: SystemTime,
+}
+
+impl CallTree {
+    pub fn new() -> Self {
+        Self {
+            started: SystemTime::now(),
+        }
+    }
+
+    /// Runs a whole exec_proc hook, which calls time for the proc it hooks. Its duration counts as
+    /// the calling proc's callee time, so the hook's own overhead isn't taken as the caller's self time.
+    pub fn hook<R>(&self, hook: impl FnOnce() -> R) -> R {
+        let start = Instant::now();
+
+        let result = hook();
+
+        let elapsed = start.elapsed();
+        OPEN_CALLS.with_borrow_mut(|open_calls| {
+            if let Some((_, callee_time)) = open_calls.last_mut() {
+                *callee_time += elapsed;
+            }
+        });
+
+        result
+    }
+
+    /// Runs call, which must run the proc procdef, and records it under the calls open on this thread.
+    pub fn time<R>(&self, procdef: usize, call: impl FnOnce() -> R) -> R {
+        let parent =
+            OPEN_CALLS.with_borrow(|open_calls| open_calls.last().map_or(ROOT, |open| open.0));
+        let node = TREE.with(|tree| lock(tree).child(parent, procdef));
+        OPEN_CALLS.with_borrow_mut(|open_calls| open_calls.push((node, Duration::ZERO)));
+        let start = Instant::now();
+
+        let result = call();
+
+        let total_time = start.elapsed();
+        let callee_time = OPEN_CALLS
+            .with_borrow_mut(|open_calls| open_calls.pop())
+            .map_or(Duration::ZERO, |(_, callee_time)| callee_time);
+        TREE.with(|tree| {
+            let stats = &mut lock(tree).nodes[node].stats;
+            stats.calls += 1;
+            stats.self_time += total_time.saturating_sub(callee_time);
+        });
+
+        result
+    }
+
+    /// Self time of every node in whole microseconds, as folded::write expects.
+    pub fn self_times(&self) -> HashMap<Vec<usize>, u64> {
+        self.nodes()
+            .into_iter()
+            .map(|(stack, node)| (stack, node.self_time.as_micros() as u64))
+            .collect()
+    }
+
+    /// Every node recorded so far, merged across threads.
+    pub fn nodes(&self) -> HashMap<Vec<usize>, NodeStats> {
+        let trees = lock(&TREES).clone();
+
+        let mut merged: HashMap<Vec<usize>, NodeStats> = HashMap::new();
+        for tree in trees {
+            // Copied so the thread isn't held up while its stacks are rebuilt
+            let nodes = lock(&tree).nodes.clone();
+
+            let mut stacks: Vec<Vec<usize>> = Vec::with_capacity(nodes.len());
+            for node in nodes {
+                let mut stack = match node.parent {
+                    ROOT => Vec::new(),
+                    parent => stacks[parent].clone(),
+                };
+                stack.push(node.procdef);
+
+                let merged = merged.entry(stack.clone()).or_default();
+                merged.calls += node.stats.calls;
+                merged.self_time += node.stats.self_time;
+                stacks.push(stack);
+            }
+        }
+        merged
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::CallTree;
+
+    // Every test's threads share one merged tree, so these procdefs are kept apart from other tests'
+    const CALLER: usize = 0x7001;
+    const CALLEE: usize = 0x7002;
+
+    #[test]
+    fn hook_overhead_is_left_out_of_self_time() {
+        let call_tree = CallTree::new();
+
+        for _ in 0..2 {
+            call_tree.hook(|| {
+                call_tree.time(CALLER, || {
+                    call_tree.hook(|| {
+                        // Standing in for what the hook does around the call
+                        thread::sleep(Duration::from_millis(30));
+                        call_tree.time(CALLEE, || thread::sleep(Duration::from_millis(10)));
+                    })
+                })
+            });
+        }
+
+        let nodes = call_tree.nodes();
+        let caller = nodes[&vec![CALLER]];
+        let callee = nodes[&vec![CALLER, CALLEE]];
+        assert_eq!((caller.calls, callee.calls), (2, 2));
+        assert!(
+            caller.self_time < Duration::from_millis(20),
+            "{:?}",
+            caller.self_time
+        );
+        assert!(
+            callee.self_time >= Duration::from_millis(20),
+            "{:?}",
+            callee.self_time
+        );
+        assert!(
+            callee.self_time < Duration::from_millis(60),
+            "{:?}",
+            callee.self_time
+        );
+    }
+
+    #[test]
+    fn threads_are_merged_when_read() {
+        let call_tree = CallTree::new();
+
+        thread::scope(|scope| {
+            for _ in 0..4 {
+                scope.spawn(|| call_tree.time(CALLEE, || ()));
+            }
+        });
+
+        assert_eq!(call_tree.nodes()[&vec![CALLEE]].calls, 4);
+    }
+}
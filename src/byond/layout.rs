@@ -0,0 +1,193 @@
+//! The layouts of BYOND's structs, checked at compile time. They're reverse engineered, so a field out
+//! of place would otherwise only show up as garbage names or a crash inside a hook.
+//!
+//! The x86 numbers are BYOND's own. No 64-bit build has been mapped, so the x86_64 ones only pin down
+//! the x86 layouts with pointers widened, which is what byond-tracy assumes until one is.
+
+use std::mem::{offset_of, size_of};
+
+use super::{
+    BuildNumber, Bytecode, DreamObject, DreamString, DreamStringId, Locals, Misc, Params, Proc,
+    offsets::Offsets,
+};
+
+// Procdefs are read through each build's procdefs_descriptor, since they grew twice. These are the
+// layouts the descriptors describe, and only exist to be measured
+
+#[repr(C)]
+#[allow(dead_code)]
+struct ProcDefinition {
+    path: DreamStringId,
+    name: u32,
+    desc: u32,
+    category: u32,
+    flags: u32,
+    _unknown0: u32,
+    bytecode: u32,
+    locals: u32,
+    parameters: u32,
+}
+
+/// 1617 added a field after the ones byond-tracy reads.
+#[repr(C)]
+#[allow(dead_code)]
+struct ProcDefinition1617 {
+    path: DreamStringId,
+    name: u32,
+    desc: u32,
+    category: u32,
+    flags: u32,
+    _unknown0: u32,
+    bytecode: u32,
+    locals: u32,
+    parameters: u32,
+    _unknown1: u32,
+}
+
+/// 1624 added another before bytecode.
+#[repr(C)]
+#[allow(dead_code)]
+struct ProcDefinition1624 {
+    path: DreamStringId,
+    name: u32,
+    desc: u32,
+    category: u32,
+    flags: u32,
+    _unknown0: u32,
+    _unknown1: u32,
+    bytecode: u32,
+    locals: u32,
+    parameters: u32,
+    _unknown2: u32,
+}
+
+/// A procdef layout packed as in Offsets::procdefs_descriptor.
+macro_rules! procdef_descriptor {
+    ($type:ty) => {
+        size_of::<$type>() | offset_of!($type, path) << 8 | offset_of!($type, bytecode) << 16
+    };
+}
+
+/// Each procdef layout by the first build to use it.
+const PROCDEF_LAYOUTS: [(BuildNumber, usize); 3] = [
+    (1543, procdef_descriptor!(ProcDefinition)),
+    (1617, procdef_descriptor!(ProcDefinition1617)),
+    (1624, procdef_descriptor!(ProcDefinition1624)),
+];
+
+/// Asserts a struct's size and the offsets of the given fields.
+macro_rules! assert_layout {
+    ($type:ty, $size:expr $(, $field:ident: $offset:expr)* $(,)?) => {
+        assert!(
+            size_of::<$type>() == $size,
+            concat!("Size of ", stringify!($type), " doesn't match BYOND's")
+        );
+        $(assert!(
+            offset_of!($type, $field) == $offset,
+            concat!("Offset of ", stringify!($type), "::", stringify!($field), " doesn't match BYOND's")
+        );)*
+    };
+}
+
+// Procdefs hold no pointers, so they're the same everywhere
+const _: () = {
+    assert_layout!(ProcDefinition, 0x24, path: 0x00, bytecode: 0x18);
+    assert_layout!(ProcDefinition1617, 0x28, path: 0x00, bytecode: 0x18);
+    assert_layout!(ProcDefinition1624, 0x2C, path: 0x00, bytecode: 0x1C);
+};
+
+#[cfg(target_arch = "x86")]
+const _: () = {
+    assert_layout!(DreamObject, 0x08, part_1: 0x00, part_2: 0x04);
+    assert_layout!(
+        DreamString,
+        0x1C,
+        data: 0x00,
+        id: 0x04,
+        left: 0x08,
+        right: 0x0C,
+        refcount: 0x10,
+        length: 0x18,
+    );
+    assert_layout!(Bytecode, 0x0C, length: 0x00, bytecode: 0x08);
+    assert_layout!(Locals, 0x0C, length: 0x00, locals: 0x08);
+    assert_layout!(Params, 0x0C, length: 0x00, params: 0x08);
+    assert_layout!(Misc, 0x24, bytecode: 0x00, locals: 0x0C, params: 0x18);
+    assert_layout!(
+        Proc,
+        0x34,
+        procdef: 0x00,
+        flags: 0x04,
+        supers: 0x05,
+        usr: 0x08,
+        src: 0x10,
+        context: 0x18,
+        sequence: 0x1C,
+        callback: 0x20,
+        callback_arg: 0x24,
+        argc: 0x28,
+        argv: 0x2C,
+        unknown_0: 0x30,
+    );
+};
+
+#[cfg(target_arch = "x86_64")]
+const _: () = {
+    assert_layout!(DreamObject, 0x08, part_1: 0x00, part_2: 0x04);
+    assert_layout!(
+        DreamString,
+        0x30,
+        data: 0x00,
+        id: 0x08,
+        left: 0x10,
+        right: 0x18,
+        refcount: 0x20,
+        length: 0x28,
+    );
+    assert_layout!(Bytecode, 0x10, length: 0x00, bytecode: 0x08);
+    assert_layout!(Locals, 0x10, length: 0x00, locals: 0x08);
+    assert_layout!(Params, 0x10, length: 0x00, params: 0x08);
+    assert_layout!(Misc, 0x30, bytecode: 0x00, locals: 0x10, params: 0x20);
+    assert_layout!(
+        Proc,
+        0x48,
+        procdef: 0x00,
+        flags: 0x04,
+        supers: 0x05,
+        usr: 0x08,
+        src: 0x10,
+        context: 0x18,
+        sequence: 0x20,
+        callback: 0x28,
+        callback_arg: 0x30,
+        argc: 0x34,
+        argv: 0x38,
+        unknown_0: 0x40,
+    );
+};
+
+/// Fails the build if a build in offsets reads procdefs with a different layout than its build range
+/// uses. Builds older than every known layout aren't checked.
+pub(super) const fn check_procdef_descriptors(offsets: &[Offsets]) {
+    let mut i = 0;
+    while i < offsets.len() {
+        let mut layout = 0;
+        while layout < PROCDEF_LAYOUTS.len() {
+            let (first_build, descriptor) = PROCDEF_LAYOUTS[layout];
+            let next_build = if layout + 1 < PROCDEF_LAYOUTS.len() {
+                PROCDEF_LAYOUTS[layout + 1].0
+            } else {
+                BuildNumber::MAX
+            };
+            let build = offsets[i].byond_build;
+            assert!(
+                build < first_build
+                    || build >= next_build
+                    || offsets[i].procdefs_descriptor == descriptor,
+                "A procdefs_descriptor doesn't match the procdef layout of its build"
+            );
+            layout += 1;
+        }
+        i += 1;
+    }
+}
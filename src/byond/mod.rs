@@ -1,36 +1,53 @@
 use std::{
-    array::from_fn,
-    ffi::{CStr, c_char},
+    ffi::{CStr, c_char, c_int, c_void},
     mem::transmute,
-    ptr::null,
+    ptr::{copy_nonoverlapping, null, read_volatile, write_volatile},
+    sync::atomic::{AtomicU16, AtomicU64, Ordering, fence},
 };
 
 use crate::byond::offsets::Offsets;
 
 use tracy_client::{SpanLocation, internal::make_span_location};
 
+mod layout;
 pub(crate) mod offsets;
+#[cfg(target_arch = "x86_64")]
+mod prologue;
+#[cfg(all(not(target_os = "windows"), target_arch = "x86"))]
+mod regparm;
 
 pub(crate) const MAX_PROCS: usize = 0x14000;
 
 pub(crate) type BuildNumber = i32;
 
-#[cfg(target_os = "windows")]
-pub(crate) type ExecProcFunction = unsafe extern "cdecl" fn(*const Proc) -> DreamObject;
-
-#[cfg(not(target_os = "windows"))]
-pub(crate) type ExecProcFunction = unsafe extern "regparm3" fn(*const Proc) -> DreamObject;
+// "C" is cdecl on x86, and on x86_64 it's the only convention each platform has. On Linux x86, BYOND's
+// side of exec_proc is regparm(3), which goes through the shims in regparm
+pub(crate) type ExecProcFunction = unsafe extern "C" fn(*const Proc) -> DreamObject;
 
-#[cfg(target_os = "windows")]
+#[cfg(all(target_os = "windows", target_arch = "x86"))]
 pub(crate) type ServerTickFunction = unsafe extern "stdcall" fn() -> i32;
 
-#[cfg(not(target_os = "windows"))]
-pub(crate) type ServerTickFunction = unsafe extern "cdecl" fn() -> i32;
+#[cfg(not(all(target_os = "windows", target_arch = "x86")))]
+pub(crate) type ServerTickFunction = unsafe extern "C" fn() -> i32;
+
+pub(crate) type SendMapsFunction = unsafe extern "C" fn();
+
+pub(crate) type MallocFunction = unsafe extern "C" fn(usize) -> *mut c_void;
+
+pub(crate) type FreeFunction = unsafe extern "C" fn(*mut c_void);
 
-pub(crate) type SendMapsFunction = unsafe extern "cdecl" fn();
+pub(crate) type RuntimeFunction = unsafe extern "C" fn(*const c_char);
 
 type DreamStringId = u32;
 
+type ProcId = u32;
+
+const NULL_TYPE: u8 = 0x00;
+
+const STRING_TYPE: u8 = 0x06;
+
+const NUMBER_TYPE: u8 = 0x2A;
+
 #[repr(C)]
 union ObjectPart1 {
     padding: u32,
@@ -60,19 +77,6 @@ struct DreamString {
     length: u32,
 }
 
-#[repr(C)]
-struct ProcDefinition {
-    path: DreamStringId,
-    name: u32,
-    desc: u32,
-    category: u32,
-    flags: u32,
-    _unknown0: u32,
-    bytecode: u32,
-    locals: u32,
-    parameters: u32,
-}
-
 #[repr(C)]
 struct Bytecode {
     length: u16,
@@ -102,11 +106,13 @@ struct Misc {
 }
 
 #[repr(C)]
-struct ExecutionContext;
+pub(crate) struct ExecutionContext;
+
+// The x86_64 layouts are the x86 ones with pointers widened, ids stay 32 bits. layout.rs checks them
 
 #[repr(C)]
 pub(crate) struct Proc {
-    pub procdef: usize,
+    procdef: ProcId,
     flags: u8,
     supers: u8,
     unused: u16,
@@ -117,10 +123,25 @@ pub(crate) struct Proc {
     callback: fn(DreamObject, u32) -> (),
     callback_arg: u32,
     argc: u32,
-    argv: *const [DreamObject],
+    argv: *const DreamObject,
     unknown_0: u32,
 }
 
+impl Proc {
+    pub fn procdef(&self) -> usize {
+        self.procdef as usize
+    }
+
+    pub fn arguments(&self) -> &[DreamObject] {
+        if self.argv.is_null() {
+            return &[];
+        }
+
+        // SAFETY: argv holds argc values for as long as the proc is executing
+        unsafe { std::slice::from_raw_parts(self.argv, self.argc as usize) }
+    }
+}
+
 #[repr(C)]
 struct ProcDefsDescriptor {
     size: usize,
@@ -128,16 +149,72 @@ struct ProcDefsDescriptor {
     bytecode_offset: usize,
 }
 
-#[repr(C)]
-#[derive(Default)]
+/// A jmp that reaches any address. x86 gets there with rel32, x86_64 jumps through the absolute address
+/// that follows the instruction, since a hook can be more than 2GB from the function it hooks.
+#[cfg(target_arch = "x86")]
+const JMP_LEN: usize = 5;
+
+#[cfg(target_arch = "x86_64")]
+const JMP_LEN: usize = 14;
+
+/// The most prologue a hook may displace.
+const MAX_PROLOGUE_LEN: usize = 32;
+
+/// The displaced prologue, then the jmp back into the original function.
+const TRAMPOLINE_LEN: usize = MAX_PROLOGUE_LEN + JMP_LEN;
+
+// Page aligned so it can be made executable without touching anything else
+#[repr(C, align(4096))]
 struct Trampoline {
-    exec_proc: [u8; 32],
-    server_tick: [u8; 32],
-    send_maps: [u8; 32],
+    exec_proc: [u8; TRAMPOLINE_LEN],
+    server_tick: [u8; TRAMPOLINE_LEN],
+    send_maps: [u8; TRAMPOLINE_LEN],
+    malloc: [u8; TRAMPOLINE_LEN],
+    free: [u8; TRAMPOLINE_LEN],
+    runtime: [u8; TRAMPOLINE_LEN],
+}
+
+// Hooked functions jump back through this, so it must never move
+static mut TRAMPOLINE: Trampoline = Trampoline {
+    exec_proc: [0; TRAMPOLINE_LEN],
+    server_tick: [0; TRAMPOLINE_LEN],
+    send_maps: [0; TRAMPOLINE_LEN],
+    malloc: [0; TRAMPOLINE_LEN],
+    free: [0; TRAMPOLINE_LEN],
+    runtime: [0; TRAMPOLINE_LEN],
+};
+
+/// The allocator hooks fire on every thread far more often than any other, so they reach the
+/// originals directly rather than through the instance.
+/// SAFETY: Must only be called from malloc_hook, after it has been installed
+pub(crate) unsafe fn orig_malloc() -> MallocFunction {
+    unsafe { transmute((&raw const TRAMPOLINE.malloc) as usize) }
+}
+
+/// SAFETY: Must only be called from free_hook, after it has been installed
+pub(crate) unsafe fn orig_free() -> FreeFunction {
+    unsafe { transmute((&raw const TRAMPOLINE.free) as usize) }
 }
 
 struct ProcdefPointer(usize);
 
+const DBG_FILE_OPCODE: u32 = 0x84;
+
+const DBG_LINE_OPCODE: u32 = 0x85;
+
+pub(crate) struct ProcInfo {
+    pub path: Option<&'static CStr>,
+    pub file: Option<&'static CStr>,
+    pub line: Option<u32>,
+}
+
+pub(crate) struct ObjectCounts {
+    pub strings: usize,
+    pub miscs: usize,
+    pub datums: Option<usize>,
+    pub lists: Option<usize>,
+}
+
 pub(crate) struct ByondReflectionData {
     strings_base_address: *const DreamString,
     strings_len: *const usize,
@@ -146,112 +223,369 @@ pub(crate) struct ByondReflectionData {
     procdefs_base_address: usize,
     procdefs_len: *const usize,
     procdef_desc: ProcDefsDescriptor,
-    exec_proc_address: usize,
+    datums_len: Option<*const usize>,
+    lists_len: Option<*const usize>,
     pub orig_exec_proc: ExecProcFunction,
-    server_tick_address: usize,
     pub orig_server_tick: ServerTickFunction,
-    send_maps_address: usize,
     pub orig_send_maps: SendMapsFunction,
-    // TODO: _Alignas(4096)
-    trampoline: Trampoline,
+    pub orig_runtime: Option<RuntimeFunction>,
+    pub allocator_hooked: bool,
 }
 
-pub struct SourceLocations {}
-
 impl ByondReflectionData {
-    pub fn create_and_initialize_hooks(
+    /// Everything is verified and made writable, but nothing is patched until the returned hooks are
+    /// installed, which must wait until the hooks have an instance to find.
+    #[allow(clippy::too_many_arguments)]
+    pub fn create_and_prepare_hooks(
         offsets: &Offsets,
         byondcore_base_address: usize,
         exec_proc_hook: ExecProcFunction,
         server_tick_hook: ServerTickFunction,
         send_maps_hook: SendMapsFunction,
-    ) -> Result<Self, String> {
+        malloc_hook: MallocFunction,
+        free_hook: FreeFunction,
+        runtime_hook: RuntimeFunction,
+    ) -> Result<(Self, PreparedHooks), String> {
         // SAFETY: Provided offsets should have been verified to be the offsets of the BYOND internals we're looking for
         unsafe {
             let exec_proc_address = byondcore_base_address + offsets.exec_proc;
             let server_tick_address = byondcore_base_address + offsets.server_tick;
             let send_maps_address = byondcore_base_address + offsets.send_maps;
 
-            let trampoline = Trampoline::default();
+            let exec_proc_prologue = offsets.prologue & 0xFF;
+            let server_tick_prologue = (offsets.prologue >> 8) & 0xFF;
+            let send_maps_prologue = (offsets.prologue >> 16) & 0xFF;
+
+            // Verify everything up front so a bad offset never leaves a partially hooked runtime
+            let signatures = offsets.signatures.as_ref();
+            verify_prologue(
+                exec_proc_address,
+                exec_proc_prologue,
+                signatures.map(|signatures| signatures.exec_proc),
+                "exec_proc",
+            )?;
+            verify_prologue(
+                server_tick_address,
+                server_tick_prologue,
+                signatures.map(|signatures| signatures.server_tick),
+                "server_tick",
+            )?;
+            verify_prologue(
+                send_maps_address,
+                send_maps_prologue,
+                signatures.map(|signatures| signatures.send_maps),
+                "send_maps",
+            )?;
 
-            Ok(Self {
-                strings_base_address: transmute(byondcore_base_address + offsets.strings),
-                strings_len: transmute(byondcore_base_address + offsets.strings_len),
-                miscs_base_address: transmute(byondcore_base_address + offsets.miscs),
-                miscs_len: transmute(byondcore_base_address + offsets.miscs_len),
+            let allocator = offsets.allocator.as_ref().map(|allocator| {
+                (
+                    byondcore_base_address + allocator.malloc,
+                    allocator.prologue & 0xFF,
+                    byondcore_base_address + allocator.free,
+                    (allocator.prologue >> 8) & 0xFF,
+                )
+            });
+            if let Some((malloc_address, malloc_prologue, free_address, free_prologue)) = allocator
+            {
+                verify_prologue(malloc_address, malloc_prologue, None, "malloc")?;
+                verify_prologue(free_address, free_prologue, None, "free")?;
+            }
+
+            let runtime = offsets.runtime.as_ref().map(|runtime| {
+                (
+                    byondcore_base_address + runtime.runtime,
+                    runtime.prologue & 0xFF,
+                )
+            });
+            if let Some((runtime_address, runtime_prologue)) = runtime {
+                verify_prologue(runtime_address, runtime_prologue, None, "runtime")?;
+            }
+
+            let trampoline_pointer = &raw mut TRAMPOLINE;
+            let trampoline = &mut *trampoline_pointer;
+            unprotect_address(
+                trampoline as *mut Trampoline as usize,
+                size_of::<Trampoline>(),
+            )?;
+
+            #[cfg(not(all(not(target_os = "windows"), target_arch = "x86")))]
+            let exec_proc_hook_address = exec_proc_hook as usize;
+            #[cfg(all(not(target_os = "windows"), target_arch = "x86"))]
+            let exec_proc_hook_address = regparm::wrap_hook(exec_proc_hook);
+
+            // Every target is made writable before any is patched, so a failure leaves BYOND untouched
+            let exec_proc = prepare_hook(
+                exec_proc_hook_address,
+                exec_proc_address,
+                exec_proc_prologue,
+                &mut trampoline.exec_proc,
+                "exec_proc",
+            )?;
+            let server_tick = prepare_hook(
+                server_tick_hook as usize,
+                server_tick_address,
+                server_tick_prologue,
+                &mut trampoline.server_tick,
+                "server_tick",
+            )?;
+            let send_maps = prepare_hook(
+                send_maps_hook as usize,
+                send_maps_address,
+                send_maps_prologue,
+                &mut trampoline.send_maps,
+                "send_maps",
+            )?;
+            let runtime = match runtime {
+                Some((runtime_address, runtime_prologue)) => Some(prepare_hook(
+                    runtime_hook as usize,
+                    runtime_address,
+                    runtime_prologue,
+                    &mut trampoline.runtime,
+                    "runtime",
+                )?),
+                None => None,
+            };
+            let allocator = match allocator {
+                Some((malloc_address, malloc_prologue, free_address, free_prologue)) => Some((
+                    prepare_hook(
+                        malloc_hook as usize,
+                        malloc_address,
+                        malloc_prologue,
+                        &mut trampoline.malloc,
+                        "malloc",
+                    )?,
+                    prepare_hook(
+                        free_hook as usize,
+                        free_address,
+                        free_prologue,
+                        &mut trampoline.free,
+                        "free",
+                    )?,
+                )),
+                None => None,
+            };
+
+            // The trampolines are filled in already, so the originals are known before anything is patched
+            let orig_exec_proc = transmute::<usize, ExecProcFunction>(exec_proc.trampoline_address);
+            #[cfg(all(not(target_os = "windows"), target_arch = "x86"))]
+            let orig_exec_proc = regparm::wrap_original(orig_exec_proc as usize);
+
+            let data = Self {
+                strings_base_address: (byondcore_base_address + offsets.strings) as *const _,
+                strings_len: (byondcore_base_address + offsets.strings_len) as *const _,
+                miscs_base_address: (byondcore_base_address + offsets.miscs) as *const _,
+                miscs_len: (byondcore_base_address + offsets.miscs_len) as *const _,
                 procdefs_base_address: byondcore_base_address + offsets.procdefs,
-                procdefs_len: transmute(byondcore_base_address + offsets.procdefs_len),
+                procdefs_len: (byondcore_base_address + offsets.procdefs_len) as *const _,
                 procdef_desc: ProcDefsDescriptor {
-                    size: (offsets.procdefs_descriptor >> 0) & 0xFF,
+                    size: offsets.procdefs_descriptor & 0xFF,
                     path_offset: (offsets.procdefs_descriptor >> 8) & 0xFF,
                     bytecode_offset: (offsets.procdefs_descriptor >> 16) & 0xFF,
                 },
-                exec_proc_address,
-                server_tick_address,
-                send_maps_address,
-                trampoline,
-                orig_exec_proc: transmute(hook::<ExecProcFunction>(
-                    exec_proc_hook,
-                    transmute(exec_proc_address),
-                    offsets.prologue >> 8,
-                    &mut trampoline.exec_proc,
-                    "exec_proc",
-                )?),
-                orig_server_tick: transmute(hook::<ServerTickFunction>(
-                    server_tick_hook,
-                    transmute(server_tick_address),
-                    offsets.prologue >> 8,
-                    &mut trampoline.server_tick,
-                    "server_tick",
-                )?),
-                orig_send_maps: transmute(hook::<SendMapsFunction>(
-                    send_maps_hook,
-                    transmute(send_maps_address),
-                    offsets.prologue >> 16,
-                    &mut trampoline.send_maps,
-                    "send_maps",
-                )?),
-            })
+                datums_len: offsets.object_tables.as_ref().map(|object_tables| {
+                    (byondcore_base_address + object_tables.datums_len) as *const _
+                }),
+                lists_len: offsets.object_tables.as_ref().map(|object_tables| {
+                    (byondcore_base_address + object_tables.lists_len) as *const _
+                }),
+                orig_exec_proc,
+                orig_server_tick: transmute::<usize, ServerTickFunction>(
+                    server_tick.trampoline_address,
+                ),
+                orig_send_maps: transmute::<usize, SendMapsFunction>(send_maps.trampoline_address),
+                orig_runtime: runtime
+                    .as_ref()
+                    .map(|runtime| transmute::<usize, RuntimeFunction>(runtime.trampoline_address)),
+                allocator_hooked: allocator.is_some(),
+            };
+
+            Ok((
+                data,
+                PreparedHooks {
+                    allocator,
+                    runtime,
+                    send_maps,
+                    server_tick,
+                    exec_proc,
+                },
+            ))
         }
     }
 
-    pub fn build_source_locations(&self) -> [SpanLocation; MAX_PROCS] {
-        from_fn(|i| {
-            let mut name: *const u8 = null();
-            let mut function = "<?>";
-            let mut file = "<?.dm>".as_ptr();
-            let mut line = 0xFFFFFFFF;
+    /// Builds a source location for every proc include accepts, indexed by procdef.
+    pub fn build_source_locations(
+        &self,
+        include: impl Fn(&CStr) -> bool,
+    ) -> Vec<Option<SpanLocation>> {
+        (0..self.procs_len())
+            .map(|index| {
+                let info = self.proc_info(index);
+                if !include(info.path.unwrap_or_default()) {
+                    return None;
+                }
 
-            if let Some(procdef) = self.get_procdef(i) {
-                let str = self.get_string_from_id(procdef.path_string_id());
+                let name = info.path.map_or(null(), |path| path.as_ptr().cast());
+                let file = info.file.unwrap_or(c"<?.dm>").as_ptr().cast();
+                let line = info.line.unwrap_or(0xFFFFFFFF);
 
-                if !str.is_null() {
-                    let str_ref = unsafe { *str };
-                    if !str_ref.data.is_null() {
-                        let c_str = unsafe { CStr::from_ptr(str_ref.data) };
-                        function = c_str.to_str().expect("Failed to get proc name!");
-                    }
+                Some(make_span_location("<?>", name, file, line))
+            })
+            .collect()
+    }
+
+    pub fn procs_len(&self) -> usize {
+        // SAFETY: See object_counts
+        unsafe { read_volatile(self.procdefs_len) }.min(MAX_PROCS)
+    }
+
+    /// Looks up everything the reflection tables know about a procdef. Missing pieces are left as None.
+    pub fn proc_info(&self, index: usize) -> ProcInfo {
+        let mut info = ProcInfo {
+            path: None,
+            file: None,
+            line: None,
+        };
+
+        let Some(procdef) = self.get_procdef(index) else {
+            return info;
+        };
 
-                    todo!()
+        info.path = self.get_cstr_from_id(procdef.path_string_id(&self.procdef_desc));
+
+        let misc = self.get_misc(procdef.bytecode_id(&self.procdef_desc));
+        if !misc.is_null() {
+            // SAFETY: Non-null entries in the misc table are valid
+            let bytecode = unsafe { &(*misc).bytecode };
+            if !bytecode.bytecode.is_null() {
+                // SAFETY: length is the number of instructions bytecode points to
+                let instructions = unsafe {
+                    std::slice::from_raw_parts(bytecode.bytecode, bytecode.length.into())
+                };
+
+                // Procs open with a DBG FILE instruction, usually followed by DBG LINE
+                if instructions.len() >= 2 && instructions[0] == DBG_FILE_OPCODE {
+                    info.file = self.get_cstr_from_id(instructions[1]);
+
+                    if instructions.len() >= 4 && instructions[2] == DBG_LINE_OPCODE {
+                        info.line = Some(instructions[3]);
+                    }
                 }
             }
+        }
+
+        info
+    }
+
+    /// The proc's path for display, e.g. "/mob/proc/Login".
+    pub fn proc_name(&self, index: usize) -> String {
+        self.get_procdef(index)
+            .and_then(|procdef| self.get_cstr_from_id(procdef.path_string_id(&self.procdef_desc)))
+            .map_or_else(
+                || "<unknown proc>".to_string(),
+                |path| path.to_string_lossy().into_owned(),
+            )
+    }
+
+    pub fn object_counts(&self) -> ObjectCounts {
+        // SAFETY: The length globals are plain words that live as long as byondcore does.
+        // They're written from the main thread, so reads can be torn at worst
+        unsafe {
+            ObjectCounts {
+                strings: read_volatile(self.strings_len),
+                miscs: read_volatile(self.miscs_len),
+                datums: self.datums_len.map(|datums_len| read_volatile(datums_len)),
+                lists: self.lists_len.map(|lists_len| read_volatile(lists_len)),
+            }
+        }
+    }
 
-            // TODO: Colour
-            make_span_location(function, name, file, line)
-        })
+    /// Renders a value roughly the way DM would, for attaching to zones.
+    pub fn describe_value(&self, value: &DreamObject) -> String {
+        // SAFETY: The tag says which half of the unions is meaningful
+        unsafe {
+            match value.part_1.object_type {
+                NULL_TYPE => "null".to_string(),
+                NUMBER_TYPE => value.part_2.f.to_string(),
+                STRING_TYPE => match self.get_cstr_from_id(value.part_2.i) {
+                    Some(string) => format!("\"{}\"", string.to_string_lossy()),
+                    None => format!("[string {:#X}]", value.part_2.i),
+                },
+                object_type => format!("[{:#04X}:{:#X}]", object_type, value.part_2.i),
+            }
+        }
     }
 
     fn get_procdef(&self, index: usize) -> Option<ProcdefPointer> {
-        todo!()
+        if index >= self.procs_len() {
+            return None;
+        }
+
+        // SAFETY: The procdefs global points at an array of procdefs_len descriptors
+        let procdefs = unsafe { read_volatile(self.procdefs_base_address as *const usize) };
+        if procdefs == 0 {
+            return None;
+        }
+
+        Some(ProcdefPointer(procdefs + index * self.procdef_desc.size))
     }
 
-    fn get_string_from_id(&self, string_id: u32) -> *const DreamString {
-        todo!()
+    fn get_string_from_id(&self, string_id: DreamStringId) -> *const DreamString {
+        // SAFETY: The strings global points at an array of strings_len string pointers
+        unsafe {
+            if string_id as usize >= read_volatile(self.strings_len) {
+                return null();
+            }
+
+            let strings =
+                read_volatile(self.strings_base_address as *const *const *const DreamString);
+            if strings.is_null() {
+                return null();
+            }
+
+            *strings.add(string_id as usize)
+        }
+    }
+
+    fn get_cstr_from_id(&self, string_id: DreamStringId) -> Option<&'static CStr> {
+        let string = self.get_string_from_id(string_id);
+        if string.is_null() {
+            return None;
+        }
+
+        // SAFETY: Non-null entries in the string table are valid, and proc strings are never freed
+        unsafe {
+            let data = (*string).data;
+            (!data.is_null()).then(|| CStr::from_ptr(data))
+        }
+    }
+
+    fn get_misc(&self, misc_id: u32) -> *const Misc {
+        // SAFETY: The miscs global points at an array of miscs_len misc pointers
+        unsafe {
+            if misc_id as usize >= read_volatile(self.miscs_len) {
+                return null();
+            }
+
+            let miscs = read_volatile(self.miscs_base_address as *const *const *const Misc);
+            if miscs.is_null() {
+                return null();
+            }
+
+            *miscs.add(misc_id as usize)
+        }
     }
 }
 
 impl ProcdefPointer {
-    fn path_string_id(&self) -> DreamStringId {}
+    fn path_string_id(&self, desc: &ProcDefsDescriptor) -> DreamStringId {
+        // SAFETY: The descriptor gives the offset of the path field within a procdef
+        unsafe { read_volatile((self.0 + desc.path_offset) as *const DreamStringId) }
+    }
+
+    fn bytecode_id(&self, desc: &ProcDefsDescriptor) -> u32 {
+        // SAFETY: The descriptor gives the offset of the bytecode field within a procdef
+        unsafe { read_volatile((self.0 + desc.bytecode_offset) as *const u32) }
+    }
 }
 
 // SAFETY: Pointers are read only and accessed in a manner with correct ownership from the BYOND runtime
@@ -260,72 +594,479 @@ unsafe impl Send for ByondReflectionData {}
 // SAFETY: Pointers are read only and accessed in a manner with correct ownership from the BYOND runtime
 unsafe impl Sync for ByondReflectionData {}
 
-// SAFETY:
-// - hook_fn and original_fn must be two different function pointers with identical calling conventions, parameters, and return types
-// - size must be the number of bytes to overwrite in the function's prologue to safely hook it
-// - trampoline's memory location must be pinned
-unsafe fn hook<T>(
-    hook_fn: *const T,
-    original_fn: *const T,
+/// Opcodes that can never begin a hookable prologue. Finding one of these at the target means the offset
+/// points at padding, at a function that is already hooked, or at the wrong place entirely.
+const INVALID_PROLOGUE_OPCODES: [u8; 7] = [
+    0x00, // Zeroed memory
+    0xC2, // ret imm16
+    0xC3, // ret
+    0xCC, // int3 padding
+    0xE8, // call rel32
+    0xE9, // jmp rel32
+    0xEB, // jmp rel8
+];
+
+// SAFETY: address must be readable for size bytes
+unsafe fn verify_prologue(
+    address: usize,
     size: usize,
-    trampoline: &mut [u8; 32],
+    expected: Option<&[u8]>,
     hook_name: &str,
-) -> Result<*const T, String> {
-    let jmp = [0xE9, 0x00, 0x00, 0x00, 0x00];
+) -> Result<(), String> {
+    if !(JMP_LEN..=MAX_PROLOGUE_LEN).contains(&size) {
+        return Err(format!(
+            "Invalid prologue size for {}: {} (must be between {} and {})",
+            hook_name, size, JMP_LEN, MAX_PROLOGUE_LEN
+        ));
+    }
 
-    let trampoline_ptr: *mut [u8; 32] = trampoline;
+    let actual = unsafe { std::slice::from_raw_parts(address as *const u8, size) };
 
-    // SAFETY: A pointer has an equivalent bit layout to a usize
-    let trampoline_address: usize = unsafe { transmute(trampoline_ptr) };
-    let og_function_address: usize;
-    let hook_fn_address: usize;
+    let mismatch = match expected {
+        Some(expected) => {
+            expected.len() > size
+                || expected
+                    .iter()
+                    .zip(actual)
+                    .any(|(expected, actual)| expected != actual)
+        }
+        None => INVALID_PROLOGUE_OPCODES.contains(&actual[0]),
+    };
 
-    // SAFETY: Based on input safety requirements, these are function pointers
-    unsafe {
-        og_function_address = transmute(original_fn);
-        hook_fn_address = transmute(hook_fn);
+    if !mismatch {
+        // The trampoline runs the prologue from elsewhere, which x86's prologues never notice
+        #[cfg(target_arch = "x86_64")]
+        prologue::check_relocatable(actual).map_err(|error| {
+            format!(
+                "Unable to move the prologue of {} at {:#010X}: {}",
+                hook_name, address, error
+            )
+        })?;
+        return Ok(());
     }
 
-    let trampoline_jmp_from = trampoline_address + size + jmp.len();
-    let trampoline_jmp_to = og_function_address + size;
-    let trampoline_offset = trampoline_jmp_to - trampoline_jmp_from;
+    let differing = match expected {
+        Some(expected) => expected
+            .iter()
+            .zip(actual)
+            .enumerate()
+            .filter(|(_, (expected, actual))| expected != actual)
+            .map(|(index, _)| index.to_string())
+            .collect::<Vec<_>>()
+            .join(", "),
+        None => "0".to_string(),
+    };
 
-    todo!("Memcpy 1");
+    Err(format!(
+        "Prologue mismatch for {} at {:#010X}: expected [{}], found [{}] (differs at byte(s) {})",
+        hook_name,
+        address,
+        match expected {
+            Some(expected) => format_bytes(expected),
+            None => "any non-branch instruction".to_string(),
+        },
+        format_bytes(actual),
+        if differing.is_empty() {
+            "past the prologue length".to_string()
+        } else {
+            differing
+        },
+    ))
+}
+
+fn format_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Every hook create_and_prepare_hooks verified, waiting to be patched in.
+pub(crate) struct PreparedHooks {
+    // Fields drop in order, so these are listed in reverse of how they were prepared. Where two share
+    // a page, the first one prepared is the last to put its protection back, restoring what it started as
+    allocator: Option<(PreparedHook, PreparedHook)>,
+    runtime: Option<PreparedHook>,
+    send_maps: PreparedHook,
+    server_tick: PreparedHook,
+    exec_proc: PreparedHook,
+}
+
+impl PreparedHooks {
+    /// Patches every hook in. The hooks may run on any thread as soon as this starts.
+    pub fn install(mut self) {
+        self.exec_proc.install();
+        self.server_tick.install();
+        self.send_maps.install();
+        if let Some(runtime) = &mut self.runtime {
+            runtime.install();
+        }
 
-    let jmp_from = og_function_address + jmp.len();
-    let jmp_to = hook_fn_address;
-    let offset = jmp_to - jmp_from;
+        // Only hooked once the required hooks are in, the originals are reached through orig_malloc/orig_free
+        if let Some((malloc, free)) = &mut self.allocator {
+            malloc.install();
+            free.install();
+        }
+    }
+}
+
+/// A hook whose trampoline is filled in and whose target is writable. Its protection is put back once
+/// it's dropped, so hooks sharing a page stay writable until every one of them is installed.
+struct PreparedHook {
+    hook_fn_address: usize,
+    og_function_address: usize,
+    size: usize,
+    trampoline_address: usize,
+    hook_name: &'static str,
+    old_protection: Option<ProtectionFlags>,
+    installed: bool,
+}
+
+// SAFETY:
+// - hook_fn_address and og_function_address must be two different functions with identical calling conventions, parameters, and return types
+// - size must be the number of bytes to overwrite in the function's prologue to safely hook it
+// - the prologue must not contain anything relative to where it runs, since it's moved to the trampoline
+// - trampoline's memory location must be pinned and executable
+unsafe fn prepare_hook(
+    hook_fn_address: usize,
+    og_function_address: usize,
+    size: usize,
+    trampoline: &mut [u8; TRAMPOLINE_LEN],
+    hook_name: &'static str,
+) -> Result<PreparedHook, String> {
+    if !can_write_head(og_function_address) {
+        return Err(format!(
+            "Unable to hook {} at {:#010X}: its first two bytes can't be replaced in one store",
+            hook_name, og_function_address
+        ));
+    }
+
+    let trampoline_address = trampoline.as_ptr() as usize;
+
+    // The trampoline runs the displaced prologue, then jumps back into the original function past it
+    // SAFETY: og_function_address is readable for size bytes (see verify_prologue), which leaves room for the jmp
+    unsafe {
+        copy_nonoverlapping(
+            og_function_address as *const u8,
+            trampoline.as_mut_ptr(),
+            size,
+        );
+    }
+    trampoline[size..size + JMP_LEN].copy_from_slice(&encode_jmp(
+        trampoline_address + size,
+        og_function_address + size,
+    ));
 
     let old_protection = unprotect_address(og_function_address, size)?;
 
-    todo!("Memcpy 2");
+    Ok(PreparedHook {
+        hook_fn_address,
+        og_function_address,
+        size,
+        trampoline_address,
+        hook_name,
+        old_protection: Some(old_protection),
+        installed: false,
+    })
+}
+
+impl PreparedHook {
+    /// Patches the jmp to the hook in. The original function is then reached through the trampoline.
+    fn install(&mut self) {
+        let nop: u8 = 0x90;
+
+        let mut patch = [nop; MAX_PROLOGUE_LEN];
+        patch[..JMP_LEN]
+            .copy_from_slice(&encode_jmp(self.og_function_address, self.hook_fn_address));
 
-    if size > jmp.len() {
-        for i in 0..(size - jmp.len()) {
-            let nop: u8 = 0x90;
-            todo!("Memcpy 3");
+        // SAFETY: The prologue stays writable until self is dropped, it's size bytes long, and
+        // prepare_hook checked can_write_head
+        unsafe {
+            write_patch(self.og_function_address, &patch[..self.size]);
         }
+
+        flush_instruction_cache(self.og_function_address, self.size);
+
+        self.installed = true;
     }
+}
 
-    reprotect_address(og_function_address, size, old_protection).expect(
-        format!(
-            "Could not reprotect address of hooked function: {}",
-            hook_name
-        )
-        .as_str(),
-    );
+impl Drop for PreparedHook {
+    fn drop(&mut self) {
+        let Some(old_protection) = self.old_protection.take() else {
+            return;
+        };
+
+        let result = reprotect_address(self.og_function_address, self.size, old_protection);
+        // Left unpatched, failing to reprotect only leaves the page writable
+        if self.installed
+            && let Err(error) = result
+        {
+            panic!(
+                "Could not reprotect address of hooked function {}: {}",
+                self.hook_name, error
+            );
+        }
+    }
+}
+
+/// A jmp placed at from that lands on to.
+#[cfg(target_arch = "x86")]
+fn encode_jmp(from: usize, to: usize) -> [u8; JMP_LEN] {
+    let offset = to.wrapping_sub(from + JMP_LEN) as u32;
+
+    let mut jmp = [0xE9, 0x00, 0x00, 0x00, 0x00]; // jmp rel32
+    jmp[1..].copy_from_slice(&offset.to_le_bytes());
+    jmp
+}
+
+/// A jmp placed at from that lands on to.
+#[cfg(target_arch = "x86_64")]
+fn encode_jmp(_from: usize, to: usize) -> [u8; JMP_LEN] {
+    #[rustfmt::skip]
+    let mut jmp = [
+        0xFF, 0x25, 0x00, 0x00, 0x00, 0x00, // jmp [rip + 0]
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // where to
+    ];
+    jmp[6..].copy_from_slice(&(to as u64).to_le_bytes());
+    jmp
+}
+
+/// Writes patch over the start of a function other threads may be executing.
+///
+/// If the whole patch fits in the aligned 8-byte word holding address it is swapped in with a single
+/// atomic store. Otherwise the first two bytes become a `jmp $` self-loop, so any thread entering the
+/// function spins while the tail of the patch is written, and the head is swapped in last to release
+/// them. Threads already executing inside the patched bytes are not protected by either strategy.
+///
+/// SAFETY: address must be writable for patch.len() bytes, patch.len() must be at least 2, and
+/// can_write_head must hold for address
+unsafe fn write_patch(address: usize, patch: &[u8]) {
+    let self_loop: [u8; 2] = [0xEB, 0xFE];
+
+    // SAFETY: Guaranteed by our caller
+    unsafe {
+        if write_within_word(address, patch) {
+            return;
+        }
+
+        write_head(address, self_loop);
+        fence(Ordering::SeqCst);
+
+        for (index, byte) in patch.iter().enumerate().skip(self_loop.len()) {
+            write_volatile((address + index) as *mut u8, *byte);
+        }
+        fence(Ordering::SeqCst);
+
+        write_head(address, [patch[0], patch[1]]);
+        fence(Ordering::SeqCst);
+    }
+}
+
+/// Whether the first two bytes at address can be replaced in one store: either they're 2-byte aligned,
+/// or they share an aligned 8-byte word.
+fn can_write_head(address: usize) -> bool {
+    address.is_multiple_of(align_of::<AtomicU16>())
+        || address % size_of::<u64>() + 2 <= size_of::<u64>()
+}
+
+/// Stores the first two bytes of a patch in one piece, so no thread ever runs half of the self-loop.
+///
+/// SAFETY: address must be writable for 2 bytes, and can_write_head must hold for it
+unsafe fn write_head(address: usize, head: [u8; 2]) {
+    if address.is_multiple_of(align_of::<AtomicU16>()) {
+        // SAFETY: address is aligned and writable
+        let word = unsafe { AtomicU16::from_ptr(address as *mut u16) };
+        word.store(u16::from_le_bytes(head), Ordering::Release);
+        return;
+    }
+
+    // SAFETY: Guaranteed by our caller
+    let written = unsafe { write_within_word(address, &head) };
+    assert!(written, "{:#010X} can't be patched in one store", address);
+}
+
+/// Writes bytes over address with a single atomic store, if they fit in the aligned 8-byte word holding
+/// address. The rest of the word is written back as it was.
+///
+/// SAFETY: address must be writable for bytes.len() bytes
+unsafe fn write_within_word(address: usize, bytes: &[u8]) -> bool {
+    let offset = address % size_of::<u64>();
+    if offset + bytes.len() > size_of::<u64>() {
+        return false;
+    }
 
-    Ok(unsafe { transmute(trampoline_address) })
+    // SAFETY: The aligned word is on the same page as address, which is writable
+    let word = unsafe { AtomicU64::from_ptr((address - offset) as *mut u64) };
+    let mut current = word.load(Ordering::Acquire);
+    loop {
+        let mut patched = current.to_le_bytes();
+        patched[offset..offset + bytes.len()].copy_from_slice(bytes);
+        match word.compare_exchange_weak(
+            current,
+            u64::from_le_bytes(patched),
+            Ordering::AcqRel,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => return true,
+            Err(actual) => current = actual,
+        }
+    }
+}
+
+#[cfg(target_os = "windows")]
+mod win32 {
+    use std::ffi::c_void;
+
+    pub const PAGE_EXECUTE_READWRITE: u32 = 0x40;
+
+    unsafe extern "system" {
+        pub fn VirtualProtect(
+            address: *const c_void,
+            size: usize,
+            new_protect: u32,
+            old_protect: *mut u32,
+        ) -> i32;
+        pub fn GetCurrentProcess() -> *mut c_void;
+        pub fn FlushInstructionCache(
+            process: *mut c_void,
+            base_address: *const c_void,
+            size: usize,
+        ) -> i32;
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+mod posix {
+    use std::ffi::{c_int, c_void};
+
+    pub const PROT_READ: c_int = 0x1;
+    pub const PROT_WRITE: c_int = 0x2;
+    pub const PROT_EXEC: c_int = 0x4;
+    pub const PAGE_SIZE: usize = 4096;
+
+    unsafe extern "C" {
+        pub fn mprotect(address: *mut c_void, len: usize, prot: c_int) -> c_int;
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn flush_instruction_cache(address: usize, size: usize) {
+    // SAFETY: Flushing is always sound, the range only narrows what gets invalidated
+    unsafe {
+        win32::FlushInstructionCache(win32::GetCurrentProcess(), address as *const _, size);
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn flush_instruction_cache(_address: usize, _size: usize) {
+    // x86 keeps instruction caches coherent with stores, only ordering against other cores matters
+    fence(Ordering::SeqCst);
 }
 
 struct ProtectionFlags {
-    // TODO
+    #[cfg(target_os = "windows")]
+    old_protect: u32,
 }
 
+#[cfg(target_os = "windows")]
 fn unprotect_address(address: usize, size: usize) -> Result<ProtectionFlags, String> {
-    todo!()
+    let mut old_protect = 0;
+    // SAFETY: VirtualProtect validates the range itself
+    let result = unsafe {
+        win32::VirtualProtect(
+            address as *const _,
+            size,
+            win32::PAGE_EXECUTE_READWRITE,
+            &mut old_protect,
+        )
+    };
+
+    if result == 0 {
+        return Err(format!(
+            "VirtualProtect failed for {:#010X}: {}",
+            address,
+            std::io::Error::last_os_error()
+        ));
+    }
+
+    Ok(ProtectionFlags { old_protect })
 }
 
+#[cfg(target_os = "windows")]
 fn reprotect_address(address: usize, size: usize, flags: ProtectionFlags) -> Result<(), String> {
-    todo!()
+    let mut old_protect = 0;
+    // SAFETY: VirtualProtect validates the range itself
+    let result = unsafe {
+        win32::VirtualProtect(
+            address as *const _,
+            size,
+            flags.old_protect,
+            &mut old_protect,
+        )
+    };
+
+    if result == 0 {
+        return Err(format!(
+            "VirtualProtect failed for {:#010X}: {}",
+            address,
+            std::io::Error::last_os_error()
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "windows"))]
+fn set_protection(address: usize, size: usize, prot: c_int) -> Result<(), String> {
+    let page_start = address & !(posix::PAGE_SIZE - 1);
+    let page_end = (address + size + posix::PAGE_SIZE - 1) & !(posix::PAGE_SIZE - 1);
+
+    // SAFETY: mprotect validates the range itself
+    let result = unsafe { posix::mprotect(page_start as *mut _, page_end - page_start, prot) };
+
+    if result != 0 {
+        return Err(format!(
+            "mprotect failed for {:#010X}: {}",
+            address,
+            std::io::Error::last_os_error()
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "windows"))]
+fn unprotect_address(address: usize, size: usize) -> Result<ProtectionFlags, String> {
+    set_protection(
+        address,
+        size,
+        posix::PROT_READ | posix::PROT_WRITE | posix::PROT_EXEC,
+    )?;
+    Ok(ProtectionFlags {})
+}
+
+/// Page size for callers allocating memory they'll change the protection of.
+#[cfg(target_os = "linux")]
+pub(crate) const PAGE_SIZE: usize = posix::PAGE_SIZE;
+
+/// For code generated into our own memory, which is written before it first runs.
+#[cfg(target_os = "linux")]
+pub(crate) fn make_writable_executable(address: usize, size: usize) -> Result<(), String> {
+    unprotect_address(address, size).map(|_| ())
+}
+
+/// Drops write access again once generated code is final.
+#[cfg(target_os = "linux")]
+pub(crate) fn make_executable(address: usize, size: usize) -> Result<(), String> {
+    reprotect_address(address, size, ProtectionFlags {})?;
+    flush_instruction_cache(address, size);
+    Ok(())
+}
+
+#[cfg(not(target_os = "windows"))]
+fn reprotect_address(address: usize, size: usize, _flags: ProtectionFlags) -> Result<(), String> {
+    // The original flags can't be queried without parsing /proc/self/maps, and code pages are always r-x
+    set_protection(address, size, posix::PROT_READ | posix::PROT_EXEC)
 }
@@ -1,6 +1,11 @@
-use crate::byond::BuildNumber;
+use crate::byond::{BuildNumber, layout::check_procdef_descriptors};
 
-pub static OFFSETS: &'static [Offsets] = platform_offsets();
+pub static OFFSETS: &[Offsets] = platform_offsets();
+
+const _: () = {
+    check_procdef_descriptors(&OFFSETS_WINDOWS);
+    check_procdef_descriptors(&OFFSETS_LINUX);
+};
 
 #[allow(unused)]
 static OFFSETS_WINDOWS: [Offsets; 103] = [
@@ -840,9 +845,40 @@ pub(crate) struct Offsets {
     pub server_tick: usize,
     pub send_maps: usize,
     pub prologue: usize,
+    pub signatures: Option<PrologueSignatures>,
+    pub allocator: Option<AllocatorOffsets>,
+    pub object_tables: Option<ObjectTableOffsets>,
+    pub runtime: Option<RuntimeOffsets>,
+}
+
+/// Known leading bytes of each hooked function, checked against live memory before patching.
+pub(crate) struct PrologueSignatures {
+    pub exec_proc: &'static [u8],
+    pub server_tick: &'static [u8],
+    pub send_maps: &'static [u8],
+}
+
+/// Offsets of byondcore's malloc/free wrappers. prologue is packed the same way as Offsets::prologue.
+pub(crate) struct AllocatorOffsets {
+    pub malloc: usize,
+    pub free: usize,
+    pub prologue: usize,
+}
+
+/// Offsets of the length globals for the datum and list tables, which count allocated slots.
+pub(crate) struct ObjectTableOffsets {
+    pub datums_len: usize,
+    pub lists_len: usize,
+}
+
+/// Offset of the function that reports DM runtime errors. prologue is the byte count to relocate.
+pub(crate) struct RuntimeOffsets {
+    pub runtime: usize,
+    pub prologue: usize,
 }
 
 impl Offsets {
+    #[allow(clippy::too_many_arguments)]
     const fn new(
         byond_build: BuildNumber,
         strings: usize,
@@ -870,13 +906,281 @@ impl Offsets {
             server_tick,
             send_maps,
             prologue,
+            signatures: None,
+            allocator: None,
+            object_tables: None,
+            runtime: None,
+        }
+    }
+
+    const fn with_runtime(mut self, runtime: usize, prologue: usize) -> Self {
+        self.runtime = Some(RuntimeOffsets { runtime, prologue });
+        self
+    }
+
+    const fn with_object_tables(mut self, datums_len: usize, lists_len: usize) -> Self {
+        self.object_tables = Some(ObjectTableOffsets {
+            datums_len,
+            lists_len,
+        });
+        self
+    }
+
+    const fn with_allocator(mut self, malloc: usize, free: usize, prologue: usize) -> Self {
+        self.allocator = Some(AllocatorOffsets {
+            malloc,
+            free,
+            prologue,
+        });
+        self
+    }
+
+    /// Every offset on one line, for identifying which row a capture was taken with.
+    pub fn describe(&self) -> String {
+        let mut description = format!(
+            "build={} strings={:#010X} strings_len={:#010X} miscs={:#010X} miscs_len={:#010X} procdefs={:#010X} procdefs_len={:#010X} procdefs_descriptor={:#010X} exec_proc={:#010X} server_tick={:#010X} send_maps={:#010X} prologue={:#010X}",
+            self.byond_build,
+            self.strings,
+            self.strings_len,
+            self.miscs,
+            self.miscs_len,
+            self.procdefs,
+            self.procdefs_len,
+            self.procdefs_descriptor,
+            self.exec_proc,
+            self.server_tick,
+            self.send_maps,
+            self.prologue,
+        );
+
+        if let Some(allocator) = &self.allocator {
+            description += &format!(
+                " malloc={:#010X} free={:#010X} allocator_prologue={:#010X}",
+                allocator.malloc, allocator.free, allocator.prologue
+            );
+        }
+        if let Some(object_tables) = &self.object_tables {
+            description += &format!(
+                " datums_len={:#010X} lists_len={:#010X}",
+                object_tables.datums_len, object_tables.lists_len
+            );
+        }
+        if let Some(runtime) = &self.runtime {
+            description += &format!(
+                " runtime={:#010X} runtime_prologue={:#04X}",
+                runtime.runtime, runtime.prologue
+            );
+        }
+        if self.signatures.is_some() {
+            description += " signatures=yes";
+        }
+
+        description
+    }
+
+    /// What the row leaves out, so a capture taken with it is never mistaken for a complete one.
+    pub fn limitations(&self) -> Vec<&'static str> {
+        let mut limitations = Vec::new();
+
+        if self.signatures.is_none() {
+            limitations.push(
+                "No prologue signatures for this build, only the first byte of each hook target was checked",
+            );
         }
+        if self.allocator.is_none() {
+            limitations.push("Memory: not tracked, BYOND's allocator isn't mapped for this build");
+        }
+        if self.object_tables.is_none() {
+            limitations
+                .push("Datums and Lists: not plotted, their tables aren't mapped for this build");
+        }
+        if self.runtime.is_none() {
+            limitations.push("Runtimes: not reported, BYOND's handler isn't mapped for this build");
+        }
+
+        limitations
+    }
+
+    const fn with_signatures(
+        mut self,
+        exec_proc: &'static [u8],
+        server_tick: &'static [u8],
+        send_maps: &'static [u8],
+    ) -> Self {
+        self.signatures = Some(PrologueSignatures {
+            exec_proc,
+            server_tick,
+            send_maps,
+        });
+        self
     }
 }
 
+/// No 64-bit build has been mapped yet, so init refuses every build on x86_64. Prologues here must be at
+/// least 14 bytes, the length of the absolute jmp x86_64 hooks patch in.
+#[allow(unused)]
+static OFFSETS_WINDOWS_X64: [Offsets; 0] = [];
+
+#[allow(unused)]
+static OFFSETS_LINUX_X64: [Offsets; 0] = [];
+
+/// Matches the layout of the fake libbyond.so, see tests/fake-byond/src/fake.rs. No real build is as old
+/// as the build number it reports. Only used with the fake-byond feature.
+#[cfg(target_arch = "x86")]
+#[allow(unused)]
+static OFFSETS_FAKE: [Offsets; 1] = [
+    /*                                strings     strings_len miscs       miscs_len   procdefs   procdefs_len procdef     exec_proc   server_tick send_maps   prologue */
+    Offsets::new(
+        1, 0x01000000, 0x01000004, 0x01000010, 0x01000014, 0x01000020, 0x01000024, 0x00180024,
+        0x01001000, 0x01001020, 0x01001040, 0x00050505,
+    )
+    .with_signatures(FAKE_PROLOGUE, FAKE_PROLOGUE, FAKE_PROLOGUE)
+    .with_allocator(0x01001060, 0x01001080, 0x0505)
+    .with_object_tables(0x01000030, 0x01000034)
+    .with_runtime(0x010010A0, 0x05),
+];
+
+#[cfg(target_arch = "x86_64")]
+#[allow(unused)]
+static OFFSETS_FAKE: [Offsets; 1] = [
+    /*                                strings     strings_len miscs       miscs_len   procdefs   procdefs_len procdef     exec_proc   server_tick send_maps   prologue */
+    Offsets::new(
+        1, 0x01000000, 0x01000008, 0x01000010, 0x01000018, 0x01000020, 0x01000028, 0x00180024,
+        0x01001000, 0x01001020, 0x01001040, 0x000E0E0E,
+    )
+    .with_signatures(FAKE_PROLOGUE, FAKE_PROLOGUE, FAKE_PROLOGUE)
+    .with_allocator(0x01001060, 0x01001080, 0x0E0E)
+    .with_object_tables(0x01000030, 0x01000038)
+    .with_runtime(0x010010A0, 0x0E),
+];
+
+/// The no-ops every hooked function in the fake opens with.
+#[cfg(target_arch = "x86")]
+const FAKE_PROLOGUE: &[u8] = &[0x0F, 0x1F, 0x44, 0x00, 0x00];
+
+#[cfg(target_arch = "x86_64")]
+const FAKE_PROLOGUE: &[u8] = &[
+    0x66, 0x0F, 0x1F, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0F, 0x1F, 0x44, 0x00, 0x00,
+];
+
 const fn platform_offsets() -> &'static [Offsets] {
-    #[cfg(target_os = "windows")]
+    #[cfg(feature = "fake-byond")]
+    return &OFFSETS_FAKE;
+    #[cfg(all(
+        not(feature = "fake-byond"),
+        target_os = "windows",
+        target_arch = "x86"
+    ))]
     return &OFFSETS_WINDOWS;
-    #[cfg(not(target_os = "windows"))]
+    #[cfg(all(
+        not(feature = "fake-byond"),
+        not(target_os = "windows"),
+        target_arch = "x86"
+    ))]
     return &OFFSETS_LINUX;
+    #[cfg(all(
+        not(feature = "fake-byond"),
+        target_os = "windows",
+        target_arch = "x86_64"
+    ))]
+    return &OFFSETS_WINDOWS_X64;
+    #[cfg(all(
+        not(feature = "fake-byond"),
+        not(target_os = "windows"),
+        target_arch = "x86_64"
+    ))]
+    return &OFFSETS_LINUX_X64;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OFFSETS_FAKE, OFFSETS_LINUX, OFFSETS_WINDOWS};
+    use crate::byond::verify_prologue;
+
+    /// The fake's exec_proc as the hooks find it, its prologue followed by a jmp.
+    fn fake_exec_proc() -> Vec<u8> {
+        let signatures = OFFSETS_FAKE[0].signatures.as_ref().unwrap();
+        [signatures.exec_proc, &[0xE9, 0x00, 0x00, 0x00, 0x00]].concat()
+    }
+
+    fn verify_fake_exec_proc(address: usize) -> Result<(), String> {
+        let offsets = &OFFSETS_FAKE[0];
+        let signatures = offsets.signatures.as_ref().unwrap();
+        // SAFETY: Only called with addresses inside fake_exec_proc, which has room for the prologue
+        unsafe {
+            verify_prologue(
+                address,
+                offsets.prologue & 0xFF,
+                Some(signatures.exec_proc),
+                "exec_proc",
+            )
+        }
+    }
+
+    #[test]
+    fn matching_signatures_are_accepted() {
+        let function = fake_exec_proc();
+
+        assert_eq!(verify_fake_exec_proc(function.as_ptr() as usize), Ok(()));
+    }
+
+    #[test]
+    fn offsets_off_the_signature_are_refused() {
+        let function = fake_exec_proc();
+
+        // As if the row were for a neighbouring build that moved the function by a byte
+        let error = verify_fake_exec_proc(function.as_ptr() as usize + 1).unwrap_err();
+        assert!(
+            error.starts_with("Prologue mismatch for exec_proc"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn unsigned_prologues_starting_with_a_branch_are_refused() {
+        let function = [0xE9; 32];
+        let prologue = OFFSETS_FAKE[0].prologue & 0xFF;
+
+        // SAFETY: function has room for any prologue
+        let error =
+            unsafe { verify_prologue(function.as_ptr() as usize, prologue, None, "runtime") }
+                .unwrap_err();
+        assert!(
+            error.starts_with("Prologue mismatch for runtime"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn rows_name_what_they_leave_out() {
+        let offsets = &OFFSETS_LINUX[0];
+
+        assert!(
+            offsets
+                .limitations()
+                .iter()
+                .any(|limitation| limitation.starts_with("Datums and Lists: not plotted"))
+        );
+        assert!(
+            offsets
+                .limitations()
+                .iter()
+                .any(|limitation| limitation.starts_with("Runtimes: not reported"))
+        );
+    }
+
+    #[test]
+    fn unsigned_rows_say_so() {
+        assert_eq!(OFFSETS_FAKE[0].limitations(), Vec::<&str>::new());
+
+        for offsets in OFFSETS_WINDOWS.iter().chain(&OFFSETS_LINUX) {
+            assert!(
+                offsets.limitations()[0].starts_with("No prologue signatures"),
+                "build {}",
+                offsets.byond_build
+            );
+        }
+    }
 }
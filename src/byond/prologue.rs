@@ -0,0 +1,249 @@
+//! On x86_64 the prologue a hook displaces is copied to the trampoline as is, so anything addressed
+//! relative to the instruction pointer would be read from, or jump to, the wrong place once it runs
+//! there. This decodes just enough of the instructions prologues are made of to find those, and
+//! refuses anything it doesn't recognise rather than guess at its length.
+
+/// Prefixes that may come before the opcode, REX excluded.
+const LEGACY_PREFIXES: [u8; 11] = [
+    0x26, 0x2E, 0x36, 0x3E, 0x64, 0x65, 0x66, 0x67, 0xF0, 0xF2, 0xF3,
+];
+
+const OPERAND_SIZE_PREFIX: u8 = 0x66;
+
+/// What follows an opcode.
+#[derive(Clone, Copy)]
+struct Operands {
+    modrm: bool,
+    /// Bytes of immediate, with 4 meaning 2 under an operand size prefix
+    immediate: usize,
+}
+
+const NONE: Operands = Operands {
+    modrm: false,
+    immediate: 0,
+};
+const MODRM: Operands = Operands {
+    modrm: true,
+    immediate: 0,
+};
+const MODRM_IMM8: Operands = Operands {
+    modrm: true,
+    immediate: 1,
+};
+const MODRM_IMM32: Operands = Operands {
+    modrm: true,
+    immediate: 4,
+};
+const IMM8: Operands = Operands {
+    modrm: false,
+    immediate: 1,
+};
+const IMM32: Operands = Operands {
+    modrm: false,
+    immediate: 4,
+};
+
+/// Returns an error naming the first instruction in code that couldn't run from a trampoline, or
+/// couldn't be decoded. code must hold whole instructions.
+pub(super) fn check_relocatable(code: &[u8]) -> Result<(), String> {
+    let mut offset = 0;
+    while offset < code.len() {
+        offset = next_instruction(code, offset)?;
+    }
+    Ok(())
+}
+
+/// Returns where the instruction at start ends.
+fn next_instruction(code: &[u8], start: usize) -> Result<usize, String> {
+    let past_end = || {
+        format!(
+            "the instruction at byte {} runs past the end of the prologue",
+            start
+        )
+    };
+    let byte_at = |offset: usize| code.get(offset).copied().ok_or_else(past_end);
+
+    let mut offset = start;
+    let mut operand_size_prefix = false;
+    while LEGACY_PREFIXES.contains(&byte_at(offset)?) {
+        operand_size_prefix |= code[offset] == OPERAND_SIZE_PREFIX;
+        offset += 1;
+    }
+    let mut rex_w = false;
+    if (0x40..=0x4F).contains(&byte_at(offset)?) {
+        rex_w = code[offset] & 0x08 != 0;
+        offset += 1;
+    }
+
+    let opcode = byte_at(offset)?;
+    offset += 1;
+    let (operands, relative_branch) = if opcode == 0x0F {
+        let opcode = byte_at(offset)?;
+        offset += 1;
+        (two_byte_operands(opcode), (0x80..=0x8F).contains(&opcode))
+    } else {
+        let relative_branch = matches!(opcode, 0x70..=0x7F | 0xE0..=0xE3 | 0xE8 | 0xE9 | 0xEB);
+        (one_byte_operands(opcode, rex_w), relative_branch)
+    };
+    let operands = operands.ok_or_else(|| match relative_branch {
+        true => format!(
+            "the branch at byte {} is relative to the instruction pointer",
+            start
+        ),
+        false => format!(
+            "the instruction at byte {} ({}) isn't one byond-tracy can relocate",
+            start,
+            super::format_bytes(&code[start..offset])
+        ),
+    })?;
+
+    let mut immediate = operands.immediate;
+    if operands.modrm {
+        let modrm = byte_at(offset)?;
+        offset += 1;
+        let (mode, rm) = (modrm >> 6, modrm & 0x07);
+
+        // F6 and F7 only take an immediate as test, which is /0 and /1
+        if matches!(opcode, 0xF6 | 0xF7) && (modrm >> 3) & 0x07 > 1 {
+            immediate = 0;
+        }
+
+        if mode != 0b11 {
+            let mut base = rm;
+            if rm == 0b100 {
+                base = byte_at(offset)? & 0x07;
+                offset += 1;
+            }
+            if (mode, rm) == (0b00, 0b101) {
+                return Err(format!(
+                    "the instruction at byte {} addresses memory relative to the instruction pointer",
+                    start
+                ));
+            }
+            offset += match (mode, base) {
+                (0b00, 0b101) | (0b10, _) => 4,
+                (0b01, _) => 1,
+                _ => 0,
+            };
+        }
+    }
+    if immediate == 4 && operand_size_prefix {
+        immediate = 2;
+    }
+    offset += immediate;
+
+    if offset > code.len() {
+        return Err(past_end());
+    }
+    Ok(offset)
+}
+
+/// None for opcodes that aren't recognised, and for branches relative to the instruction pointer.
+fn one_byte_operands(opcode: u8, rex_w: bool) -> Option<Operands> {
+    Some(match opcode {
+        // add, or, adc, sbb, and, sub, xor and cmp
+        0x00..=0x3F => match opcode & 0x07 {
+            0..=3 => MODRM,
+            4 => IMM8,
+            5 => IMM32,
+            _ => return None,
+        },
+        // push and pop
+        0x50..=0x5F => NONE,
+        // movsxd
+        0x63 => MODRM,
+        0x68 => IMM32,
+        0x69 => MODRM_IMM32,
+        0x6A => IMM8,
+        0x6B => MODRM_IMM8,
+        0x80 | 0x83 | 0xC0 | 0xC1 | 0xC6 => MODRM_IMM8,
+        0x81 | 0xC7 => MODRM_IMM32,
+        // test, xchg, mov and lea
+        0x84..=0x8B | 0x8D => MODRM,
+        // nop
+        0x90 => NONE,
+        0xA8 => IMM8,
+        0xA9 => IMM32,
+        0xB0..=0xB7 => IMM8,
+        // mov r64, imm64 under REX.W
+        0xB8..=0xBF if rex_w => Operands {
+            modrm: false,
+            immediate: 8,
+        },
+        0xB8..=0xBF => IMM32,
+        0xD1 | 0xD3 => MODRM,
+        0xF6 => MODRM_IMM8,
+        0xF7 => MODRM_IMM32,
+        0xFE | 0xFF => MODRM,
+        _ => return None,
+    })
+}
+
+/// Opcodes following 0x0F. None for opcodes that aren't recognised, and for jcc rel32.
+fn two_byte_operands(opcode: u8) -> Option<Operands> {
+    Some(match opcode {
+        // SSE moves, multi byte nops, cmovcc, SSE arithmetic, movd and movq
+        0x10..=0x17 | 0x1F | 0x28..=0x2F | 0x40..=0x4F | 0x51..=0x6F | 0x7E | 0x7F => MODRM,
+        // imul, movzx and movsx
+        0xAF | 0xB6 | 0xB7 | 0xBE | 0xBF => MODRM,
+        0xD6 | 0xEF => MODRM,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check_relocatable;
+
+    #[test]
+    fn ordinary_prologues_are_relocatable() {
+        #[rustfmt::skip]
+        let prologues: [&[u8]; 3] = [
+            // The fake's, nop word [rax + rax], nop dword [rax + rax]
+            &[0x66, 0x0F, 0x1F, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0F, 0x1F, 0x44, 0x00, 0x00],
+            // push rbp, mov rbp, rsp, push r15, push r14, sub rsp, 0x28
+            &[0x55, 0x48, 0x89, 0xE5, 0x41, 0x57, 0x41, 0x56, 0x48, 0x83, 0xEC, 0x28],
+            // mov rax, imm64, lea rcx, [rsp + 0x10]
+            &[0x48, 0xB8, 1, 2, 3, 4, 5, 6, 7, 8, 0x48, 0x8D, 0x4C, 0x24, 0x10],
+        ];
+
+        for prologue in prologues {
+            assert_eq!(check_relocatable(prologue), Ok(()));
+        }
+    }
+
+    #[test]
+    fn instruction_pointer_relative_operands_are_refused() {
+        // push rbp, mov rax, [rip + 0x1234]
+        let error =
+            check_relocatable(&[0x55, 0x48, 0x8B, 0x05, 0x34, 0x12, 0x00, 0x00]).unwrap_err();
+        assert_eq!(
+            error,
+            "the instruction at byte 1 addresses memory relative to the instruction pointer"
+        );
+
+        // push rbp, je rel8
+        let error = check_relocatable(&[0x55, 0x74, 0x10]).unwrap_err();
+        assert_eq!(
+            error,
+            "the branch at byte 1 is relative to the instruction pointer"
+        );
+    }
+
+    #[test]
+    fn partial_and_unknown_instructions_are_refused() {
+        // sub rsp, imm32 cut short
+        let error = check_relocatable(&[0x48, 0x81, 0xEC, 0x00, 0x01]).unwrap_err();
+        assert_eq!(
+            error,
+            "the instruction at byte 0 runs past the end of the prologue"
+        );
+
+        // int3
+        let error = check_relocatable(&[0xCC]).unwrap_err();
+        assert_eq!(
+            error,
+            "the instruction at byte 0 (CC) isn't one byond-tracy can relocate"
+        );
+    }
+}
@@ -0,0 +1,84 @@
+//! BYOND's Linux build calls exec_proc with GCC's regparm(3), which Rust has no ABI for. exec_proc
+//! returns a DreamObject, so the pointer to write it to is passed in eax and the proc in edx, and the
+//! pointer is handed back in eax. These shims move between that and cdecl, so everything else can
+//! treat ExecProcFunction as cdecl.
+
+use std::{
+    arch::naked_asm,
+    mem::{MaybeUninit, transmute},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use super::{DreamObject, ExecProcFunction, Proc};
+
+/// The cdecl hook entry forwards to.
+static HOOK: AtomicUsize = AtomicUsize::new(0);
+
+/// The regparm(3) function original calls.
+static ORIGINAL: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns the address of a regparm(3) function that calls hook, to patch into exec_proc.
+pub(super) fn wrap_hook(hook: ExecProcFunction) -> usize {
+    HOOK.store(hook as usize, Ordering::Release);
+    entry as *const () as usize
+}
+
+/// Returns a cdecl function that calls the regparm(3) function at address.
+pub(super) fn wrap_original(address: usize) -> ExecProcFunction {
+    ORIGINAL.store(address, Ordering::Release);
+    original
+}
+
+/// The regparm(3) side of wrap_hook. Never call it from Rust.
+#[unsafe(naked)]
+unsafe extern "C" fn entry() {
+    naked_asm!(
+        // Passing eax, edx and ecx on the stack also keeps it 16 byte aligned for the call
+        "push ecx",
+        "push edx",
+        "push eax",
+        "call {forward}",
+        // Hand the result pointer back in eax
+        "pop eax",
+        "add esp, 8",
+        "ret",
+        forward = sym forward_to_hook,
+    )
+}
+
+unsafe extern "C" fn forward_to_hook(result: *mut DreamObject, proc: *const Proc) {
+    // SAFETY: HOOK is set before entry is patched in, and BYOND passes a pointer to write the result to
+    unsafe {
+        let hook: ExecProcFunction = transmute(HOOK.load(Ordering::Acquire));
+        result.write(hook(proc));
+    }
+}
+
+unsafe extern "C" fn original(proc: *const Proc) -> DreamObject {
+    let mut result = MaybeUninit::<DreamObject>::uninit();
+    // SAFETY: ORIGINAL is set before original is handed out, and exec_proc writes the result
+    unsafe {
+        call_regparm3(
+            ORIGINAL.load(Ordering::Acquire),
+            result.as_mut_ptr() as usize,
+            proc as usize,
+            0,
+        );
+        result.assume_init()
+    }
+}
+
+/// Calls the regparm(3) function at function with the given eax, edx and ecx.
+#[unsafe(naked)]
+unsafe extern "C" fn call_regparm3(function: usize, eax: usize, edx: usize, ecx: usize) {
+    naked_asm!(
+        "mov eax, [esp + 8]",
+        "mov edx, [esp + 12]",
+        "mov ecx, [esp + 16]",
+        // Keep the stack 16 byte aligned for the call
+        "sub esp, 12",
+        "call [esp + 16]",
+        "add esp, 12",
+        "ret",
+    )
+}
@@ -0,0 +1,494 @@
+//! Exports for the byondapi calling convention used by call_ext()() on BYOND 515 and later, which
+//! passes arguments and results as native values. Each one mirrors the legacy export without the _ext suffix.
+
+use std::{
+    ffi::{CString, c_char},
+    slice,
+    sync::OnceLock,
+};
+
+use crate::{
+    INSTANCE, Instance,
+    config::{Config, InitArg, Value},
+    exports, get_byondcore_handle, init_core,
+};
+
+const NULL_TYPE: u8 = 0x00;
+const LIST_TYPE: u8 = 0x0F;
+const NUMBER_TYPE: u8 = 0x2A;
+
+/// Returned in place of an error byondapi couldn't make a string of, since null means success.
+const UNREPORTABLE_ERROR: f32 = -1.0;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+union ByondValueData {
+    reference: u32,
+    number: f32,
+}
+
+/// Mirrors CByondValue from byondapi.h.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct CByondValue {
+    value_type: u8,
+    junk: [u8; 3],
+    data: ByondValueData,
+}
+
+type ToStringFunction = unsafe extern "C" fn(*const CByondValue, *mut c_char, *mut u32) -> bool;
+
+type SetStrFunction = unsafe extern "C" fn(*mut CByondValue, *const c_char);
+
+type CreateListFunction = unsafe extern "C" fn(*mut CByondValue) -> bool;
+
+type WriteListFunction = unsafe extern "C" fn(*const CByondValue, *const CByondValue, u32) -> bool;
+
+/// Byond_ReadList and Byond_ReadListAssoc, which share a signature.
+type ReadListFunction =
+    unsafe extern "C" fn(*const CByondValue, *mut CByondValue, *mut u32) -> bool;
+
+/// The handful of byondapi functions byondcore exports that we need to move strings and lists across.
+struct ByondApi {
+    to_string: ToStringFunction,
+    set_str: SetStrFunction,
+    create_list: CreateListFunction,
+    write_list: WriteListFunction,
+    read_list: ReadListFunction,
+    read_list_assoc: ReadListFunction,
+}
+
+static BYOND_API: OnceLock<Result<ByondApi, String>> = OnceLock::new();
+
+fn byond_api() -> Result<&'static ByondApi, String> {
+    BYOND_API
+        .get_or_init(|| {
+            let byondcore_handle = get_byondcore_handle()?;
+
+            // SAFETY: These are the documented signatures from byondapi.h
+            unsafe {
+                let to_string = byondcore_handle
+                    .get::<ToStringFunction>(b"Byond_ToString")
+                    .map_err(|error| format!("Unable to find symbol Byond_ToString: {}", error))?;
+                let set_str = byondcore_handle
+                    .get::<SetStrFunction>(b"ByondValue_SetStr")
+                    .map_err(|error| {
+                        format!("Unable to find symbol ByondValue_SetStr: {}", error)
+                    })?;
+                let create_list = byondcore_handle
+                    .get::<CreateListFunction>(b"Byond_CreateList")
+                    .map_err(|error| {
+                        format!("Unable to find symbol Byond_CreateList: {}", error)
+                    })?;
+                let write_list = byondcore_handle
+                    .get::<WriteListFunction>(b"Byond_WriteList")
+                    .map_err(|error| format!("Unable to find symbol Byond_WriteList: {}", error))?;
+                let read_list = byondcore_handle
+                    .get::<ReadListFunction>(b"Byond_ReadList")
+                    .map_err(|error| format!("Unable to find symbol Byond_ReadList: {}", error))?;
+                let read_list_assoc = byondcore_handle
+                    .get::<ReadListFunction>(b"Byond_ReadListAssoc")
+                    .map_err(|error| {
+                        format!("Unable to find symbol Byond_ReadListAssoc: {}", error)
+                    })?;
+
+                Ok(ByondApi {
+                    to_string: *to_string,
+                    set_str: *set_str,
+                    create_list: *create_list,
+                    write_list: *write_list,
+                    read_list: *read_list,
+                    read_list_assoc: *read_list_assoc,
+                })
+            }
+        })
+        .as_ref()
+        .map_err(|error| error.clone())
+}
+
+impl CByondValue {
+    pub fn null() -> Self {
+        Self {
+            value_type: NULL_TYPE,
+            junk: [0; 3],
+            data: ByondValueData { reference: 0 },
+        }
+    }
+
+    pub fn number(number: f32) -> Self {
+        Self {
+            value_type: NUMBER_TYPE,
+            junk: [0; 3],
+            data: ByondValueData { number },
+        }
+    }
+
+    pub fn string(string: &str) -> Result<Self, String> {
+        let api = byond_api()?;
+
+        let mut value = Self::null();
+        // Panicking over an FFI boundary is bad form, so if a NUL ends up
+        // in the result, just truncate.
+        let cstring =
+            CString::new(string.split('\0').next().unwrap_or_default()).unwrap_or_default();
+
+        // SAFETY: value is a valid CByondValue and cstring outlives the call
+        unsafe { (api.set_str)(&mut value, cstring.as_ptr()) };
+
+        // SetStr returns nothing, it just leaves the value alone when it fails
+        if value.is_null() {
+            return Err("ByondValue_SetStr failed".to_string());
+        }
+        Ok(value)
+    }
+
+    /// The error as text, or UNREPORTABLE_ERROR if byondapi can't make it one.
+    pub fn error(error: &str) -> Self {
+        Self::string(error).unwrap_or(Self::number(UNREPORTABLE_ERROR))
+    }
+
+    /// A new list holding items.
+    pub fn list(items: &[CByondValue]) -> Result<Self, String> {
+        let api = byond_api()?;
+
+        let mut list = Self::null();
+        // SAFETY: list is a valid CByondValue to write the new reference into
+        if !unsafe { (api.create_list)(&mut list) } {
+            return Err("Byond_CreateList failed".to_string());
+        }
+
+        // SAFETY: items is items.len() values long
+        if !unsafe { (api.write_list)(&list, items.as_ptr(), items.len() as u32) } {
+            return Err("Byond_WriteList failed".to_string());
+        }
+
+        Ok(list)
+    }
+
+    pub fn is_null(&self) -> bool {
+        self.value_type == NULL_TYPE
+    }
+
+    pub fn is_list(&self) -> bool {
+        self.value_type == LIST_TYPE
+    }
+
+    /// Every item of a list.
+    pub fn list_items(&self) -> Result<Vec<CByondValue>, String> {
+        self.read_list(byond_api()?.read_list, "Byond_ReadList")
+    }
+
+    /// Every key of a list with its associated value, null where it has none.
+    pub fn list_pairs(&self) -> Result<Vec<(CByondValue, CByondValue)>, String> {
+        let flat = self.read_list(byond_api()?.read_list_assoc, "Byond_ReadListAssoc")?;
+
+        Ok(flat
+            .chunks_exact(2)
+            .map(|pair| (pair[0], pair[1]))
+            .collect())
+    }
+
+    fn read_list(&self, read: ReadListFunction, name: &str) -> Result<Vec<CByondValue>, String> {
+        let mut length = 0;
+        // SAFETY: A null buffer with a length of zero asks for the required length
+        unsafe { read(self, std::ptr::null_mut(), &mut length) };
+
+        let mut values = vec![Self::null(); length as usize];
+        // SAFETY: values is length values long
+        if !unsafe { read(self, values.as_mut_ptr(), &mut length) } {
+            return Err(format!("{} failed", name));
+        }
+
+        values.truncate(length as usize);
+        Ok(values)
+    }
+
+    pub fn as_number(&self) -> Option<f32> {
+        // SAFETY: Any 4 bytes are a valid f32, the tag decides whether the number means anything
+        (self.value_type == NUMBER_TYPE).then_some(unsafe { self.data.number })
+    }
+
+    /// Stringifies the value the same way DM's "[]" would.
+    pub fn to_string(self) -> Result<String, String> {
+        let api = byond_api()?;
+
+        let mut length = 0;
+        // SAFETY: A null buffer with a length of zero asks for the required length
+        unsafe { (api.to_string)(&self, std::ptr::null_mut(), &mut length) };
+
+        let mut buffer = vec![0u8; length as usize];
+        // SAFETY: buffer is length bytes long
+        if !unsafe { (api.to_string)(&self, buffer.as_mut_ptr().cast(), &mut length) } {
+            return Err("Byond_ToString failed".to_string());
+        }
+
+        let terminator = buffer
+            .iter()
+            .position(|byte| *byte == 0)
+            .unwrap_or(buffer.len());
+        buffer.truncate(terminator);
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+}
+
+/// SAFETY: This function must only be called via the call_ext()() proc using the byondapi calling convention
+/// of a game running using Build Your Own Net Dream (BYOND, https://www.byond.com/).
+/// Takes the same configuration arguments as init, or assoc lists of settings whose numbers and lists
+/// are read as they are, like list("port" = 9000, "include" = list("/datum")).
+/// Returns 1 if this call initialized the profiler, 0 if it was already running, or the error as text.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn init_ext(argc: u32, argv: *const CByondValue) -> CByondValue {
+    // SAFETY: Forwarded from our caller
+    let args = unsafe { values(argc, argv) };
+    let result = args
+        .iter()
+        .map(init_arg)
+        .collect::<Result<Vec<_>, _>>()
+        .and_then(|args| Config::load(&args))
+        .and_then(init_core);
+
+    match result {
+        Ok(true) => CByondValue::number(1.0),
+        Ok(false) => CByondValue::number(0.0),
+        Err(error) => CByondValue::error(&error),
+    }
+}
+
+/// SAFETY: See init_ext
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn tracy_message_ext(argc: u32, argv: *const CByondValue) -> CByondValue {
+    // SAFETY: Forwarded from our caller
+    let args = unsafe { values(argc, argv) };
+    with_instance(|instance| {
+        let text = required_arg(args, 0, "text")?.to_string()?;
+        let color = match args.get(1) {
+            Some(color) if color.is_null() => 0,
+            Some(color) => match color.as_number() {
+                Some(number) => number_color(number)?,
+                None => exports::parse_color(&color.to_string()?)?,
+            },
+            None => 0,
+        };
+
+        exports::message(instance, &text, color);
+        Ok(())
+    })
+}
+
+/// SAFETY: See init_ext
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn tracy_plot_ext(argc: u32, argv: *const CByondValue) -> CByondValue {
+    // SAFETY: Forwarded from our caller
+    let args = unsafe { values(argc, argv) };
+    with_instance(|instance| {
+        let name = required_arg(args, 0, "name")?.to_string()?;
+        let value = required_arg(args, 1, "value")?
+            .as_number()
+            .ok_or("Plot value must be a number")?;
+
+        exports::plot(instance, &name, value.into());
+        Ok(())
+    })
+}
+
+/// SAFETY: See init_ext
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn tracy_zone_begin_ext(argc: u32, argv: *const CByondValue) -> CByondValue {
+    // SAFETY: Forwarded from our caller
+    let args = unsafe { values(argc, argv) };
+    with_instance(|instance| {
+        let name = required_arg(args, 0, "name")?.to_string()?;
+
+        exports::zone_begin(instance, &name);
+        Ok(())
+    })
+}
+
+/// SAFETY: See init_ext
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn tracy_zone_end_ext(_argc: u32, _argv: *const CByondValue) -> CByondValue {
+    with_instance(exports::zone_end)
+}
+
+/// SAFETY: See init_ext
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn tracy_frame_mark_ext(argc: u32, argv: *const CByondValue) -> CByondValue {
+    // SAFETY: Forwarded from our caller
+    let args = unsafe { values(argc, argv) };
+    with_instance(|instance| {
+        let name = match args.first() {
+            Some(name) if !name.is_null() => Some(name.to_string()?),
+            _ => None,
+        };
+
+        exports::frame_mark(instance, name.as_deref());
+        Ok(())
+    })
+}
+
+/// SAFETY: See init_ext
+/// Returns the procs on this thread's callstack as a list of text, innermost first. See get_callstack.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn get_callstack_ext(_argc: u32, _argv: *const CByondValue) -> CByondValue {
+    let Some(instance) = INSTANCE.get() else {
+        return CByondValue::error("not initialized");
+    };
+
+    exports::callstack_names(instance)
+        .iter()
+        .map(|name| CByondValue::string(name))
+        .collect::<Result<Vec<_>, _>>()
+        .and_then(|names| CByondValue::list(&names))
+        .unwrap_or_else(|error| CByondValue::error(&error))
+}
+
+/// SAFETY: See init_ext
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn write_samples_ext(argc: u32, argv: *const CByondValue) -> CByondValue {
+    // SAFETY: Forwarded from our caller
+    let args = unsafe { values(argc, argv) };
+    with_instance(|instance| {
+        let path = required_arg(args, 0, "path")?.to_string()?;
+
+        exports::write_samples_to(instance, &path)
+    })
+}
+
+/// SAFETY: See init_ext
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn write_call_tree_ext(argc: u32, argv: *const CByondValue) -> CByondValue {
+    // SAFETY: Forwarded from our caller
+    let args = unsafe { values(argc, argv) };
+    with_instance(|instance| {
+        let path = required_arg(args, 0, "path")?.to_string()?;
+
+        exports::write_call_tree_to(instance, &path)
+    })
+}
+
+/// SAFETY: See init_ext
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn write_pprof_ext(argc: u32, argv: *const CByondValue) -> CByondValue {
+    // SAFETY: Forwarded from our caller
+    let args = unsafe { values(argc, argv) };
+    with_instance(|instance| {
+        let path = required_arg(args, 0, "path")?.to_string()?;
+
+        exports::write_pprof_to(instance, &path)
+    })
+}
+
+/// SAFETY: See init_ext
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn dump_flight_recorder_ext(
+    argc: u32,
+    argv: *const CByondValue,
+) -> CByondValue {
+    // SAFETY: Forwarded from our caller
+    let args = unsafe { values(argc, argv) };
+    with_instance(|instance| {
+        let path = required_arg(args, 0, "path")?.to_string()?;
+        let seconds = match args.get(1) {
+            Some(seconds) if seconds.is_null() => None,
+            Some(seconds) => Some(
+                seconds
+                    .as_number()
+                    .ok_or("Seconds must be a number")?
+                    .into(),
+            ),
+            None => None,
+        };
+
+        exports::dump_flight_recorder_to(instance, &path, seconds)
+    })
+}
+
+/// SAFETY: argv must point to argc valid values
+/// Assoc lists are taken as settings, anything else as text parsed like init's arguments.
+fn init_arg(arg: &CByondValue) -> Result<InitArg, String> {
+    if !arg.is_list() {
+        return Ok(InitArg::Text(arg.to_string()?));
+    }
+
+    arg.list_pairs()?
+        .into_iter()
+        .map(|(key, value)| Ok((key.to_string()?, setting_value(&value)?)))
+        .collect::<Result<_, String>>()
+        .map(InitArg::Settings)
+}
+
+fn setting_value(value: &CByondValue) -> Result<Value, String> {
+    if let Some(number) = value.as_number() {
+        return Ok(Value::Number(number.into()));
+    }
+
+    if value.is_list() {
+        return value
+            .list_items()?
+            .into_iter()
+            .map(CByondValue::to_string)
+            .collect::<Result<_, _>>()
+            .map(Value::List);
+    }
+
+    value.to_string().map(Value::String)
+}
+
+/// Colors are whole numbers from 0 to 0xFFFFFF, which an f32 holds exactly.
+fn number_color(number: f32) -> Result<u32, String> {
+    if number < 0.0 || number.fract() != 0.0 || number > 0xFFFFFF as f32 {
+        return Err(format!("Invalid color: {}", number));
+    }
+
+    Ok(number as u32)
+}
+
+unsafe fn values<'a>(argc: u32, argv: *const CByondValue) -> &'a [CByondValue] {
+    if argc == 0 || argv.is_null() {
+        return &[];
+    }
+
+    // SAFETY: Guaranteed by our caller
+    unsafe { slice::from_raw_parts(argv, argc as usize) }
+}
+
+fn required_arg<'a>(
+    args: &'a [CByondValue],
+    index: usize,
+    name: &str,
+) -> Result<&'a CByondValue, String> {
+    match args.get(index) {
+        Some(arg) => Ok(arg),
+        None => Err(format!("Missing argument {}: {}", index + 1, name)),
+    }
+}
+
+/// Successful calls return null so DM can test the result with a plain if().
+fn with_instance(action: impl FnOnce(&Instance) -> Result<(), String>) -> CByondValue {
+    let Some(instance) = INSTANCE.get() else {
+        return CByondValue::error("not initialized");
+    };
+
+    match action(instance) {
+        Ok(()) => CByondValue::null(),
+        Err(error) => CByondValue::error(&error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::number_color;
+
+    #[test]
+    fn number_colors_must_be_whole_and_in_range() {
+        assert_eq!(number_color(0.0), Ok(0));
+        assert_eq!(number_color(16777215.0), Ok(0xFFFFFF));
+
+        for number in [-1.0, 0.5, 16777216.0, f32::NAN, f32::INFINITY] {
+            assert_eq!(
+                number_color(number),
+                Err(format!("Invalid color: {}", number))
+            );
+        }
+    }
+}
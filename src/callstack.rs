@@ -0,0 +1,190 @@
+//! A shadow of the DM callstack, maintained by exec_proc_hook_core.
+//!
+//! A proc that sleeps returns out of exec_proc and is later resumed by a fresh exec_proc call from the
+//! scheduler, so the shadow stack follows the native one: a resumed proc shows up without the callers
+//! it had before sleeping, and is flagged as resumed.
+
+use std::sync::{
+    Arc, Mutex,
+    atomic::{AtomicU32, AtomicUsize, Ordering},
+};
+
+/// Frames past this depth are counted but not recorded.
+pub(crate) const MAX_DEPTH: usize = 1024;
+
+const RESUMED_FLAG: u32 = 1 << 31;
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct StackEntry {
+    pub procdef: usize,
+    pub resumed: bool,
+}
+
+/// One thread's stack. Entries are atomics so a reader on another thread can copy it while the owner
+/// pushes and pops, at the cost of possibly tearing at the top of the stack.
+pub(crate) struct ShadowStack {
+    depth: AtomicUsize,
+    entries: [AtomicU32; MAX_DEPTH],
+}
+
+/// Pushed by enter() and popped when dropped.
+pub(crate) struct CallFrame {
+    stack: Arc<ShadowStack>,
+}
+
+static STACKS: Mutex<Vec<Arc<ShadowStack>>> = Mutex::new(Vec::new());
+
+thread_local! {
+    static STACK: Arc<ShadowStack> = {
+        let stack = Arc::new(ShadowStack::new());
+        STACKS
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(stack.clone());
+        stack
+    };
+}
+
+impl ShadowStack {
+    fn new() -> Self {
+        Self {
+            depth: AtomicUsize::new(0),
+            entries: std::array::from_fn(|_| AtomicU32::new(0)),
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::Acquire)
+    }
+
+    /// Copies the recorded frames, outermost first.
+    pub fn snapshot(&self) -> Vec<StackEntry> {
+        let depth = self.depth().min(MAX_DEPTH);
+        self.entries[..depth].iter().map(Self::decode).collect()
+    }
+
+    fn decode(entry: &AtomicU32) -> StackEntry {
+        let value = entry.load(Ordering::Relaxed);
+        StackEntry {
+            procdef: (value & !RESUMED_FLAG) as usize,
+            resumed: value & RESUMED_FLAG != 0,
+        }
+    }
+
+    fn push(&self, entry: StackEntry) {
+        let depth = self.depth.load(Ordering::Relaxed);
+        if depth < MAX_DEPTH {
+            let value = entry.procdef as u32 | if entry.resumed { RESUMED_FLAG } else { 0 };
+            self.entries[depth].store(value, Ordering::Relaxed);
+        }
+        self.depth.store(depth + 1, Ordering::Release);
+    }
+
+    fn pop(&self) {
+        let depth = self.depth.load(Ordering::Relaxed);
+        self.depth.store(depth.saturating_sub(1), Ordering::Release);
+    }
+}
+
+/// Records a proc starting on this thread. The frame is popped when the returned guard is dropped.
+pub(crate) fn enter(procdef: usize, resumed: bool) -> CallFrame {
+    let stack = STACK.with(Arc::clone);
+    stack.push(StackEntry { procdef, resumed });
+
+    CallFrame { stack }
+}
+
+/// How many frames this thread's stack holds, including any past MAX_DEPTH.
+pub(crate) fn depth() -> usize {
+    STACK.with(|stack| stack.depth())
+}
+
+/// This thread's stack, outermost first.
+pub(crate) fn snapshot() -> Vec<StackEntry> {
+    STACK.with(|stack| stack.snapshot())
+}
+
+/// The stacks of every thread that has run a proc, for reading from other threads.
+pub(crate) fn all_stacks() -> Vec<Arc<ShadowStack>> {
+    STACKS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone()
+}
+
+impl Drop for CallFrame {
+    fn drop(&mut self) {
+        self.stack.pop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(procdef: usize, resumed: bool) -> StackEntry {
+        StackEntry { procdef, resumed }
+    }
+
+    #[test]
+    fn frames_push_and_pop_in_order() {
+        let outer = enter(1, false);
+        let inner = enter(2, false);
+        assert!(snapshot() == [entry(1, false), entry(2, false)]);
+
+        drop(inner);
+        assert!(snapshot() == [entry(1, false)]);
+
+        drop(outer);
+        assert!(snapshot().is_empty());
+    }
+
+    #[test]
+    fn early_returns_pop_their_frame() {
+        fn run(procdef: usize, return_early: bool) -> Option<usize> {
+            let _frame = enter(procdef, false);
+            if return_early {
+                return None;
+            }
+
+            let _callee = enter(procdef + 1, false);
+            Some(snapshot().len())
+        }
+
+        let _caller = enter(1, false);
+        assert_eq!(run(2, true), None);
+        assert!(snapshot() == [entry(1, false)]);
+
+        assert_eq!(run(2, false), Some(3));
+        assert!(snapshot() == [entry(1, false)]);
+    }
+
+    #[test]
+    fn resumed_procs_are_flagged_without_their_old_callers() {
+        {
+            let _caller = enter(1, false);
+            let _sleeper = enter(0x13FFF, false);
+        }
+
+        // The scheduler resumes the sleeping proc from an empty stack
+        let resumed = enter(0x13FFF, true);
+        let callee = enter(3, false);
+        assert!(snapshot() == [entry(0x13FFF, true), entry(3, false)]);
+
+        drop(callee);
+        drop(resumed);
+        assert!(snapshot().is_empty());
+    }
+
+    #[test]
+    fn frames_past_max_depth_are_counted_but_not_recorded() {
+        let frames = (0..MAX_DEPTH + 2)
+            .map(|procdef| enter(procdef, false))
+            .collect::<Vec<_>>();
+        assert_eq!(STACK.with(|stack| stack.depth()), MAX_DEPTH + 2);
+        assert_eq!(snapshot().len(), MAX_DEPTH);
+
+        drop(frames);
+        assert_eq!(STACK.with(|stack| stack.depth()), 0);
+    }
+}
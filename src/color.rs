@@ -0,0 +1,64 @@
+use crate::config::Config;
+
+/// Picks the zone colour for a proc. Explicit rules win, longest prefix first, then the type hash.
+/// Returns 0, which Tracy treats as uncoloured, if neither applies.
+pub(crate) fn proc_color(config: &Config, path: &str) -> u32 {
+    let rule = config
+        .color_rules
+        .iter()
+        .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+        .max_by_key(|(prefix, _)| prefix.len());
+
+    if let Some((_, color)) = rule {
+        return *color;
+    }
+
+    if config.color_by_type && !path.is_empty() {
+        return hash_color(type_path(path));
+    }
+
+    0
+}
+
+/// "/datum/foo/proc/bar" -> "/datum/foo", and global procs all share "/".
+fn type_path(proc_path: &str) -> &str {
+    let end = ["/proc/", "/verb/"]
+        .iter()
+        .filter_map(|marker| proc_path.rfind(marker))
+        .max()
+        .unwrap_or(proc_path.len());
+
+    match &proc_path[..end] {
+        "" => "/",
+        type_path => type_path,
+    }
+}
+
+/// Maps a string to a stable, readable colour: the hue comes from an FNV-1a hash, while saturation
+/// and value are fixed so every type stands out against Tracy's dark background by the same amount.
+fn hash_color(text: &str) -> u32 {
+    let mut hash: u32 = 0x811C9DC5;
+    for byte in text.bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+
+    let hue = (hash % 360) as f32;
+    let saturation = 0.55;
+    let value = 0.8;
+
+    let chroma = value * saturation;
+    let x = chroma * (1.0 - ((hue / 60.0) % 2.0 - 1.0).abs());
+    let (red, green, blue) = match hue as u32 / 60 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+
+    let offset = value - chroma;
+    let channel = |component: f32| ((component + offset) * 255.0).round() as u32;
+    (channel(red) << 16) | (channel(green) << 8) | channel(blue)
+}
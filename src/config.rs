@@ -0,0 +1,1135 @@
+//! Runtime configuration, merged from (lowest priority first) byond-tracy.toml in the working directory,
+//! the BYOND_TRACY environment variable, and the arguments passed to init.
+//!
+//! The environment variable and each init argument hold either key=value pairs separated by '&' or ';'
+//! (as produced by DM's list2params()) or a flat JSON object. The file holds top level TOML key = value lines.
+//! init_ext can also pass an assoc list, whose values are taken as they are.
+
+use std::{ffi::CStr, fs, io::ErrorKind, time::Duration};
+
+use crate::exports::parse_color;
+
+pub(crate) const CONFIG_FILE_NAME: &str = "byond-tracy.toml";
+
+pub(crate) const CONFIG_ENVIRONMENT_VARIABLE: &str = "BYOND_TRACY";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Mode {
+    /// A zone for every proc call
+    Zones,
+    /// Only engine zones, frame marks, plots and anything DM emits itself
+    Frames,
+    /// Like frames, plus a background thread sampling the DM callstack at sample_rate
+    Sampling,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum SinkKind {
+    /// Stream to a connected Tracy viewer
+    Tracy,
+    /// Write Chrome Trace Event JSON to chrome_trace
+    Chrome,
+    /// Keep the last recorder_capacity events in memory for dump_flight_recorder
+    Recorder,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum TracyPort {
+    /// Tracy's own default, searching upwards from 8086 if taken
+    Default,
+    Fixed(u16),
+    /// Derived from world_port, see AUTO_PORT_OFFSET
+    Auto,
+}
+
+/// Highest accepted sample_rate. Past this the sampler thread would do little but take locks
+pub(crate) const MAX_SAMPLE_RATE: u32 = 100_000;
+
+/// Highest accepted recorder_capacity, a few gigabytes of events at most
+pub(crate) const MAX_RECORDER_CAPACITY: u64 = 50_000_000;
+
+/// Deepest memory_callstack_depth Tracy accepts, it asserts on anything from 63 up
+pub(crate) const MAX_MEMORY_CALLSTACK_DEPTH: u16 = 62;
+
+/// port=auto listens on world_port plus this, so servers on neighbouring ports stay apart
+pub(crate) const AUTO_PORT_OFFSET: u16 = 10000;
+
+pub(crate) struct Config {
+    pub mode: Mode,
+    pub port: TracyPort,
+    /// DreamDaemon's world.port, used by port=auto and the program name
+    pub world_port: Option<u16>,
+    /// DreamDaemon's world.name, used by the program name
+    pub world_name: Option<String>,
+    /// Proc path prefixes to profile. Everything is profiled when empty
+    pub include: Vec<String>,
+    /// Proc path prefixes never to profile, checked after include
+    pub exclude: Vec<String>,
+    /// Attach each call's arguments to its zone as text
+    pub capture_arguments: bool,
+    /// Colour of zones for procs resuming from sleep
+    pub resumed_color: u32,
+    /// Give each type a colour derived from its path, so related procs look alike
+    pub color_by_type: bool,
+    /// Explicit proc path prefix colours, written as "prefix=color". These win over color_by_type
+    pub color_rules: Vec<(String, u32)>,
+    pub server_tick_color: u32,
+    pub send_maps_color: u32,
+    /// Native callstack depth recorded with each allocation, 0 to disable
+    pub memory_callstack_depth: u16,
+    /// Samples per second in sampling mode
+    pub sample_rate: u32,
+    /// Aggregate every call into a call tree for write_call_tree
+    pub call_tree: bool,
+    /// Write /tmp/perf-<pid>.map so Linux perf can name DM procs
+    pub perf_map: bool,
+    /// Where hook and DM events are reported
+    pub sinks: Vec<SinkKind>,
+    /// File the chrome sink writes to
+    pub chrome_trace: String,
+    /// Events the recorder sink holds before dropping the oldest
+    pub recorder_capacity: usize,
+    /// DreamDaemon's world.tick_lag in deciseconds, used by spike_tick_lag_multiple
+    pub tick_lag: Option<f64>,
+    /// Ticks taking longer than this many milliseconds are captured, 0 to disable
+    pub spike_threshold_ms: u32,
+    /// Ticks taking longer than this many tick_lags are captured, 0 to disable
+    pub spike_tick_lag_multiple: f64,
+    /// Seconds of recorder events written with each spike capture
+    pub spike_window: f64,
+    /// Minimum seconds between two spike captures, so a struggling server isn't made worse by them
+    pub spike_cooldown: f64,
+    /// Directory spike captures are written to, created if missing
+    pub spike_directory: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Value {
+    String(String),
+    Number(f64),
+    Bool(bool),
+    List(Vec<String>),
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            mode: Mode::Zones,
+            port: TracyPort::Default,
+            world_port: None,
+            world_name: None,
+            include: Vec::new(),
+            exclude: Vec::new(),
+            capture_arguments: false,
+            resumed_color: 0xAF4444,
+            color_by_type: true,
+            color_rules: Vec::new(),
+            server_tick_color: 0x4C8C4A,
+            send_maps_color: 0x4A6E8C,
+            memory_callstack_depth: 0,
+            sample_rate: 1000,
+            call_tree: false,
+            perf_map: false,
+            sinks: vec![SinkKind::Tracy],
+            chrome_trace: "byond-tracy.json".to_string(),
+            recorder_capacity: 1_000_000,
+            tick_lag: None,
+            spike_threshold_ms: 0,
+            spike_tick_lag_multiple: 0.0,
+            spike_window: 5.0,
+            spike_cooldown: 60.0,
+            spike_directory: "spikes".to_string(),
+        }
+    }
+}
+
+/// One argument passed to init.
+pub(crate) enum InitArg {
+    /// Parsed like the environment variable
+    Text(String),
+    /// Already split into keys and values, from an assoc list passed to init_ext
+    Settings(Vec<(String, Value)>),
+}
+
+impl Config {
+    /// Merges every configuration source over the defaults.
+    pub fn load(args: &[InitArg]) -> Result<Self, String> {
+        let file = match fs::read_to_string(CONFIG_FILE_NAME) {
+            Ok(contents) => Some(contents),
+            Err(error) if error.kind() == ErrorKind::NotFound => None,
+            Err(error) => return Err(format!("Unable to read {}: {}", CONFIG_FILE_NAME, error)),
+        };
+        let variable = std::env::var(CONFIG_ENVIRONMENT_VARIABLE).ok();
+
+        Self::from_sources(file.as_deref(), variable.as_deref(), args)
+    }
+
+    /// Merges the contents of each source over the defaults, in the same order as load.
+    fn from_sources(
+        file: Option<&str>,
+        variable: Option<&str>,
+        args: &[InitArg],
+    ) -> Result<Self, String> {
+        let mut config = Self::default();
+
+        if let Some(file) = file {
+            config
+                .apply_all(parse_toml(file))
+                .map_err(|error| format!("{}: {}", CONFIG_FILE_NAME, error))?;
+        }
+
+        if let Some(variable) = variable {
+            config
+                .apply_all(parse_inline(variable))
+                .map_err(|error| format!("{}: {}", CONFIG_ENVIRONMENT_VARIABLE, error))?;
+        }
+
+        for (index, arg) in args.iter().enumerate() {
+            let pairs = match arg {
+                InitArg::Text(text) => parse_inline(text),
+                InitArg::Settings(settings) => Ok(settings.clone()),
+            };
+            config
+                .apply_all(pairs)
+                .map_err(|error| format!("init argument {}: {}", index + 1, error))?;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks the settings that depend on each other, once every source is merged.
+    fn validate(&self) -> Result<(), String> {
+        if self.perf_map && !cfg!(target_os = "linux") {
+            return Err("perf_map is only supported on Linux".to_string());
+        }
+
+        if self.port == TracyPort::Auto {
+            let Some(world_port) = self.world_port else {
+                return Err("port=auto requires world_port".to_string());
+            };
+            if world_port.checked_add(AUTO_PORT_OFFSET).is_none() {
+                return Err(format!(
+                    "port=auto requires a world_port of at most {}, got {}",
+                    u16::MAX - AUTO_PORT_OFFSET,
+                    world_port
+                ));
+            }
+        }
+
+        if self.spike_tick_lag_multiple > 0.0 && self.tick_lag.is_none() {
+            return Err("spike_tick_lag_multiple requires tick_lag".to_string());
+        }
+
+        if self.spike_threshold().is_some() && !self.sinks.contains(&SinkKind::Recorder) {
+            return Err("Spike capture requires the 'recorder' sink".to_string());
+        }
+
+        Ok(())
+    }
+
+    /// The port Tracy should listen on, or None to leave Tracy's default alone.
+    pub fn tracy_port(&self) -> Option<u16> {
+        match self.port {
+            TracyPort::Default => None,
+            TracyPort::Fixed(port) => Some(port),
+            TracyPort::Auto => self
+                .world_port
+                .and_then(|world_port| world_port.checked_add(AUTO_PORT_OFFSET)),
+        }
+    }
+
+    /// Name announced to the Tracy viewer's server list, so several DreamDaemons can be told apart.
+    pub fn program_name(&self) -> Option<String> {
+        match (&self.world_name, self.world_port) {
+            (Some(name), Some(port)) => Some(format!("{} ({})", name, port)),
+            (Some(name), None) => Some(name.clone()),
+            (None, Some(port)) => Some(format!("DreamDaemon ({})", port)),
+            (None, None) => None,
+        }
+    }
+
+    /// How long a tick may take before it's captured as a spike, the lower of the configured limits.
+    pub fn spike_threshold(&self) -> Option<Duration> {
+        let absolute = (self.spike_threshold_ms > 0)
+            .then(|| Duration::from_millis(self.spike_threshold_ms.into()));
+        // tick_lag is in deciseconds
+        let relative = self
+            .tick_lag
+            .filter(|_| self.spike_tick_lag_multiple > 0.0)
+            .map(|tick_lag| {
+                Duration::from_secs_f64(tick_lag * self.spike_tick_lag_multiple / 10.0)
+            });
+
+        match (absolute, relative) {
+            (Some(absolute), Some(relative)) => Some(absolute.min(relative)),
+            (absolute, relative) => absolute.or(relative),
+        }
+    }
+
+    /// Whether a proc with the given path should get zones.
+    pub fn includes_proc(&self, path: &CStr) -> bool {
+        let path = path.to_string_lossy();
+        (self.include.is_empty()
+            || self
+                .include
+                .iter()
+                .any(|prefix| path.starts_with(prefix.as_str())))
+            && !self
+                .exclude
+                .iter()
+                .any(|prefix| path.starts_with(prefix.as_str()))
+    }
+
+    fn apply_all(&mut self, pairs: Result<Vec<(String, Value)>, String>) -> Result<(), String> {
+        for (key, value) in pairs? {
+            self.apply(&key, value)?;
+        }
+
+        Ok(())
+    }
+
+    fn apply(&mut self, key: &str, value: Value) -> Result<(), String> {
+        match key {
+            "port" => {
+                self.port = match value {
+                    Value::String(string) if string == "auto" => TracyPort::Auto,
+                    Value::String(string) if string == "default" => TracyPort::Default,
+                    other => TracyPort::Fixed(expect_integer(key, other, u16::MAX.into())? as u16),
+                }
+            }
+            "world_port" => {
+                self.world_port = Some(expect_integer(key, value, u16::MAX.into())? as u16)
+            }
+            "world_name" => self.world_name = Some(expect_string(key, value)?),
+            "mode" => {
+                self.mode = match expect_string(key, value)?.as_str() {
+                    "zones" => Mode::Zones,
+                    "frames" => Mode::Frames,
+                    "sampling" => Mode::Sampling,
+                    other => {
+                        return Err(format!(
+                            "Invalid mode '{}', expected 'zones', 'frames' or 'sampling'",
+                            other
+                        ));
+                    }
+                }
+            }
+            "include" => self.include = expect_list(value),
+            "exclude" => self.exclude = expect_list(value),
+            "capture_arguments" => self.capture_arguments = expect_bool(key, value)?,
+            "resumed_color" => self.resumed_color = expect_color(key, value)?,
+            "color_by_type" => self.color_by_type = expect_bool(key, value)?,
+            "colors" => {
+                self.color_rules = expect_list(value)
+                    .into_iter()
+                    .map(|rule| match rule.rsplit_once('=') {
+                        Some((prefix, color)) => Ok((
+                            prefix.trim().to_string(),
+                            parse_color(color).map_err(|error| format!("'{}': {}", key, error))?,
+                        )),
+                        None => Err(format!(
+                            "'{}' entries must be prefix=color, got '{}'",
+                            key, rule
+                        )),
+                    })
+                    .collect::<Result<_, String>>()?
+            }
+            "server_tick_color" => self.server_tick_color = expect_color(key, value)?,
+            "send_maps_color" => self.send_maps_color = expect_color(key, value)?,
+            "memory_callstack_depth" => {
+                self.memory_callstack_depth =
+                    expect_integer(key, value, MAX_MEMORY_CALLSTACK_DEPTH.into())? as u16
+            }
+            "sample_rate" => {
+                self.sample_rate = match expect_integer(key, value, MAX_SAMPLE_RATE.into())? {
+                    0 => return Err(format!("'{}' must be at least 1", key)),
+                    rate => rate as u32,
+                }
+            }
+            "call_tree" => self.call_tree = expect_bool(key, value)?,
+            "perf_map" => self.perf_map = expect_bool(key, value)?,
+            "sinks" => {
+                self.sinks = expect_list(value)
+                    .into_iter()
+                    .map(|sink| match sink.as_str() {
+                        "tracy" => Ok(SinkKind::Tracy),
+                        "chrome" => Ok(SinkKind::Chrome),
+                        "recorder" => Ok(SinkKind::Recorder),
+                        other => Err(format!(
+                            "Invalid sink '{}', expected 'tracy', 'chrome' or 'recorder'",
+                            other
+                        )),
+                    })
+                    .collect::<Result<_, String>>()?;
+                self.sinks.dedup();
+            }
+            "chrome_trace" => self.chrome_trace = expect_string(key, value)?,
+            "tick_lag" => self.tick_lag = Some(expect_positive(key, value)?),
+            "spike_threshold_ms" => {
+                self.spike_threshold_ms = expect_integer(key, value, u32::MAX.into())? as u32
+            }
+            "spike_tick_lag_multiple" => {
+                self.spike_tick_lag_multiple = expect_non_negative(key, value)?
+            }
+            "spike_window" => self.spike_window = expect_positive(key, value)?,
+            "spike_cooldown" => self.spike_cooldown = expect_non_negative(key, value)?,
+            "spike_directory" => self.spike_directory = expect_string(key, value)?,
+            "recorder_capacity" => {
+                self.recorder_capacity = match expect_integer(key, value, MAX_RECORDER_CAPACITY)? {
+                    0 => return Err(format!("'{}' must be at least 1", key)),
+                    capacity => capacity as usize,
+                }
+            }
+            _ => return Err(format!("Unknown key '{}'", key)),
+        }
+
+        Ok(())
+    }
+}
+
+fn expect_string(key: &str, value: Value) -> Result<String, String> {
+    match value {
+        Value::String(string) => Ok(string),
+        other => Err(format!("'{}' must be a string, got {:?}", key, other)),
+    }
+}
+
+/// Lists may also be written as a single comma separated string.
+fn expect_list(value: Value) -> Vec<String> {
+    match value {
+        Value::List(list) => list,
+        Value::String(string) => string
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(str::to_string)
+            .collect(),
+        Value::Number(number) => vec![number.to_string()],
+        Value::Bool(bool) => vec![bool.to_string()],
+    }
+}
+
+fn expect_bool(key: &str, value: Value) -> Result<bool, String> {
+    match value {
+        Value::Bool(bool) => Ok(bool),
+        Value::Number(number) => Ok(number != 0.0),
+        Value::String(string) => match string.as_str() {
+            "true" | "1" | "yes" | "on" => Ok(true),
+            "false" | "0" | "no" | "off" => Ok(false),
+            _ => Err(format!("'{}' must be true or false, got '{}'", key, string)),
+        },
+        other => Err(format!("'{}' must be true or false, got {:?}", key, other)),
+    }
+}
+
+fn expect_number(key: &str, value: Value) -> Result<f64, String> {
+    let number = match value {
+        Value::Number(number) => number,
+        Value::String(string) => string
+            .trim()
+            .parse()
+            .map_err(|_| format!("'{}' must be a number, got '{}'", key, string))?,
+        other => return Err(format!("'{}' must be a number, got {:?}", key, other)),
+    };
+
+    if !number.is_finite() {
+        return Err(format!("'{}' must be a finite number, got {}", key, number));
+    }
+
+    Ok(number)
+}
+
+fn expect_non_negative(key: &str, value: Value) -> Result<f64, String> {
+    match expect_number(key, value)? {
+        number if number >= 0.0 && number <= u32::MAX.into() => Ok(number),
+        number => Err(format!(
+            "'{}' must be between 0 and {}, got {}",
+            key,
+            u32::MAX,
+            number
+        )),
+    }
+}
+
+/// Greater than zero, and small enough to be a Duration in seconds.
+fn expect_positive(key: &str, value: Value) -> Result<f64, String> {
+    match expect_number(key, value)? {
+        number if number > 0.0 && number <= u32::MAX.into() => Ok(number),
+        number => Err(format!(
+            "'{}' must be above 0 and at most {}, got {}",
+            key,
+            u32::MAX,
+            number
+        )),
+    }
+}
+
+fn expect_integer(key: &str, value: Value, max: u64) -> Result<u64, String> {
+    let number = expect_number(key, value)?;
+
+    if number < 0.0 || number.fract() != 0.0 || number > max as f64 {
+        return Err(format!(
+            "'{}' must be a whole number between 0 and {}, got {}",
+            key, max, number
+        ));
+    }
+
+    Ok(number as u64)
+}
+
+fn expect_color(key: &str, value: Value) -> Result<u32, String> {
+    match value {
+        Value::String(string) => {
+            parse_color(&string).map_err(|error| format!("'{}': {}", key, error))
+        }
+        other => Ok(expect_integer(key, other, 0xFFFFFF)? as u32),
+    }
+}
+
+/// Parses either a JSON object or key=value pairs.
+fn parse_inline(text: &str) -> Result<Vec<(String, Value)>, String> {
+    let trimmed = text.trim();
+    if trimmed.starts_with('{') {
+        return parse_json_object(trimmed);
+    }
+
+    trimmed
+        .split(['&', ';'])
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) => Ok((
+                percent_decode(key.trim()),
+                Value::String(percent_decode(value.trim())),
+            )),
+            None => Err(format!("Expected key=value, got '{}'", pair)),
+        })
+        .collect()
+}
+
+/// Undoes the URL encoding list2params() applies, so "%2Fdatum" comes back as "/datum".
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+        match bytes[index] {
+            b'+' => decoded.push(b' '),
+            b'%' if let Some(byte) = text
+                .get(index + 1..index + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok()) =>
+            {
+                decoded.push(byte);
+                index += 2;
+            }
+            byte => decoded.push(byte),
+        }
+        index += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Parses top level key = value lines, which is all of TOML the configuration needs.
+fn parse_toml(text: &str) -> Result<Vec<(String, Value)>, String> {
+    let mut pairs = Vec::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let mut parser = Parser::new(line);
+        parser.skip_whitespace();
+        if parser.at_end_of_line() {
+            continue;
+        }
+
+        if parser.peek() == Some('[') {
+            return Err(format!("line {}: Tables are not supported", line_number));
+        }
+
+        let result = parser.parse_key().and_then(|key| {
+            parser.skip_whitespace();
+            parser.expect('=')?;
+            parser.skip_whitespace();
+            let value = parser.parse_value()?;
+            parser.skip_whitespace();
+            if !parser.at_end_of_line() {
+                return Err("Unexpected text after value".to_string());
+            }
+
+            Ok((key, value))
+        });
+
+        pairs.push(result.map_err(|error| format!("line {}: {}", line_number, error))?);
+    }
+
+    Ok(pairs)
+}
+
+fn parse_json_object(text: &str) -> Result<Vec<(String, Value)>, String> {
+    let mut parser = Parser::new(text);
+    let mut pairs = Vec::new();
+
+    parser.skip_whitespace();
+    parser.expect('{')?;
+    parser.skip_whitespace();
+    if parser.peek() == Some('}') {
+        parser.next();
+    } else {
+        loop {
+            parser.skip_whitespace();
+            let key = parser.parse_string()?;
+            parser.skip_whitespace();
+            parser.expect(':')?;
+            parser.skip_whitespace();
+            pairs.push((key, parser.parse_value()?));
+            parser.skip_whitespace();
+
+            match parser.next() {
+                Some(',') => continue,
+                Some('}') => break,
+                Some(other) => return Err(format!("Expected ',' or '}}', got '{}'", other)),
+                None => return Err("Unterminated object".to_string()),
+            }
+        }
+    }
+
+    parser.skip_whitespace();
+    if parser.peek().is_some() {
+        return Err("Unexpected text after object".to_string());
+    }
+
+    Ok(pairs)
+}
+
+/// Character level parsing shared by the JSON and TOML subsets, whose values look the same.
+struct Parser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+}
+
+impl<'a> Parser<'a> {
+    fn new(text: &'a str) -> Self {
+        Self {
+            chars: text.chars().peekable(),
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        self.chars.next()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.next();
+        }
+    }
+
+    fn at_end_of_line(&mut self) -> bool {
+        matches!(self.peek(), None | Some('#'))
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        match self.next() {
+            Some(actual) if actual == expected => Ok(()),
+            Some(actual) => Err(format!("Expected '{}', got '{}'", expected, actual)),
+            None => Err(format!("Expected '{}', got end of input", expected)),
+        }
+    }
+
+    fn parse_key(&mut self) -> Result<String, String> {
+        if matches!(self.peek(), Some('"' | '\'')) {
+            return self.parse_string();
+        }
+
+        let mut key = String::new();
+        while let Some(char) = self.peek() {
+            if !(char.is_ascii_alphanumeric() || char == '_' || char == '-') {
+                break;
+            }
+            key.push(char);
+            self.next();
+        }
+
+        if key.is_empty() {
+            return Err("Expected a key".to_string());
+        }
+
+        Ok(key)
+    }
+
+    fn parse_value(&mut self) -> Result<Value, String> {
+        match self.peek() {
+            Some('"' | '\'') => Ok(Value::String(self.parse_string()?)),
+            Some('[') => self.parse_list(),
+            Some(_) => {
+                let mut word = String::new();
+                while let Some(char) = self.peek() {
+                    if char.is_whitespace() || matches!(char, ',' | '}' | ']' | '#') {
+                        break;
+                    }
+                    word.push(char);
+                    self.next();
+                }
+
+                match word.as_str() {
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    _ => word
+                        .parse()
+                        .map(Value::Number)
+                        .map_err(|_| format!("Invalid value '{}'", word)),
+                }
+            }
+            None => Err("Expected a value".to_string()),
+        }
+    }
+
+    fn parse_list(&mut self) -> Result<Value, String> {
+        self.expect('[')?;
+        let mut list = Vec::new();
+
+        loop {
+            self.skip_whitespace();
+            if self.peek() == Some(']') {
+                self.next();
+                return Ok(Value::List(list));
+            }
+
+            match self.parse_value()? {
+                Value::String(string) => list.push(string),
+                other => return Err(format!("Lists may only contain strings, got {:?}", other)),
+            }
+
+            self.skip_whitespace();
+            match self.next() {
+                Some(',') => {}
+                Some(']') => return Ok(Value::List(list)),
+                Some(other) => return Err(format!("Expected ',' or ']', got '{}'", other)),
+                None => return Err("Unterminated list".to_string()),
+            }
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String, String> {
+        let quote = match self.next() {
+            Some(quote @ ('"' | '\'')) => quote,
+            Some(other) => return Err(format!("Expected a string, got '{}'", other)),
+            None => return Err("Expected a string, got end of input".to_string()),
+        };
+
+        let mut string = String::new();
+        loop {
+            match self.next() {
+                Some(char) if char == quote => return Ok(string),
+                // Single quoted TOML strings are literal
+                Some('\\') if quote == '"' => match self.next() {
+                    Some('n') => string.push('\n'),
+                    Some('t') => string.push('\t'),
+                    Some('r') => string.push('\r'),
+                    Some(escaped @ ('"' | '\\' | '/')) => string.push(escaped),
+                    Some(other) => return Err(format!("Unsupported escape '\\{}'", other)),
+                    None => return Err("Unterminated string".to_string()),
+                },
+                Some(char) => string.push(char),
+                None => return Err("Unterminated string".to_string()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{
+        CONFIG_ENVIRONMENT_VARIABLE, CONFIG_FILE_NAME, Config, InitArg, Mode, SinkKind, TracyPort,
+        Value, parse_inline, parse_json_object, parse_toml, percent_decode,
+    };
+
+    fn string(text: &str) -> Value {
+        Value::String(text.to_string())
+    }
+
+    fn list(items: &[&str]) -> Value {
+        Value::List(items.iter().map(|item| item.to_string()).collect())
+    }
+
+    /// The error from applying a single key to the defaults.
+    fn apply_error(key: &str, value: Value) -> String {
+        Config::default().apply(key, value).unwrap_err()
+    }
+
+    fn text_args(args: &[&str]) -> Vec<InitArg> {
+        args.iter()
+            .map(|arg| InitArg::Text(arg.to_string()))
+            .collect()
+    }
+
+    fn from_args(args: &[&str]) -> Result<Config, String> {
+        Config::from_sources(None, None, &text_args(args))
+    }
+
+    #[test]
+    fn inline_pairs_split_on_either_separator() {
+        assert_eq!(
+            parse_inline(" mode=frames&port = 9000; include=/datum ;"),
+            Ok(vec![
+                ("mode".to_string(), string("frames")),
+                ("port".to_string(), string("9000")),
+                ("include".to_string(), string("/datum")),
+            ])
+        );
+        assert_eq!(parse_inline(""), Ok(vec![]));
+        assert_eq!(
+            parse_inline("mode=frames&sinks"),
+            Err("Expected key=value, got 'sinks'".to_string())
+        );
+    }
+
+    #[test]
+    fn inline_pairs_are_percent_decoded() {
+        assert_eq!(
+            parse_inline("colors=%2Fdatum%3D%23FF0000&world+name=My+Server"),
+            Ok(vec![
+                ("colors".to_string(), string("/datum=#FF0000")),
+                ("world name".to_string(), string("My Server")),
+            ])
+        );
+        assert_eq!(percent_decode("caf%C3%A9"), "café");
+        // Anything that isn't a full escape is left as it was
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%2"), "%zz%2");
+    }
+
+    #[test]
+    fn json_objects_hold_every_kind_of_value() {
+        assert_eq!(
+            parse_inline(
+                r#" {"mode": "frames", "port": 9000, "call_tree": true, "include": ["/datum", "/mob"],
+                    "chrome_trace": "a \"b\"\\c"} "#
+            ),
+            Ok(vec![
+                ("mode".to_string(), string("frames")),
+                ("port".to_string(), Value::Number(9000.0)),
+                ("call_tree".to_string(), Value::Bool(true)),
+                ("include".to_string(), list(&["/datum", "/mob"])),
+                ("chrome_trace".to_string(), string("a \"b\"\\c")),
+            ])
+        );
+        assert_eq!(parse_json_object("{ }"), Ok(vec![]));
+    }
+
+    #[test]
+    fn malformed_json_is_refused() {
+        for (json, error) in [
+            (r#"{"mode" "frames"}"#, "Expected ':', got '\"'"),
+            (
+                r#"{"mode": "frames" "port": 1}"#,
+                "Expected ',' or '}', got '\"'",
+            ),
+            (r#"{"mode": "frames""#, "Unterminated object"),
+            (r#"{"mode": "frames"} x"#, "Unexpected text after object"),
+            (r#"{"mode": "fra"#, "Unterminated string"),
+            (r#"{"mode": "\q"}"#, "Unsupported escape '\\q'"),
+            (r#"{"port": 90x0}"#, "Invalid value '90x0'"),
+            (
+                r#"{"include": ["/datum", 1]}"#,
+                "Lists may only contain strings, got Number(1.0)",
+            ),
+            (
+                r#"{"include": ["/datum" "/mob"]}"#,
+                "Expected ',' or ']', got '\"'",
+            ),
+            (r#"{mode: "frames"}"#, "Expected a string, got 'm'"),
+        ] {
+            assert_eq!(parse_json_object(json), Err(error.to_string()), "{}", json);
+        }
+    }
+
+    #[test]
+    fn toml_lines_skip_blanks_and_comments() {
+        let toml = concat!(
+            "# byond-tracy.toml\n",
+            "\n",
+            "mode = \"sampling\" # trailing comment\n",
+            "sample_rate=250\n",
+            "'perf_map' = false\n",
+            "chrome_trace = 'C:\\traces\\tick.json'\n",
+            "sinks = [\"tracy\", \"recorder\",]\n",
+        );
+
+        assert_eq!(
+            parse_toml(toml),
+            Ok(vec![
+                ("mode".to_string(), string("sampling")),
+                ("sample_rate".to_string(), Value::Number(250.0)),
+                ("perf_map".to_string(), Value::Bool(false)),
+                ("chrome_trace".to_string(), string("C:\\traces\\tick.json")),
+                ("sinks".to_string(), list(&["tracy", "recorder"])),
+            ])
+        );
+    }
+
+    #[test]
+    fn malformed_toml_names_the_line() {
+        for (toml, error) in [
+            (
+                "mode = \"zones\"\n[tracy]",
+                "line 2: Tables are not supported",
+            ),
+            ("mode \"zones\"", "line 1: Expected '=', got '\"'"),
+            ("mode = \"zones\" x", "line 1: Unexpected text after value"),
+            ("mode =", "line 1: Expected a value"),
+            ("= 1", "line 1: Expected a key"),
+            ("sinks = [\"tracy\"", "line 1: Unterminated list"),
+        ] {
+            assert_eq!(parse_toml(toml), Err(error.to_string()), "{}", toml);
+        }
+    }
+
+    #[test]
+    fn later_sources_win() {
+        let config = Config::from_sources(
+            Some("mode = \"frames\"\nport = 1\nsample_rate = 10"),
+            Some("port=2&sample_rate=20"),
+            &text_args(&["port=3", "{\"call_tree\": true}"]),
+        )
+        .unwrap();
+
+        assert_eq!(config.mode, Mode::Frames);
+        assert_eq!(config.sample_rate, 20);
+        assert_eq!(config.port, TracyPort::Fixed(3));
+        assert!(config.call_tree);
+    }
+
+    #[test]
+    fn settings_are_applied_without_parsing() {
+        let config = Config::from_sources(
+            None,
+            Some("port=1"),
+            &[
+                InitArg::Settings(vec![
+                    ("port".to_string(), Value::Number(9000.0)),
+                    ("include".to_string(), list(&["/datum", "/mob"])),
+                    ("capture_arguments".to_string(), Value::Number(1.0)),
+                ]),
+                InitArg::Text("mode=frames".to_string()),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(config.port, TracyPort::Fixed(9000));
+        assert_eq!(config.include, ["/datum", "/mob"]);
+        assert!(config.capture_arguments);
+        assert_eq!(config.mode, Mode::Frames);
+
+        assert_eq!(
+            Config::from_sources(
+                None,
+                None,
+                &[InitArg::Settings(vec![(
+                    "port".to_string(),
+                    string("9000&mode=frames")
+                )])]
+            )
+            .err(),
+            Some("init argument 1: 'port' must be a number, got '9000&mode=frames'".to_string())
+        );
+    }
+
+    #[test]
+    fn errors_name_their_source() {
+        assert_eq!(
+            Config::from_sources(Some("mode = 1"), None, &[]).err(),
+            Some(format!(
+                "{}: 'mode' must be a string, got Number(1.0)",
+                CONFIG_FILE_NAME
+            ))
+        );
+        assert_eq!(
+            Config::from_sources(None, Some("colour=red"), &[]).err(),
+            Some(format!(
+                "{}: Unknown key 'colour'",
+                CONFIG_ENVIRONMENT_VARIABLE
+            ))
+        );
+        assert_eq!(
+            from_args(&["mode=zones", "mode=fast"]).err(),
+            Some(
+                "init argument 2: Invalid mode 'fast', expected 'zones', 'frames' or 'sampling'"
+                    .to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn lists_may_be_comma_separated() {
+        let config = from_args(&["sinks=tracy, chrome,,chrome&include=%2Fdatum,%2Fmob"]).unwrap();
+
+        // Repeats next to each other collapse
+        assert_eq!(config.sinks, [SinkKind::Tracy, SinkKind::Chrome]);
+        assert_eq!(config.include, ["/datum", "/mob"]);
+        assert_eq!(
+            apply_error("sinks", string("tracy,file")),
+            "Invalid sink 'file', expected 'tracy', 'chrome' or 'recorder'"
+        );
+    }
+
+    #[test]
+    fn strings_must_be_strings() {
+        assert_eq!(
+            apply_error("chrome_trace", Value::Bool(true)),
+            "'chrome_trace' must be a string, got Bool(true)"
+        );
+    }
+
+    #[test]
+    fn bools_accept_words_and_numbers() {
+        let mut config = Config::default();
+        for (value, expected) in [
+            (string("yes"), true),
+            (string("off"), false),
+            (Value::Number(2.0), true),
+            (Value::Bool(false), false),
+        ] {
+            config.apply("call_tree", value).unwrap();
+            assert_eq!(config.call_tree, expected);
+        }
+
+        assert_eq!(
+            apply_error("call_tree", string("maybe")),
+            "'call_tree' must be true or false, got 'maybe'"
+        );
+        assert_eq!(
+            apply_error("call_tree", list(&["true"])),
+            "'call_tree' must be true or false, got List([\"true\"])"
+        );
+    }
+
+    #[test]
+    fn numbers_must_be_finite() {
+        assert_eq!(
+            apply_error("tick_lag", string("fast")),
+            "'tick_lag' must be a number, got 'fast'"
+        );
+        assert_eq!(
+            apply_error("tick_lag", list(&[])),
+            "'tick_lag' must be a number, got List([])"
+        );
+        assert_eq!(
+            apply_error("tick_lag", string("inf")),
+            "'tick_lag' must be a finite number, got inf"
+        );
+    }
+
+    #[test]
+    fn numbers_are_range_checked() {
+        assert_eq!(
+            apply_error("spike_cooldown", Value::Number(-1.0)),
+            "'spike_cooldown' must be between 0 and 4294967295, got -1"
+        );
+        assert_eq!(
+            apply_error("spike_window", Value::Number(0.0)),
+            "'spike_window' must be above 0 and at most 4294967295, got 0"
+        );
+        assert_eq!(
+            apply_error("spike_window", Value::Number(5e9)),
+            "'spike_window' must be above 0 and at most 4294967295, got 5000000000"
+        );
+
+        for value in [-1.0, 1.5, 65536.0] {
+            assert_eq!(
+                apply_error("world_port", Value::Number(value)),
+                format!(
+                    "'world_port' must be a whole number between 0 and 65535, got {}",
+                    value
+                )
+            );
+        }
+        assert_eq!(
+            apply_error("memory_callstack_depth", Value::Number(63.0)),
+            "'memory_callstack_depth' must be a whole number between 0 and 62, got 63"
+        );
+        assert_eq!(
+            apply_error("sample_rate", Value::Number(0.0)),
+            "'sample_rate' must be at least 1"
+        );
+        assert_eq!(
+            apply_error("recorder_capacity", string("0")),
+            "'recorder_capacity' must be at least 1"
+        );
+    }
+
+    #[test]
+    fn colors_take_hex_or_numbers() {
+        let config = from_args(&["resumed_color=%23102030&server_tick_color=255"]).unwrap();
+        assert_eq!(config.resumed_color, 0x102030);
+        assert_eq!(config.server_tick_color, 0xFF);
+
+        let config = Config::from_sources(Some("send_maps_color = 16"), None, &[]);
+        assert_eq!(config.unwrap().send_maps_color, 16);
+
+        assert_eq!(
+            apply_error("resumed_color", string("red")),
+            "'resumed_color': Invalid color: red"
+        );
+        assert_eq!(
+            apply_error("resumed_color", Value::Number(16777216.0)),
+            "'resumed_color' must be a whole number between 0 and 16777215, got 16777216"
+        );
+        assert_eq!(
+            apply_error("colors", string("/datum")),
+            "'colors' entries must be prefix=color, got '/datum'"
+        );
+        assert_eq!(
+            apply_error("colors", string("/datum=#GG0000")),
+            "'colors': Invalid color: #GG0000"
+        );
+    }
+
+    #[test]
+    fn spike_capture_needs_the_recorder() {
+        assert_eq!(
+            from_args(&["spike_threshold_ms=100"]).err(),
+            Some("Spike capture requires the 'recorder' sink".to_string())
+        );
+        assert_eq!(
+            from_args(&["spike_tick_lag_multiple=2&sinks=recorder"]).err(),
+            Some("spike_tick_lag_multiple requires tick_lag".to_string())
+        );
+
+        let config = from_args(&[
+            "spike_threshold_ms=100&spike_tick_lag_multiple=2&tick_lag=0.25&sinks=tracy,recorder",
+        ])
+        .unwrap();
+        // Two tick_lags of 25 milliseconds is the lower limit
+        assert_eq!(config.spike_threshold(), Some(Duration::from_millis(50)));
+    }
+
+    #[test]
+    fn port_auto_needs_world_port() {
+        assert_eq!(
+            from_args(&["port=auto"]).err(),
+            Some("port=auto requires world_port".to_string())
+        );
+
+        let config = from_args(&["port=auto&world_port=1337"]).unwrap();
+        assert_eq!(config.tracy_port(), Some(11337));
+    }
+
+    #[test]
+    fn port_auto_stays_in_range() {
+        let config = from_args(&["port=auto&world_port=55535"]).unwrap();
+        assert_eq!(config.tracy_port(), Some(u16::MAX));
+
+        assert_eq!(
+            from_args(&["port=auto&world_port=55536"]).err(),
+            Some("port=auto requires a world_port of at most 55535, got 55536".to_string())
+        );
+    }
+}
@@ -0,0 +1,400 @@
+//! Procs DM code can call through call_ext()() to add its own instrumentation.
+//! Everything here uses the legacy string API, the same as init. See byondapi for the native equivalents.
+//!
+//! The native equivalents return null where these return "ok", and the error as text otherwise. If
+//! byondapi is missing or can't make that text, they return -1 instead, so a failure never reads as
+//! success.
+
+use std::{
+    borrow::Cow,
+    cell::RefCell,
+    ffi::{CStr, c_char, c_int},
+    slice,
+    time::Duration,
+};
+
+use crate::{
+    INSTANCE, Instance, aggregate::CallTree, callstack, folded, pprof, return_string,
+    sink::ZoneName,
+};
+
+thread_local! {
+    // Callstack depth of the proc that began each DM zone not yet ended, innermost last. These must nest
+    // correctly with the proc zones around them, so a zone must be ended by the same proc that began it,
+    // and any it leaves open are ended when it sleeps or returns
+    static DM_ZONES: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+/// SAFETY: See init
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn tracy_message(argc: c_int, argv: *const *const c_char) -> *const c_char {
+    // SAFETY: Forwarded from our caller
+    let args = unsafe { parse_args(argc, argv) };
+    with_instance(|instance| {
+        let text = required_arg(&args, 0, "text")?;
+        let color = match args.get(1) {
+            Some(color) if !color.is_empty() => parse_color(color)?,
+            _ => 0,
+        };
+
+        message(instance, text, color);
+        Ok(())
+    })
+}
+
+/// SAFETY: See init
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn tracy_plot(argc: c_int, argv: *const *const c_char) -> *const c_char {
+    // SAFETY: Forwarded from our caller
+    let args = unsafe { parse_args(argc, argv) };
+    with_instance(|instance| {
+        let name = required_arg(&args, 0, "name")?;
+        let value = required_arg(&args, 1, "value")?;
+        let value: f64 = value
+            .trim()
+            .parse()
+            .map_err(|_| format!("Invalid plot value: {}", value))?;
+
+        plot(instance, name, value);
+        Ok(())
+    })
+}
+
+/// SAFETY: See init
+/// Begins a zone that the same proc must end with tracy_zone_end. Ended for it if it sleeps or returns first.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn tracy_zone_begin(
+    argc: c_int,
+    argv: *const *const c_char,
+) -> *const c_char {
+    // SAFETY: Forwarded from our caller
+    let args = unsafe { parse_args(argc, argv) };
+    with_instance(|instance| {
+        let name = required_arg(&args, 0, "name")?;
+
+        zone_begin(instance, name);
+        Ok(())
+    })
+}
+
+/// SAFETY: See init
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn tracy_zone_end(
+    _argc: c_int,
+    _argv: *const *const c_char,
+) -> *const c_char {
+    with_instance(zone_end)
+}
+
+/// SAFETY: See init
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn tracy_frame_mark(
+    argc: c_int,
+    argv: *const *const c_char,
+) -> *const c_char {
+    // SAFETY: Forwarded from our caller
+    let args = unsafe { parse_args(argc, argv) };
+    with_instance(|instance| {
+        frame_mark(
+            instance,
+            args.first()
+                .map(|name| name.as_ref())
+                .filter(|name| !name.is_empty()),
+        );
+        Ok(())
+    })
+}
+
+/// SAFETY: See init
+/// Returns the procs on this thread's callstack, innermost first and one per line.
+/// Procs that resumed from a sleep are suffixed with " (resumed)", the callers they had before sleeping are gone.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn get_callstack(_argc: c_int, _argv: *const *const c_char) -> *const c_char {
+    let Some(instance) = INSTANCE.get() else {
+        return return_string("not initialized".to_string());
+    };
+
+    return_string(callstack_names(instance).join("\n"))
+}
+
+/// SAFETY: See init
+/// Writes every callstack sampled so far to the given file in collapsed stack form. Requires mode=sampling.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn write_samples(argc: c_int, argv: *const *const c_char) -> *const c_char {
+    // SAFETY: Forwarded from our caller
+    let args = unsafe { parse_args(argc, argv) };
+    with_instance(|instance| {
+        let path = required_arg(&args, 0, "path")?;
+
+        write_samples_to(instance, path)
+    })
+}
+
+pub(crate) fn write_samples_to(instance: &Instance, path: &str) -> Result<(), String> {
+    let sampler = instance
+        .sampler
+        .as_ref()
+        .ok_or("Sampling is disabled, set mode=sampling")?;
+
+    folded::write(path, &instance.byond, &sampler.stacks())
+}
+
+/// SAFETY: See init
+/// Writes the aggregated call tree to the given file in collapsed stack form, weighted by self time in
+/// microseconds. Requires call_tree=true.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn write_call_tree(argc: c_int, argv: *const *const c_char) -> *const c_char {
+    // SAFETY: Forwarded from our caller
+    let args = unsafe { parse_args(argc, argv) };
+    with_instance(|instance| {
+        let path = required_arg(&args, 0, "path")?;
+
+        write_call_tree_to(instance, path)
+    })
+}
+
+pub(crate) fn write_call_tree_to(instance: &Instance, path: &str) -> Result<(), String> {
+    folded::write(path, &instance.byond, &call_tree(instance)?.self_times())
+}
+
+/// SAFETY: See init
+/// Writes the aggregated call tree to the given file as a gzipped pprof profile. Requires call_tree=true.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn write_pprof(argc: c_int, argv: *const *const c_char) -> *const c_char {
+    // SAFETY: Forwarded from our caller
+    let args = unsafe { parse_args(argc, argv) };
+    with_instance(|instance| {
+        let path = required_arg(&args, 0, "path")?;
+
+        write_pprof_to(instance, path)
+    })
+}
+
+pub(crate) fn write_pprof_to(instance: &Instance, path: &str) -> Result<(), String> {
+    pprof::write(path, &instance.byond, call_tree(instance)?)
+}
+
+/// SAFETY: See init
+/// Writes the flight recorder's last seconds of events, or all it holds if omitted, to the given file as
+/// Chrome Trace Event JSON. Requires the recorder sink.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn dump_flight_recorder(
+    argc: c_int,
+    argv: *const *const c_char,
+) -> *const c_char {
+    // SAFETY: Forwarded from our caller
+    let args = unsafe { parse_args(argc, argv) };
+    with_instance(|instance| {
+        let path = required_arg(&args, 0, "path")?;
+        let seconds = match args.get(1) {
+            Some(seconds) if !seconds.trim().is_empty() => Some(
+                seconds
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("Invalid seconds: {}", seconds))?,
+            ),
+            _ => None,
+        };
+
+        dump_flight_recorder_to(instance, path, seconds)
+    })
+}
+
+pub(crate) fn dump_flight_recorder_to(
+    instance: &Instance,
+    path: &str,
+    seconds: Option<f64>,
+) -> Result<(), String> {
+    let recorder = instance
+        .recorder
+        .as_ref()
+        .ok_or_else(|| "The flight recorder is disabled, add 'recorder' to sinks".to_string())?;
+    let window = seconds
+        .map(|seconds| {
+            Duration::try_from_secs_f64(seconds)
+                .map_err(|_| format!("Seconds must be a positive number, got {}", seconds))
+        })
+        .transpose()?;
+
+    recorder.dump(path, window)
+}
+
+fn call_tree(instance: &Instance) -> Result<&CallTree, String> {
+    instance
+        .call_tree
+        .as_ref()
+        .ok_or_else(|| "Call tree aggregation is disabled, set call_tree=true".to_string())
+}
+
+pub(crate) fn callstack_names(instance: &Instance) -> Vec<String> {
+    callstack::snapshot()
+        .iter()
+        .rev()
+        .map(|entry| {
+            let name = instance.byond.proc_name(entry.procdef);
+            if entry.resumed {
+                format!("{} (resumed)", name)
+            } else {
+                name
+            }
+        })
+        .collect()
+}
+
+pub(crate) fn message(instance: &Instance, text: &str, color: u32) {
+    instance.sinks.message(text, color);
+}
+
+pub(crate) fn plot(instance: &Instance, name: &str, value: f64) {
+    instance.sinks.plot(name, value);
+}
+
+pub(crate) fn zone_begin(instance: &Instance, name: &str) {
+    instance.sinks.zone_begin(ZoneName::Dm(name), 0);
+    push_dm_zone();
+}
+
+pub(crate) fn zone_end(instance: &Instance) -> Result<(), String> {
+    pop_dm_zone()?;
+    instance.sinks.zone_end();
+    Ok(())
+}
+
+/// Ends the DM zones the proc at depth left open. Called as it returns or sleeps.
+pub(crate) fn end_orphaned_zones(instance: &Instance, depth: usize) {
+    for _ in 0..take_orphaned_dm_zones(depth) {
+        instance.sinks.zone_end();
+    }
+}
+
+fn push_dm_zone() {
+    let depth = callstack::depth();
+    DM_ZONES.with_borrow_mut(|zones| zones.push(depth));
+}
+
+fn pop_dm_zone() -> Result<(), String> {
+    let depth = callstack::depth();
+    DM_ZONES.with_borrow_mut(|zones| match zones.last() {
+        None => Err("tracy_zone_end called without a matching tracy_zone_begin".to_string()),
+        Some(began) if *began != depth => Err(
+            "tracy_zone_end must be called by the proc that called tracy_zone_begin".to_string(),
+        ),
+        Some(_) => {
+            zones.pop();
+            Ok(())
+        }
+    })
+}
+
+/// Forgets the zones begun at depth or deeper, returning how many there were.
+fn take_orphaned_dm_zones(depth: usize) -> usize {
+    DM_ZONES.with_borrow_mut(|zones| {
+        // Zones only ever open at the depth of the innermost frame, so they're sorted by depth
+        let kept = zones.partition_point(|began| *began < depth);
+        zones.drain(kept..).count()
+    })
+}
+
+pub(crate) fn frame_mark(instance: &Instance, name: Option<&str>) {
+    instance.sinks.frame_mark(name);
+}
+
+/// SAFETY: argv must point to argc valid NUL terminated strings
+pub(crate) unsafe fn parse_args<'a>(argc: c_int, argv: *const *const c_char) -> Vec<Cow<'a, str>> {
+    if argc <= 0 || argv.is_null() {
+        return Vec::new();
+    }
+
+    // SAFETY: Guaranteed by our caller
+    unsafe { slice::from_raw_parts(argv, argc as usize) }
+        .iter()
+        .map(|arg| {
+            if arg.is_null() {
+                Cow::Borrowed("")
+            } else {
+                // SAFETY: Guaranteed by our caller
+                unsafe { CStr::from_ptr(*arg) }.to_string_lossy()
+            }
+        })
+        .collect()
+}
+
+fn required_arg<'a>(args: &'a [Cow<str>], index: usize, name: &str) -> Result<&'a str, String> {
+    match args.get(index) {
+        Some(arg) => Ok(arg),
+        None => Err(format!("Missing argument {}: {}", index + 1, name)),
+    }
+}
+
+/// Accepts "#RRGGBB" as produced by DM's rgb(), or a decimal number.
+pub(crate) fn parse_color(color: &str) -> Result<u32, String> {
+    let trimmed = color.trim();
+    let parsed = match trimmed.strip_prefix('#') {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => trimmed.parse(),
+    };
+
+    match parsed {
+        Ok(value) if value <= 0xFFFFFF => Ok(value),
+        _ => Err(format!("Invalid color: {}", color)),
+    }
+}
+
+fn with_instance(action: impl FnOnce(&Instance) -> Result<(), String>) -> *const c_char {
+    let Some(instance) = INSTANCE.get() else {
+        return return_string("not initialized".to_string());
+    };
+
+    match action(instance) {
+        Ok(()) => c"ok".as_ptr(),
+        Err(error) => return_string(error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pop_dm_zone, push_dm_zone, take_orphaned_dm_zones};
+    use crate::callstack;
+
+    #[test]
+    fn zones_must_end_in_the_proc_that_began_them() {
+        let _caller = callstack::enter(1, false);
+        push_dm_zone();
+
+        let callee = callstack::enter(2, false);
+        assert_eq!(
+            pop_dm_zone(),
+            Err(
+                "tracy_zone_end must be called by the proc that called tracy_zone_begin"
+                    .to_string()
+            )
+        );
+        drop(callee);
+
+        assert_eq!(pop_dm_zone(), Ok(()));
+        assert_eq!(
+            pop_dm_zone(),
+            Err("tracy_zone_end called without a matching tracy_zone_begin".to_string())
+        );
+    }
+
+    #[test]
+    fn zones_left_open_are_taken_when_their_proc_exits() {
+        let _caller = callstack::enter(1, false);
+        let caller_depth = callstack::depth();
+        push_dm_zone();
+
+        let callee = callstack::enter(2, false);
+        let callee_depth = callstack::depth();
+        push_dm_zone();
+        push_dm_zone();
+
+        assert_eq!(take_orphaned_dm_zones(callee_depth), 2);
+        drop(callee);
+
+        // The caller's zone is still its own to end
+        assert_eq!(take_orphaned_dm_zones(callee_depth), 0);
+        assert_eq!(pop_dm_zone(), Ok(()));
+        assert_eq!(take_orphaned_dm_zones(caller_depth), 0);
+    }
+}
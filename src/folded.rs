@@ -0,0 +1,47 @@
+//! Brendan Gregg's collapsed stack format, one "outer;inner count" line per stack, as read by
+//! flamegraph.pl, inferno and speedscope.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+};
+
+use crate::byond::ByondReflectionData;
+
+/// Writes stacks of procdef indices, outermost first, to path. Lines are sorted so files diff cleanly.
+pub(crate) fn write(
+    path: &str,
+    byond: &ByondReflectionData,
+    stacks: &HashMap<Vec<usize>, u64>,
+) -> Result<(), String> {
+    let mut names = HashMap::new();
+    let mut lines = stacks
+        .iter()
+        .filter(|(_, count)| **count > 0)
+        .map(|(stack, count)| {
+            let frames = stack
+                .iter()
+                .map(|procdef| {
+                    names
+                        .entry(*procdef)
+                        .or_insert_with(|| byond.proc_name(*procdef).replace([';', ' '], "_"))
+                        .clone()
+                })
+                .collect::<Vec<_>>();
+            format!("{} {}", frames.join(";"), count)
+        })
+        .collect::<Vec<_>>();
+    lines.sort_unstable();
+
+    let file =
+        File::create(path).map_err(|error| format!("Unable to create {}: {}", path, error))?;
+    let mut writer = BufWriter::new(file);
+    for line in lines {
+        writeln!(writer, "{}", line)
+            .map_err(|error| format!("Unable to write {}: {}", path, error))?;
+    }
+    writer
+        .flush()
+        .map_err(|error| format!("Unable to write {}: {}", path, error))
+}
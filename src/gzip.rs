@@ -0,0 +1,82 @@
+//! Just enough gzip to wrap data for tools that insist on it. Everything goes into stored (uncompressed)
+//! deflate blocks, so the output is a little larger than its input.
+
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+pub(crate) fn encode(data: &[u8]) -> Vec<u8> {
+    let blocks = data.len().div_ceil(MAX_STORED_BLOCK).max(1);
+    let mut out = Vec::with_capacity(data.len() + blocks * 5 + 18);
+
+    // Magic, deflate, no flags, no mtime, no extra flags, unknown OS
+    out.extend_from_slice(&[0x1F, 0x8B, 0x08, 0x00, 0, 0, 0, 0, 0x00, 0xFF]);
+
+    let mut chunks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if chunks.peek().is_none() {
+        out.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(chunk) = chunks.next() {
+        let last = chunks.peek().is_none();
+        let length = chunk.len() as u16;
+        // BFINAL in the low bit, BTYPE 00, then padding to the byte boundary
+        out.push(last as u8);
+        out.extend_from_slice(&length.to_le_bytes());
+        out.extend_from_slice(&(!length).to_le_bytes());
+        out.extend_from_slice(chunk);
+    }
+
+    out.extend_from_slice(&crc32(data).to_le_bytes());
+    out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut table = [0u32; 256];
+    for (index, entry) in table.iter_mut().enumerate() {
+        let mut value = index as u32;
+        for _ in 0..8 {
+            value = if value & 1 != 0 {
+                0xEDB88320 ^ (value >> 1)
+            } else {
+                value >> 1
+            };
+        }
+        *entry = value;
+    }
+
+    !data.iter().fold(!0u32, |crc, byte| {
+        table[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use flate2::read::GzDecoder;
+
+    use super::{MAX_STORED_BLOCK, crc32, encode};
+
+    fn decode(gzip: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        GzDecoder::new(gzip).read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn crc32_matches_the_check_value() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    }
+
+    #[test]
+    fn stored_blocks_round_trip() {
+        let long: Vec<u8> = (0..MAX_STORED_BLOCK * 2 + 7)
+            .map(|index| index as u8)
+            .collect();
+
+        for data in [&[][..], b"pprof", &long[..MAX_STORED_BLOCK], &long] {
+            // The decoder checks the trailing CRC32 and length as well as the blocks
+            assert_eq!(decode(&encode(data)), data, "{} bytes", data.len());
+        }
+    }
+}
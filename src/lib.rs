@@ -1,8 +1,35 @@
 #![feature(once_cell_try)]
+mod aggregate;
 mod byond;
-
-use crate::byond::{
-    BuildNumber, ByondReflectionData, DreamObject, MAX_PROCS, Proc, offsets::OFFSETS,
+mod byondapi;
+mod callstack;
+mod color;
+mod config;
+mod exports;
+mod folded;
+mod gzip;
+mod memory;
+#[cfg(target_os = "linux")]
+mod perf;
+mod pprof;
+mod sampler;
+mod sink;
+mod spike;
+mod tick;
+
+use crate::{
+    aggregate::CallTree,
+    byond::{
+        BuildNumber, ByondReflectionData, DreamObject, PreparedHooks, Proc,
+        offsets::{OFFSETS, Offsets},
+    },
+    config::{Config, InitArg, Mode, SinkKind},
+    exports::parse_args,
+    memory::MemoryTracker,
+    sampler::Sampler,
+    sink::{ChromeSink, FlightRecorder, ProfilerSink, Sinks, TracySink, ZoneName},
+    spike::SpikeCapture,
+    tick::TickStats,
 };
 #[cfg(not(target_os = "windows"))]
 use libloading::os::unix::{Library, RTLD_NOW};
@@ -10,14 +37,15 @@ use libloading::os::unix::{Library, RTLD_NOW};
 use libloading::os::windows::Library;
 use std::{
     cell::RefCell,
-    ffi::{CString, c_char, c_int},
-    ptr::null,
-    sync::OnceLock,
+    ffi::{CStr, CString, c_char, c_int, c_void},
+    sync::{Arc, OnceLock},
+    time::Instant,
 };
-use tracy_client::{Client, SpanLocation, internal::make_span_location};
+use tracy_client::Client;
 
-#[cfg(not(target_pointer_width = "32"))]
-compile_error!("Compiling for non-32bit is not allowed.");
+// Hooks are patched in as x86 machine code
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+compile_error!("Only x86 and x86_64 are supported.");
 
 static EMPTY_STRING: c_char = 0;
 thread_local! {
@@ -26,69 +54,66 @@ thread_local! {
 
 static INSTANCE: OnceLock<Instance> = OnceLock::new();
 
-static SERVER_TICK_SOURCE_LOCATION: OnceLock<SpanLocation> = OnceLock::new();
-
-static SEND_MAPS_SOURCE_LOCATION: OnceLock<SpanLocation> = OnceLock::new();
-
 struct Instance {
     pub byond: ByondReflectionData,
-    tracy_client: Client,
-    source_locations: [SpanLocation; MAX_PROCS],
-}
-
-impl Instance {
-    fn tracy_client(&self) -> Client {
-        self.tracy_client.clone()
-    }
+    config: Config,
+    /// Only present when the tracy sink is enabled
+    tracy_client: Option<Client>,
+    sinks: Sinks,
+    /// Indexed by procdef, whether the proc gets zones
+    zoned_procs: Vec<bool>,
+    proc_colors: Vec<u32>,
+    memory: Option<MemoryTracker>,
+    tick_stats: TickStats,
+    /// Only present in sampling mode
+    sampler: Option<Sampler>,
+    #[cfg(target_os = "linux")]
+    perf_map: Option<perf::PerfMap>,
+    call_tree: Option<CallTree>,
+    /// Also one of the sinks, kept here for dump_flight_recorder
+    recorder: Option<Arc<FlightRecorder>>,
+    spike_capture: Option<SpikeCapture>,
 }
 
-/// SAFETY: This function must only be called via the call()() or call_ext()() procs using the legacy API of a game running using Build Your Own Net Dream (BYOND, https://www.byond.com/).
+/// # Safety
+///
+/// This function must only be called via the call()() or call_ext()() procs using the legacy API of a game running using Build Your Own Net Dream (BYOND, https://www.byond.com/).
 /// It relies on reverse engineered internals of the game runtime
 #[unsafe(no_mangle)]
-pub unsafe extern "C" fn init(_argc: c_int, _argv: *const *const c_char) -> *const c_char {
-    init_core()
-}
-
-fn init_core() -> *const c_char {
-    let mut initialize_attempted = false;
-    match INSTANCE.get_or_try_init(|| {
-        initialize_attempted = true;
-        setup()
-    }) {
-        Ok(_) => if initialize_attempted {
-            "ok"
-        } else {
-            "already initialized"
-        }
-        .as_ptr() as *const c_char,
-        Err(error) => error,
+pub unsafe extern "C" fn init(argc: c_int, argv: *const *const c_char) -> *const c_char {
+    // SAFETY: BYOND passes argc valid strings
+    let args = unsafe { parse_args(argc, argv) };
+    let args: Vec<_> = args
+        .into_iter()
+        .map(|arg| InitArg::Text(arg.into_owned()))
+        .collect();
+    match Config::load(&args).and_then(init_core) {
+        Ok(true) => c"ok".as_ptr(),
+        Ok(false) => c"already initialized".as_ptr(),
+        Err(error) => return_string(error),
     }
 }
 
-fn setup() -> Result<Instance, *const c_char> {
-    let (byond_build, byondcore_base_address) = match get_byond_build_and_byondcore_handle() {
-        Ok(byond_build) => byond_build,
-        Err(error) => {
-            return Err(if error.is_empty() {
-                &EMPTY_STRING
-            } else {
-                RETURN_STRING.with(|cell| {
-                    // Panicking over an FFI boundary is bad form, so if a NUL ends up
-                    // in the result, just truncate.
-                    let cstring = match CString::new(error) {
-                        Ok(s) => s,
-                        Err(e) => {
-                            let (pos, mut vec) = (e.nul_position(), e.into_vec());
-                            vec.truncate(pos);
-                            CString::new(vec).unwrap_or_default()
-                        }
-                    };
-                    cell.replace(cstring);
-                    cell.borrow().as_ptr()
-                })
-            });
-        }
+/// Returns true if this call performed the initialization. config is discarded if already initialized.
+fn init_core(config: Config) -> Result<bool, String> {
+    let mut hooks = None;
+    INSTANCE.get_or_try_init(|| {
+        let (instance, prepared_hooks) = setup(config)?;
+        hooks = Some(prepared_hooks);
+        Ok::<_, String>(instance)
+    })?;
+
+    // Patched in last, so no hook can run before the instance it reads is stored
+    let Some(hooks) = hooks else {
+        return Ok(false);
     };
+    hooks.install();
+
+    Ok(true)
+}
+
+fn setup(config: Config) -> Result<(Instance, PreparedHooks), String> {
+    let (byond_build, byondcore_base_address) = get_byond_build_and_byondcore_handle()?;
 
     let mut target_offsets = None;
     for offsets in OFFSETS {
@@ -99,33 +124,221 @@ fn setup() -> Result<Instance, *const c_char> {
 
     let offsets = match target_offsets {
         Some(offsets) => offsets,
-        None => return Err("byond version unsupported".as_ptr() as *const c_char),
+        None if OFFSETS.is_empty() => {
+            return Err(format!(
+                "byond version unsupported, no {} build of BYOND is supported yet",
+                std::env::consts::ARCH
+            ));
+        }
+        None => return Err("byond version unsupported".to_string()),
     };
+    if config.memory_callstack_depth > 0 && offsets.allocator.is_none() {
+        return Err(format!(
+            "memory_callstack_depth needs BYOND's allocator, which isn't mapped for build {}",
+            byond_build
+        ));
+    }
 
-    let byond = match ByondReflectionData::create_and_initialize_hooks(
+    // Anything that can fail goes before the hooks are prepared, which hold BYOND's code writable
+    let sampler = match config.mode {
+        Mode::Sampling => Some(Sampler::start(config.sample_rate)?),
+        Mode::Zones | Mode::Frames => None,
+    };
+    #[cfg(target_os = "linux")]
+    let perf_map = if config.perf_map {
+        Some(perf::PerfMap::reserve()?)
+    } else {
+        None
+    };
+    let chrome_sink = if config.sinks.contains(&SinkKind::Chrome) {
+        Some(ChromeSink::create(&config.chrome_trace)?)
+    } else {
+        None
+    };
+    let spike_capture = SpikeCapture::new(&config)?;
+
+    let (byond, hooks) = ByondReflectionData::create_and_prepare_hooks(
         offsets,
         byondcore_base_address,
         exec_proc_hook,
         server_tick_hook,
         send_maps_hook,
-    ) {
-        Ok(data) => data,
-        Err(error) => return Err(error.as_ptr() as *const c_char),
+        malloc_hook,
+        free_hook,
+        runtime_hook,
+    )?;
+
+    let zoned_procs = match config.mode {
+        Mode::Zones => (0..byond.procs_len())
+            .map(|index| config.includes_proc(byond.proc_info(index).path.unwrap_or_default()))
+            .collect(),
+        Mode::Frames | Mode::Sampling => Vec::new(),
+    };
+    let proc_colors = (0..zoned_procs.len())
+        .map(|index| {
+            let path = byond.proc_info(index).path.unwrap_or_default();
+            color::proc_color(&config, &path.to_string_lossy())
+        })
+        .collect();
+
+    let call_tree = config.call_tree.then(CallTree::new);
+    #[cfg(target_os = "linux")]
+    let perf_map = perf_map.map(|perf_map| perf_map.populate(&byond));
+
+    let mut sinks: Vec<Box<dyn ProfilerSink>> = Vec::new();
+    let tracy_client = if config.sinks.contains(&SinkKind::Tracy) {
+        configure_tracy(&config);
+        let tracy_client = Client::start();
+        send_app_info(&config, byond_build, offsets);
+
+        sinks.push(Box::new(TracySink::new(
+            tracy_client.clone(),
+            byond.build_source_locations(|path| {
+                config.mode == Mode::Zones && config.includes_proc(path)
+            }),
+        )));
+        Some(tracy_client)
+    } else {
+        None
+    };
+    let proc_names = || {
+        (0..byond.procs_len())
+            .map(|index| byond.proc_name(index))
+            .collect()
     };
+    if let Some(chrome_sink) = chrome_sink {
+        sinks.push(Box::new(chrome_sink.with_proc_names(proc_names())));
+    }
+    let recorder = config.sinks.contains(&SinkKind::Recorder).then(|| {
+        Arc::new(FlightRecorder::new(config.recorder_capacity).with_proc_names(proc_names()))
+    });
+    if let Some(recorder) = &recorder {
+        sinks.push(Box::new(recorder.clone()));
+    }
 
     let instance = Instance {
+        // Tracy is the only sink that understands allocations
+        memory: (byond.allocator_hooked && tracy_client.is_some())
+            .then(|| MemoryTracker::new(config.memory_callstack_depth)),
         byond,
-        tracy_client: Client::start(),
-        source_locations: byond.build_source_locations(),
+        config,
+        tracy_client,
+        sinks: Sinks::new(sinks),
+        zoned_procs,
+        proc_colors,
+        tick_stats: TickStats::default(),
+        sampler,
+        #[cfg(target_os = "linux")]
+        perf_map,
+        call_tree,
+        recorder,
+        spike_capture,
+    };
+    for limitation in offsets.limitations() {
+        instance.sinks.message(limitation, LIMITATION_MESSAGE_COLOR);
+    }
+
+    Ok((instance, hooks))
+}
+
+/// Applies the settings Tracy reads from the process when it starts, so this must run before Client::start.
+fn configure_tracy(config: &Config) {
+    if let Some(port) = config.tracy_port() {
+        // SAFETY: Other BYOND threads could be reading the environment. Nothing in the runtime is
+        // known to do so after startup, and Tracy only reads it once, on our thread, during start
+        unsafe { std::env::set_var("TRACY_PORT", port.to_string()) };
+    }
+
+    if let Some(program_name) = config.program_name() {
+        set_program_name(program_name);
+    }
+}
+
+/// Tags the capture with where it came from, so saved traces can be compared later. Tracy must be running.
+fn send_app_info(config: &Config, byond_build: BuildNumber, offsets: &Offsets) {
+    let mut lines = vec![
+        format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION")),
+        format!("BYOND build: {}", byond_build),
+        format!("OS: {} {}", std::env::consts::OS, std::env::consts::ARCH),
+    ];
+
+    if let Some(world_name) = &config.world_name {
+        lines.push(format!("World: {}", world_name));
+    }
+    if let Some(world_port) = config.world_port {
+        lines.push(format!("Port: {}", world_port));
+    }
+    if !cfg!(target_os = "linux") && config.program_name().is_some() {
+        lines
+            .push("Server list name: unsupported on Windows, listed as the executable".to_string());
+    }
+    lines.push(format!("Offsets: {}", offsets.describe()));
+    lines.extend(offsets.limitations().into_iter().map(String::from));
+
+    let app_info = lines.join("\n");
+    // SAFETY: Tracy copies the text before returning
+    unsafe {
+        tracy_client::sys::___tracy_emit_message_appinfo(app_info.as_ptr().cast(), app_info.len())
     };
+}
 
-    Ok(instance)
+/// Tracy reads the broadcast name from glibc's program_invocation_short_name on Linux.
+#[cfg(target_os = "linux")]
+fn set_program_name(program_name: String) {
+    unsafe extern "C" {
+        static mut program_invocation_short_name: *const c_char;
+    }
+
+    let Ok(program_name) = CString::new(program_name) else {
+        return;
+    };
+
+    // SAFETY: glibc never frees this pointer, and the string is leaked so it lives as long as the process
+    unsafe { program_invocation_short_name = program_name.into_raw() };
+}
+
+/// Tracy takes the broadcast name from the executable path on Windows, which can't be changed from here.
+/// send_app_info notes that the name was left out.
+#[cfg(not(target_os = "linux"))]
+fn set_program_name(_program_name: String) {}
+
+fn return_string(string: String) -> *const c_char {
+    if string.is_empty() {
+        return &EMPTY_STRING;
+    }
+
+    RETURN_STRING.with(|cell| {
+        // Panicking over an FFI boundary is bad form, so if a NUL ends up
+        // in the result, just truncate.
+        let cstring = match CString::new(string) {
+            Ok(s) => s,
+            Err(e) => {
+                let (pos, mut vec) = (e.nul_position(), e.into_vec());
+                vec.truncate(pos);
+                CString::new(vec).unwrap_or_default()
+            }
+        };
+        cell.replace(cstring);
+        cell.borrow().as_ptr()
+    })
 }
 
+/// MSVC's name for `public: long __thiscall ByondLib::GetByondBuild(void)`.
+#[cfg(all(target_os = "windows", target_arch = "x86"))]
+const GET_BYOND_BUILD_SYMBOL: &str = "?GetByondBuild@ByondLib@@QAEJXZ";
+
+/// MSVC's name for `public: long __cdecl ByondLib::GetByondBuild(void) __ptr64`.
+#[cfg(all(target_os = "windows", target_arch = "x86_64"))]
+const GET_BYOND_BUILD_SYMBOL: &str = "?GetByondBuild@ByondLib@@QEAAJXZ";
+
+/// The same function as named by g++. ELF symbols can't carry MSVC names, the linker reads '@' as a version.
+#[cfg(not(target_os = "windows"))]
+const GET_BYOND_BUILD_SYMBOL: &str = "_ZN8ByondLib13GetByondBuildEv";
+
 fn get_byond_build_and_byondcore_handle() -> Result<(BuildNumber, usize), String> {
     let byondcore_handle = get_byondcore_handle()?;
 
-    let get_byond_build_name = "?GetByondBuild@ByondLib@@QAEJXZ";
+    let get_byond_build_name = GET_BYOND_BUILD_SYMBOL;
     // SAFETY: The symbol specified using get_byond_build_name demangles to the following C++ declaration:
     // public: long __thiscall ByondLib::GetByondBuild(void)
     // Reverse engineering shows the "this" pointer is not used in this function
@@ -145,7 +358,22 @@ fn get_byond_build_and_byondcore_handle() -> Result<(BuildNumber, usize), String
     // SAFETY: GetByondBuild() is essentially a static const function
     let build_number = unsafe { get_byond_build() };
 
-    Ok((build_number, byondcore_handle.into_raw() as usize))
+    Ok((build_number, base_address(byondcore_handle)))
+}
+
+/// On Windows a module handle is the address the module was loaded at.
+#[cfg(target_os = "windows")]
+fn base_address(handle: Library) -> usize {
+    handle.into_raw() as usize
+}
+
+/// dlopen's handle is glibc's struct link_map, which begins with l_addr, the difference between the
+/// addresses the library was linked at and where it was loaded. libbyond.so is linked at 0, so that's
+/// its base address.
+#[cfg(not(target_os = "windows"))]
+fn base_address(handle: Library) -> usize {
+    // SAFETY: The handle stays valid since the library is never closed, and l_addr is part of link.h's stable ABI
+    unsafe { *(handle.into_raw() as *const usize) }
 }
 
 #[cfg(target_os = "windows")]
@@ -155,7 +383,7 @@ fn get_byondcore_handle() -> Result<Library, String> {
     let handle_acquisition_result = Library::open_already_loaded(byond_dll_name);
 
     match handle_acquisition_result {
-        Ok(handle) => Ok(handle.into()),
+        Ok(handle) => Ok(handle),
         Err(error) => Err(format!(
             "Unable to find {} handle: {}",
             byond_dll_name, error,
@@ -177,7 +405,7 @@ fn get_byondcore_handle() -> Result<Library, String> {
         unsafe { Library::open(Some(byond_so_name), RTLD_NOW | RTLD_NOLOAD) };
 
     match handle_acquisition_result {
-        Ok(handle) => Ok(handle.into()),
+        Ok(handle) => Ok(handle),
         Err(error) => Err(format!(
             "Unable to find {} address: {}",
             byond_so_name, error,
@@ -185,49 +413,92 @@ fn get_byondcore_handle() -> Result<Library, String> {
     }
 }
 
-#[cfg(target_os = "windows")]
 unsafe extern "C" fn exec_proc_hook(proc: *const Proc) -> DreamObject {
     exec_proc_hook_core(proc)
 }
 
-#[cfg(not(target_os = "windows"))]
-unsafe extern "regparm(3)" fn exec_proc_hook(proc: *const Proc) -> DreamObject {
-    exec_proc_hook_core(proc)
-}
-
 #[inline(always)]
 fn exec_proc_hook_core(proc: *const Proc) -> DreamObject {
     let instance_ref = INSTANCE
         .get()
         .expect("(exec_proc_hook) Hook installed but OnceLock empty!");
-    let orig_exec_proc = instance_ref.byond.orig_exec_proc;
+
+    match &instance_ref.call_tree {
+        Some(call_tree) => call_tree.hook(|| exec_proc_hook_body(instance_ref, proc)),
+        None => exec_proc_hook_body(instance_ref, proc),
+    }
+}
+
+#[inline(always)]
+fn exec_proc_hook_body(instance_ref: &Instance, proc: *const Proc) -> DreamObject {
     let proc_ref: &Proc = unsafe { &*proc };
-    if proc_ref.procdef < instance_ref.source_locations.len()
-        && let Some(srcloc) = &instance_ref.source_locations[proc_ref.procdef]
-    {
-        let zone = instance_ref.tracy_client().span(srcloc, 0);
+    // procs with pre-existing contexts are resuming from sleep
+    let resumed = !proc_ref.context.is_null();
+    let procdef = proc_ref.procdef();
+    let _frame = callstack::enter(procdef, resumed);
+    let depth = callstack::depth();
 
-        // procs with pre-existing contexts are resuming from sleep
-        if !proc_ref.context.is_null() {
-            zone.emit_color(0xAF4444);
+    let orig_exec_proc = instance_ref.byond.orig_exec_proc;
+    let call_orig_exec_proc = || {
+        #[cfg(target_os = "linux")]
+        let call = || match &instance_ref.perf_map {
+            Some(perf_map) => unsafe { perf_map.call(orig_exec_proc, proc) },
+            None => unsafe { orig_exec_proc(proc) },
+        };
+        #[cfg(not(target_os = "linux"))]
+        let call = || unsafe { orig_exec_proc(proc) };
+
+        let return_value = match &instance_ref.call_tree {
+            Some(call_tree) => call_tree.time(procdef, call),
+            None => call(),
+        };
+        // Before the proc's own zone ends, so zones still nest
+        exports::end_orphaned_zones(instance_ref, depth);
+
+        return_value
+    };
+
+    let _proc_scope = instance_ref.tick_stats.proc_scope();
+
+    // The sampler does the rest from its own thread, keep the hot path as short as possible
+    if instance_ref.sampler.is_some() {
+        return call_orig_exec_proc();
+    }
+
+    if instance_ref.zoned_procs.get(procdef) == Some(&true) {
+        let color = if resumed {
+            instance_ref.config.resumed_color
+        } else {
+            instance_ref.proc_colors[procdef]
+        };
+        let zone = instance_ref.sinks.zone(ZoneName::Proc(procdef), color);
+
+        if instance_ref.config.capture_arguments {
+            let arguments = proc_ref
+                .arguments()
+                .iter()
+                .map(|argument| instance_ref.byond.describe_value(argument))
+                .collect::<Vec<_>>()
+                .join(", ");
+            zone.text(&arguments);
         }
 
-        let return_value = unsafe { orig_exec_proc(proc) };
+        let return_value = call_orig_exec_proc();
 
         drop(zone);
 
         return_value
     } else {
-        unsafe { orig_exec_proc(proc) }
+        call_orig_exec_proc()
     }
 }
 
-#[cfg(target_os = "windows")]
+#[cfg(all(target_os = "windows", target_arch = "x86"))]
 unsafe extern "stdcall" fn server_tick_hook() -> i32 {
     server_tick_hook_core()
 }
 
-#[cfg(not(target_os = "windows"))]
+#[cfg(not(all(target_os = "windows", target_arch = "x86")))]
 unsafe extern "C" fn server_tick_hook() -> i32 {
     server_tick_hook_core()
 }
@@ -236,43 +507,160 @@ unsafe extern "C" fn server_tick_hook() -> i32 {
 fn server_tick_hook_core() -> i32 {
     let instance_ref = INSTANCE
         .get()
-        .expect("(exec_proc_hook) Hook installed but OnceLock empty!");
+        .expect("(server_tick_hook) Hook installed but OnceLock empty!");
     let orig_server_tick = instance_ref.byond.orig_server_tick;
 
-    let tracy_client = instance_ref.tracy_client();
+    let sinks = &instance_ref.sinks;
 
-    tracy_client.frame_mark();
+    sinks.frame_mark(None);
 
-    let zone = tracy_client.span(
-        SERVER_TICK_SOURCE_LOCATION.get_or_init(|| {
-            // TODO: Colour
-            make_span_location("ServerTick", null(), "Unknown".as_bytes().as_ptr(), 1)
-        }),
-        0,
-    );
+    let object_counts = instance_ref.byond.object_counts();
+    sinks.plot("Strings", object_counts.strings as f64);
+    sinks.plot("Miscs", object_counts.miscs as f64);
+    if let Some(datums) = object_counts.datums {
+        sinks.plot("Datums", datums as f64);
+    }
+    if let Some(lists) = object_counts.lists {
+        sinks.plot("Lists", lists as f64);
+    }
+
+    let zone = instance_ref
+        .sinks
+        .zone(ZoneName::ServerTick, instance_ref.config.server_tick_color);
 
+    let tick_start = Instant::now();
     let interval = unsafe { orig_server_tick() };
+    let tick_duration = tick_start.elapsed();
+
+    let mut sampled_procs = Vec::new();
+    if let Some(sampler) = &instance_ref.sampler {
+        let tick_samples = sampler.take_tick();
+        sinks.plot("Samples", tick_samples.count as f64);
+
+        sampled_procs = tick_samples
+            .hottest
+            .iter()
+            .map(|(procdef, count)| {
+                format!(
+                    "{:.1}% {}",
+                    *count as f64 * 100.0 / tick_samples.count as f64,
+                    instance_ref.byond.proc_name(*procdef)
+                )
+            })
+            .collect();
+        if !sampled_procs.is_empty() {
+            zone.text(&sampled_procs.join("\n"));
+        }
+    }
 
     drop(zone);
 
+    if let Some(spike_capture) = &instance_ref.spike_capture {
+        spike_capture.check_tick(instance_ref, tick_start, tick_duration, &sampled_procs);
+    }
+
+    let tick_sample = instance_ref.tick_stats.take();
+    sinks.plot("Tick Interval", interval as f64);
+    sinks.plot("Tick Duration (ms)", tick_duration.as_secs_f64() * 1000.0);
+    sinks.plot("Proc Calls", tick_sample.proc_calls as f64);
+    sinks.plot(
+        "Proc Time (ms)",
+        tick_sample.proc_time.as_secs_f64() * 1000.0,
+    );
+    sinks.plot(
+        "SendMaps Time (ms)",
+        tick_sample.send_maps_time.as_secs_f64() * 1000.0,
+    );
+
     interval
 }
 
 unsafe extern "C" fn send_maps_hook() {
     let instance_ref = INSTANCE
         .get()
-        .expect("(exec_proc_hook) Hook installed but OnceLock empty!");
+        .expect("(send_maps_hook) Hook installed but OnceLock empty!");
     let orig_send_maps = instance_ref.byond.orig_send_maps;
 
-    let zone = instance_ref.tracy_client().span(
-        SEND_MAPS_SOURCE_LOCATION.get_or_init(|| {
-            // TODO: Colour
-            make_span_location("SendMaps", null(), "Unknown".as_bytes().as_ptr(), 2)
-        }),
-        0,
-    );
+    let zone = instance_ref
+        .sinks
+        .zone(ZoneName::SendMaps, instance_ref.config.send_maps_color);
 
+    let send_maps_start = Instant::now();
     unsafe { orig_send_maps() };
+    instance_ref
+        .tick_stats
+        .record_send_maps(send_maps_start.elapsed());
 
     drop(zone);
 }
+
+unsafe extern "C" fn malloc_hook(size: usize) -> *mut c_void {
+    // SAFETY: We are malloc_hook
+    let pointer = unsafe { byond::orig_malloc()(size) };
+
+    if let Some(instance_ref) = INSTANCE.get()
+        && let Some(memory) = &instance_ref.memory
+        && let Some(tracy_client) = &instance_ref.tracy_client
+    {
+        memory.alloc(tracy_client, pointer, size);
+    }
+
+    pointer
+}
+
+unsafe extern "C" fn free_hook(pointer: *mut c_void) {
+    // Reported before releasing so a reallocation of the same address can't be seen first
+    if let Some(instance_ref) = INSTANCE.get()
+        && let Some(memory) = &instance_ref.memory
+        && let Some(tracy_client) = &instance_ref.tracy_client
+    {
+        memory.free(tracy_client, pointer);
+    }
+
+    // SAFETY: We are free_hook
+    unsafe { byond::orig_free()(pointer) }
+}
+
+const RUNTIME_MESSAGE_COLOR: u32 = 0xFF0000;
+
+/// Yellow, for what the build's offsets leave out.
+const LIMITATION_MESSAGE_COLOR: u32 = 0xFFC000;
+
+unsafe extern "C" fn runtime_hook(error: *const c_char) {
+    let instance_ref = INSTANCE
+        .get()
+        .expect("(runtime_hook) Hook installed but OnceLock empty!");
+    let orig_runtime = instance_ref
+        .byond
+        .orig_runtime
+        .expect("(runtime_hook) Hook installed without an original!");
+
+    let error_text = if error.is_null() {
+        "<no message>".into()
+    } else {
+        // SAFETY: BYOND passes the formatted error as a C string
+        unsafe { CStr::from_ptr(error) }.to_string_lossy()
+    };
+    let stack = callstack::snapshot();
+    let proc_path = stack.last().map_or("<unknown proc>".to_string(), |entry| {
+        instance_ref.byond.proc_name(entry.procdef)
+    });
+    let callers = stack
+        .iter()
+        .rev()
+        .skip(1)
+        .map(|entry| {
+            format!(
+                "\n  called by {}",
+                instance_ref.byond.proc_name(entry.procdef)
+            )
+        })
+        .collect::<String>();
+
+    instance_ref.sinks.message(
+        &format!("Runtime in {}: {}{}", proc_path, error_text, callers),
+        RUNTIME_MESSAGE_COLOR,
+    );
+
+    unsafe { orig_runtime(error) }
+}
@@ -0,0 +1,139 @@
+use std::{
+    collections::HashSet,
+    ffi::c_void,
+    sync::{Mutex, MutexGuard},
+};
+
+use tracy_client::{Client, sys};
+
+/// Independently locked parts of the live set. Every BYOND thread allocates, so one lock would have
+/// them all queue up behind each other. A power of two, so a shard is the top bits of a hash.
+const SHARDS: usize = 64;
+
+const _: () = assert!(SHARDS.is_power_of_two());
+
+/// Forwards BYOND heap activity to Tracy's memory profiler.
+///
+/// Tracy attributes each event to the thread and time it was emitted at, so allocations land inside
+/// whichever proc zone exec_proc_hook_core has open.
+pub(crate) struct MemoryTracker {
+    // Tracy aborts the capture on a free it never saw allocated, which is every block
+    // that existed before init, so only pointers reported here are released
+    live: LiveSet,
+    callstack_depth: u16,
+}
+
+impl MemoryTracker {
+    pub fn new(callstack_depth: u16) -> Self {
+        Self {
+            live: LiveSet::new(),
+            callstack_depth,
+        }
+    }
+
+    pub fn alloc(&self, _client: &Client, pointer: *mut c_void, size: usize) {
+        if pointer.is_null() {
+            return;
+        }
+
+        // Held until the event is out, so a free of the same block on another thread can't go first
+        let Some(_shard) = self.live.insert(pointer as usize) else {
+            return;
+        };
+
+        // SAFETY: Holding a Client guarantees the profiler is running
+        unsafe {
+            if self.callstack_depth > 0 {
+                sys::___tracy_emit_memory_alloc_callstack(
+                    pointer,
+                    size,
+                    self.callstack_depth.into(),
+                    0,
+                );
+            } else {
+                sys::___tracy_emit_memory_alloc(pointer, size, 0);
+            }
+        }
+    }
+
+    pub fn free(&self, _client: &Client, pointer: *mut c_void) {
+        let Some(_shard) = self.live.remove(pointer as usize) else {
+            return;
+        };
+
+        // SAFETY: Holding a Client guarantees the profiler is running
+        unsafe {
+            if self.callstack_depth > 0 {
+                sys::___tracy_emit_memory_free_callstack(pointer, self.callstack_depth.into(), 0);
+            } else {
+                sys::___tracy_emit_memory_free(pointer, 0);
+            }
+        }
+    }
+}
+
+/// The blocks reported as allocated and not yet freed, sharded by address.
+struct LiveSet {
+    shards: [Mutex<HashSet<usize>>; SHARDS],
+}
+
+type ShardGuard<'a> = MutexGuard<'a, HashSet<usize>>;
+
+impl LiveSet {
+    fn new() -> Self {
+        Self {
+            shards: std::array::from_fn(|_| Mutex::new(HashSet::new())),
+        }
+    }
+
+    /// Returns the locked shard if pointer wasn't already live.
+    fn insert(&self, pointer: usize) -> Option<ShardGuard<'_>> {
+        let mut shard = self.shard(pointer)?;
+        shard.insert(pointer).then_some(shard)
+    }
+
+    /// Returns the locked shard if pointer was live.
+    fn remove(&self, pointer: usize) -> Option<ShardGuard<'_>> {
+        let mut shard = self.shard(pointer)?;
+        shard.remove(&pointer).then_some(shard)
+    }
+
+    fn shard(&self, pointer: usize) -> Option<ShardGuard<'_>> {
+        // Blocks are at least 8 byte aligned, so the low bits say nothing. Fibonacci hashing
+        // spreads neighbouring blocks over every shard
+        let hash = ((pointer >> 3) as u64).wrapping_mul(0x9E3779B97F4A7C15);
+        let index = (hash >> (u64::BITS - SHARDS.trailing_zeros())) as usize;
+        self.shards[index].lock().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LiveSet, SHARDS};
+
+    #[test]
+    fn only_live_blocks_are_removed() {
+        let live = LiveSet::new();
+
+        assert!(live.remove(0x1000).is_none());
+        assert!(live.insert(0x1000).is_some());
+        assert!(live.insert(0x1000).is_none());
+        assert!(live.remove(0x1000).is_some());
+        assert!(live.remove(0x1000).is_none());
+    }
+
+    #[test]
+    fn neighbouring_blocks_spread_over_the_shards() {
+        let live = LiveSet::new();
+        for index in 0..SHARDS * 16 {
+            drop(live.insert(0x10000 + index * 16));
+        }
+
+        let used = live
+            .shards
+            .iter()
+            .filter(|shard| !shard.lock().unwrap().is_empty())
+            .count();
+        assert_eq!(used, SHARDS);
+    }
+}
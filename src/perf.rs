@@ -0,0 +1,178 @@
+//! Symbols for Linux perf, so native profiles attribute time to DM procs instead of one big exec_proc.
+//!
+//! Every procdef gets a small stub that exec_proc_hook_core calls the original exec_proc through,
+//! and /tmp/perf-<pid>.map names each stub after its proc. The stubs keep a frame pointer, so a
+//! `perf record --call-graph=fp` sample taken anywhere inside a proc unwinds through its stub.
+
+use std::{
+    alloc::{Layout, alloc_zeroed},
+    fs::File,
+    io::{BufWriter, Write},
+    mem::MaybeUninit,
+};
+
+use crate::byond::{self, ByondReflectionData, DreamObject, ExecProcFunction, MAX_PROCS, Proc};
+
+#[cfg(target_arch = "x86")]
+const STUB_SIZE: usize = 16;
+
+/// Offset of the call's rel32 operand in STUB, and of the instruction after the call
+#[cfg(target_arch = "x86")]
+const STUB_CALL_OPERAND: usize = 10;
+#[cfg(target_arch = "x86")]
+const STUB_CALL_END: usize = 14;
+
+#[cfg(target_arch = "x86")]
+#[rustfmt::skip]
+const STUB: [u8; STUB_SIZE] = [
+    0x55,                         // push ebp
+    0x89, 0xE5,                   // mov ebp, esp
+    0x83, 0xEC, 0x04,             // sub esp, 4 (keeps the stack 16 byte aligned at the call)
+    0xFF, 0x75, 0x08,             // push dword [ebp + 8]
+    0xE8, 0x00, 0x00, 0x00, 0x00, // call perf_shim
+    0xC9,                         // leave
+    0xC3,                         // ret
+];
+
+#[cfg(target_arch = "x86_64")]
+const STUB_SIZE: usize = 32;
+
+/// Offset of perf_shim's absolute address in STUB. The argument is left in rdi for it
+#[cfg(target_arch = "x86_64")]
+const STUB_SHIM_ADDRESS: usize = 6;
+
+#[cfg(target_arch = "x86_64")]
+#[rustfmt::skip]
+const STUB: [u8; STUB_SIZE] = [
+    0x55,                                           // push rbp
+    0x48, 0x89, 0xE5,                               // mov rbp, rsp
+    0x48, 0xB8, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // mov rax, perf_shim
+    0x00, 0x00,
+    0xFF, 0xD0,                                     // call rax
+    0x5D,                                           // pop rbp
+    0xC3,                                           // ret
+    0xCC, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC, // padding
+    0xCC, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC,
+];
+
+type StubFunction = unsafe extern "C" fn(*mut PerfCall);
+
+/// Passed through a stub to perf_shim, which makes the actual call.
+#[repr(C)]
+struct PerfCall {
+    orig_exec_proc: ExecProcFunction,
+    proc: *const Proc,
+    result: MaybeUninit<DreamObject>,
+}
+
+pub(crate) struct PerfMap {
+    stubs: usize,
+    file: BufWriter<File>,
+    /// Stubs written by populate, procdefs past this are called directly
+    len: usize,
+}
+
+impl PerfMap {
+    /// Allocates stubs for as many procs as BYOND supports and creates the map file. This is all that
+    /// can fail, so it's done before hooking.
+    pub fn reserve() -> Result<Self, String> {
+        let path = format!("/tmp/perf-{}.map", std::process::id());
+        let file =
+            File::create(&path).map_err(|error| format!("Unable to create {}: {}", path, error))?;
+
+        let layout = Layout::from_size_align(MAX_PROCS * STUB_SIZE, byond::PAGE_SIZE)
+            .map_err(|error| error.to_string())?;
+        // SAFETY: The layout has a non-zero size
+        let stubs = unsafe { alloc_zeroed(layout) } as usize;
+        if stubs == 0 {
+            return Err("Unable to allocate perf stubs".to_string());
+        }
+
+        // Stubs are only written once, but some systems refuse writable and executable memory. Better to find out now
+        byond::make_writable_executable(stubs, layout.size())?;
+
+        Ok(Self {
+            stubs,
+            file: BufWriter::new(file),
+            len: 0,
+        })
+    }
+
+    /// Writes a stub and map entry for every procdef. Failing to write the map only costs the names.
+    pub fn populate(mut self, byond: &ByondReflectionData) -> Self {
+        self.len = byond.procs_len();
+
+        for procdef in 0..self.len {
+            let address = self.stubs + procdef * STUB_SIZE;
+            let stub = stub(address);
+
+            // SAFETY: reserve made room for MAX_PROCS stubs, which procs_len never exceeds
+            unsafe { std::ptr::copy_nonoverlapping(stub.as_ptr(), address as *mut u8, STUB_SIZE) };
+
+            let _ = writeln!(
+                self.file,
+                "{:x} {:x} {}",
+                address,
+                STUB_SIZE,
+                byond.proc_name(procdef)
+            );
+        }
+
+        let _ = self.file.flush();
+
+        // Still runnable if this fails, just left writable
+        let _ = byond::make_executable(self.stubs, MAX_PROCS * STUB_SIZE);
+        self
+    }
+
+    /// Calls orig_exec_proc through the proc's stub.
+    ///
+    /// SAFETY: proc must be valid to pass to orig_exec_proc
+    pub unsafe fn call(&self, orig_exec_proc: ExecProcFunction, proc: *const Proc) -> DreamObject {
+        // SAFETY: Guaranteed by our caller
+        let procdef = unsafe { (*proc).procdef() };
+        if procdef >= self.len {
+            // SAFETY: Guaranteed by our caller
+            return unsafe { orig_exec_proc(proc) };
+        }
+
+        let mut call = PerfCall {
+            orig_exec_proc,
+            proc,
+            result: MaybeUninit::uninit(),
+        };
+
+        // SAFETY: populate wrote a stub here, and it calls perf_shim with the pointer it was given
+        unsafe {
+            let stub: StubFunction = std::mem::transmute(self.stubs + procdef * STUB_SIZE);
+            stub(&mut call);
+            call.result.assume_init()
+        }
+    }
+}
+
+/// STUB as it runs from address.
+#[cfg(target_arch = "x86")]
+fn stub(address: usize) -> [u8; STUB_SIZE] {
+    let mut stub = STUB;
+    let offset = (perf_shim as StubFunction as usize).wrapping_sub(address + STUB_CALL_END);
+    stub[STUB_CALL_OPERAND..STUB_CALL_END].copy_from_slice(&(offset as u32).to_le_bytes());
+    stub
+}
+
+/// STUB as it runs from address.
+#[cfg(target_arch = "x86_64")]
+fn stub(_address: usize) -> [u8; STUB_SIZE] {
+    let mut stub = STUB;
+    stub[STUB_SHIM_ADDRESS..STUB_SHIM_ADDRESS + size_of::<usize>()]
+        .copy_from_slice(&(perf_shim as StubFunction as usize).to_le_bytes());
+    stub
+}
+
+unsafe extern "C" fn perf_shim(call: *mut PerfCall) {
+    // SAFETY: Only called from a stub, with the PerfCall built by PerfMap::call
+    unsafe {
+        let call = &mut *call;
+        call.result.write((call.orig_exec_proc)(call.proc));
+    }
+}
@@ -0,0 +1,187 @@
+//! The aggregated call tree as a gzipped pprof profile.proto, for `go tool pprof` and friends.
+//!
+//! Each proc is a function with one location at the line it starts on. Samples carry the call count
+//! and self time of one call tree node.
+
+use std::{collections::HashMap, fs, time::UNIX_EPOCH};
+
+use crate::{aggregate::CallTree, byond::ByondReflectionData, gzip};
+
+// Field numbers from profile.proto
+const PROFILE_SAMPLE_TYPE: u32 = 1;
+const PROFILE_SAMPLE: u32 = 2;
+const PROFILE_LOCATION: u32 = 4;
+const PROFILE_FUNCTION: u32 = 5;
+const PROFILE_STRING_TABLE: u32 = 6;
+const PROFILE_TIME_NANOS: u32 = 9;
+const PROFILE_DURATION_NANOS: u32 = 10;
+
+const VALUE_TYPE_TYPE: u32 = 1;
+const VALUE_TYPE_UNIT: u32 = 2;
+
+const SAMPLE_LOCATION_ID: u32 = 1;
+const SAMPLE_VALUE: u32 = 2;
+
+const LOCATION_ID: u32 = 1;
+const LOCATION_LINE: u32 = 4;
+
+const LINE_FUNCTION_ID: u32 = 1;
+const LINE_LINE: u32 = 2;
+
+const FUNCTION_ID: u32 = 1;
+const FUNCTION_NAME: u32 = 2;
+const FUNCTION_SYSTEM_NAME: u32 = 3;
+const FUNCTION_FILENAME: u32 = 4;
+const FUNCTION_START_LINE: u32 = 5;
+
+const WIRE_VARINT: u32 = 0;
+const WIRE_LENGTH_DELIMITED: u32 = 2;
+
+/// A protobuf message being encoded. Only the wire types profile.proto uses are supported.
+#[derive(Default)]
+struct Message(Vec<u8>);
+
+impl Message {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u32) {
+        self.varint(((field << 3) | wire_type).into());
+    }
+
+    /// Zero is the default and left out, as protobuf encoders do.
+    fn uint(&mut self, field: u32, value: u64) {
+        if value != 0 {
+            self.key(field, WIRE_VARINT);
+            self.varint(value);
+        }
+    }
+
+    fn bytes(&mut self, field: u32, bytes: &[u8]) {
+        self.key(field, WIRE_LENGTH_DELIMITED);
+        self.varint(bytes.len() as u64);
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn message(&mut self, field: u32, message: Message) {
+        self.bytes(field, &message.0);
+    }
+
+    fn packed(&mut self, field: u32, values: impl IntoIterator<Item = u64>) {
+        let mut packed = Message::default();
+        for value in values {
+            packed.varint(value);
+        }
+        self.bytes(field, &packed.0);
+    }
+}
+
+/// Deduplicates strings into the profile's string table, which must start with "".
+struct StringTable {
+    strings: Vec<String>,
+    indices: HashMap<String, u64>,
+}
+
+impl StringTable {
+    fn new() -> Self {
+        Self {
+            strings: vec![String::new()],
+            indices: HashMap::from([(String::new(), 0)]),
+        }
+    }
+
+    fn index(&mut self, string: &str) -> u64 {
+        if let Some(index) = self.indices.get(string) {
+            return *index;
+        }
+
+        let index = self.strings.len() as u64;
+        self.strings.push(string.to_string());
+        self.indices.insert(string.to_string(), index);
+        index
+    }
+}
+
+pub(crate) fn write(
+    path: &str,
+    byond: &ByondReflectionData,
+    call_tree: &CallTree,
+) -> Result<(), String> {
+    let profile = encode(byond, call_tree);
+
+    fs::write(path, gzip::encode(&profile))
+        .map_err(|error| format!("Unable to write {}: {}", path, error))
+}
+
+fn encode(byond: &ByondReflectionData, call_tree: &CallTree) -> Vec<u8> {
+    let mut strings = StringTable::new();
+    let mut profile = Message::default();
+
+    for (value_type, unit) in [("calls", "count"), ("self", "nanoseconds")] {
+        let mut sample_type = Message::default();
+        sample_type.uint(VALUE_TYPE_TYPE, strings.index(value_type));
+        sample_type.uint(VALUE_TYPE_UNIT, strings.index(unit));
+        profile.message(PROFILE_SAMPLE_TYPE, sample_type);
+    }
+
+    // IDs must be non-zero, so procdef n is function and location n + 1
+    let mut procdefs = Vec::new();
+    for (stack, node) in call_tree.nodes() {
+        let mut sample = Message::default();
+        sample.packed(
+            SAMPLE_LOCATION_ID,
+            stack.iter().rev().map(|procdef| *procdef as u64 + 1),
+        );
+        sample.packed(SAMPLE_VALUE, [node.calls, node.self_time.as_nanos() as u64]);
+        profile.message(PROFILE_SAMPLE, sample);
+
+        procdefs.extend_from_slice(&stack);
+    }
+    procdefs.sort_unstable();
+    procdefs.dedup();
+
+    for procdef in &procdefs {
+        let id = *procdef as u64 + 1;
+        let info = byond.proc_info(*procdef);
+        let name = strings.index(&byond.proc_name(*procdef));
+        let file = info
+            .file
+            .map_or(0, |file| strings.index(&file.to_string_lossy()));
+        let line = info.line.unwrap_or(0).into();
+
+        let mut function = Message::default();
+        function.uint(FUNCTION_ID, id);
+        function.uint(FUNCTION_NAME, name);
+        function.uint(FUNCTION_SYSTEM_NAME, name);
+        function.uint(FUNCTION_FILENAME, file);
+        function.uint(FUNCTION_START_LINE, line);
+        profile.message(PROFILE_FUNCTION, function);
+
+        let mut location_line = Message::default();
+        location_line.uint(LINE_FUNCTION_ID, id);
+        location_line.uint(LINE_LINE, line);
+
+        let mut location = Message::default();
+        location.uint(LOCATION_ID, id);
+        location.message(LOCATION_LINE, location_line);
+        profile.message(PROFILE_LOCATION, location);
+    }
+
+    for string in &strings.strings {
+        profile.bytes(PROFILE_STRING_TABLE, string.as_bytes());
+    }
+
+    if let Ok(started) = call_tree.started.duration_since(UNIX_EPOCH) {
+        profile.uint(PROFILE_TIME_NANOS, started.as_nanos() as u64);
+    }
+    if let Ok(duration) = call_tree.started.elapsed() {
+        profile.uint(PROFILE_DURATION_NANOS, duration.as_nanos() as u64);
+    }
+
+    profile.0
+}
@@ -0,0 +1,114 @@
+//! Sampling mode. Instead of a zone per call, a background thread copies every shadow callstack at a
+//! fixed rate and counts how often each stack is seen.
+//!
+//! Tracy's client API has no way to submit callstack samples for foreign code, so the samples reach
+//! Tracy as a summary of the hottest procs attached to each ServerTick zone. The full stacks can be
+//! written out in collapsed stack form with write_samples.
+
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::callstack;
+
+/// How many procs the per tick summary names.
+const SUMMARY_LENGTH: usize = 5;
+
+#[derive(Default)]
+struct Samples {
+    /// Every stack seen since the sampler started, as procdef indices outermost first
+    stacks: HashMap<Vec<usize>, u64>,
+    /// Innermost procs seen since the last server tick
+    tick_leaves: HashMap<usize, u64>,
+}
+
+pub(crate) struct Sampler {
+    samples: Arc<Mutex<Samples>>,
+}
+
+/// What the sampler saw during one server tick.
+pub(crate) struct TickSamples {
+    pub count: u64,
+    /// The most sampled innermost procs and their sample counts, highest first
+    pub hottest: Vec<(usize, u64)>,
+}
+
+impl Sampler {
+    /// Starts the sampling thread. It runs for the rest of the process, like the instance that owns it.
+    pub fn start(rate: u32) -> Result<Self, String> {
+        let samples = Arc::new(Mutex::new(Samples::default()));
+        let interval = Duration::from_secs(1) / rate.max(1);
+
+        let thread_samples = samples.clone();
+        thread::Builder::new()
+            .name("byond-tracy sampler".to_string())
+            .spawn(move || sample_loop(&thread_samples, interval))
+            .map_err(|error| format!("Unable to start the sampler thread: {}", error))?;
+
+        Ok(Self { samples })
+    }
+
+    /// Returns what was sampled since the previous call and starts counting again.
+    pub fn take_tick(&self) -> TickSamples {
+        let mut samples = self.lock();
+        let leaves = std::mem::take(&mut samples.tick_leaves);
+        drop(samples);
+
+        let count = leaves.values().sum();
+        let mut hottest = leaves.into_iter().collect::<Vec<_>>();
+        hottest.sort_unstable_by_key(|(_, count)| Reverse(*count));
+        hottest.truncate(SUMMARY_LENGTH);
+
+        TickSamples { count, hottest }
+    }
+
+    /// A copy of every stack sampled so far.
+    pub fn stacks(&self) -> HashMap<Vec<usize>, u64> {
+        self.lock().stacks.clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Samples> {
+        self.samples
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+fn sample_loop(samples: &Mutex<Samples>, interval: Duration) {
+    let mut next = Instant::now() + interval;
+    loop {
+        let now = Instant::now();
+        if next > now {
+            thread::sleep(next - now);
+            next += interval;
+        } else {
+            // Fell behind, most likely the OS timer is coarser than the rate. Don't try to catch up
+            next = now + interval;
+        }
+
+        let stacks = callstack::all_stacks()
+            .iter()
+            .map(|stack| stack.snapshot())
+            .filter(|stack| !stack.is_empty())
+            .collect::<Vec<_>>();
+        if stacks.is_empty() {
+            continue;
+        }
+
+        let mut samples = samples
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        for stack in stacks {
+            if let Some(leaf) = stack.last() {
+                *samples.tick_leaves.entry(leaf.procdef).or_default() += 1;
+            }
+
+            let procdefs = stack.iter().map(|entry| entry.procdef).collect();
+            *samples.stacks.entry(procdefs).or_default() += 1;
+        }
+    }
+}
@@ -0,0 +1,202 @@
+//! Chrome Trace Event JSON, viewable at ui.perfetto.dev or chrome://tracing without the Tracy viewer.
+//!
+//! The file uses the JSON array format with the closing bracket left off, which both viewers accept,
+//! so a trace cut short by a crash or a killed server still loads.
+
+use std::{
+    cell::RefCell,
+    fmt::Write as _,
+    fs::File,
+    io::{BufWriter, Write},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use super::{ProfilerSink, ZoneName, thread_id};
+
+/// One trace event, less the timestamp, process and thread every event carries.
+pub(super) enum ChromeEvent<'a> {
+    Begin(&'a str),
+    /// With the text attached to the zone, if any
+    End(Option<&'a str>),
+    /// A named secondary frame, or the main frame
+    Frame(Option<&'a str>),
+    Counter(&'a str, f64),
+    Instant(&'a str),
+}
+
+thread_local! {
+    // Text attached to each open zone, written out with its end event
+    static ZONE_TEXTS: RefCell<Vec<Option<String>>> = const { RefCell::new(Vec::new()) };
+}
+
+pub(crate) struct ChromeSink {
+    start: Instant,
+    process_id: u32,
+    /// Indexed by procdef
+    proc_names: Vec<String>,
+    /// None once a write has failed, the trace is abandoned rather than left with holes
+    writer: Mutex<Option<BufWriter<File>>>,
+}
+
+impl ChromeSink {
+    /// Creates the file up front so a bad path fails init. Zones for procs are unnamed until with_proc_names.
+    pub fn create(path: &str) -> Result<Self, String> {
+        let file =
+            File::create(path).map_err(|error| format!("Unable to create {}: {}", path, error))?;
+        let mut writer = BufWriter::new(file);
+        writer
+            .write_all(b"[\n")
+            .map_err(|error| format!("Unable to write {}: {}", path, error))?;
+
+        Ok(Self {
+            start: Instant::now(),
+            process_id: std::process::id(),
+            proc_names: Vec::new(),
+            writer: Mutex::new(Some(writer)),
+        })
+    }
+
+    pub fn with_proc_names(self, proc_names: Vec<String>) -> Self {
+        Self { proc_names, ..self }
+    }
+
+    fn name<'a>(&'a self, name: ZoneName<'a>) -> &'a str {
+        match name {
+            ZoneName::Proc(procdef) => self
+                .proc_names
+                .get(procdef)
+                .map_or("<unknown proc>", String::as_str),
+            ZoneName::ServerTick => "ServerTick",
+            ZoneName::SendMaps => "SendMaps",
+            ZoneName::Dm(name) => name,
+        }
+    }
+
+    fn write_event(&self, event: ChromeEvent) {
+        let line = format_event(event, self.start.elapsed(), self.process_id, thread_id());
+
+        self.with_writer(|writer| writer.write_all(line.as_bytes()));
+    }
+
+    fn with_writer(&self, action: impl FnOnce(&mut BufWriter<File>) -> std::io::Result<()>) {
+        let mut writer = self
+            .writer
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        if let Some(file) = writer.as_mut()
+            && action(file).is_err()
+        {
+            *writer = None;
+        }
+    }
+}
+
+impl ProfilerSink for ChromeSink {
+    fn zone_begin(&self, name: ZoneName, _color: u32) {
+        ZONE_TEXTS.with_borrow_mut(|texts| texts.push(None));
+
+        self.write_event(ChromeEvent::Begin(self.name(name)));
+    }
+
+    fn zone_text(&self, text: &str) {
+        ZONE_TEXTS.with_borrow_mut(|texts| {
+            if let Some(zone_text) = texts.last_mut() {
+                zone_text.get_or_insert_default().push_str(text);
+            }
+        });
+    }
+
+    fn zone_end(&self) {
+        let text = ZONE_TEXTS.with_borrow_mut(|texts| texts.pop()).flatten();
+
+        self.write_event(ChromeEvent::End(text.as_deref()));
+    }
+
+    fn frame_mark(&self, name: Option<&str>) {
+        self.write_event(ChromeEvent::Frame(name));
+
+        // Once per tick, so the file on disk is never more than a tick behind
+        if name.is_none() {
+            self.with_writer(|writer| writer.flush());
+        }
+    }
+
+    fn plot(&self, name: &str, value: f64) {
+        self.write_event(ChromeEvent::Counter(name, value));
+    }
+
+    /// Chrome has no per event colors.
+    fn message(&self, text: &str, _color: u32) {
+        self.write_event(ChromeEvent::Instant(text));
+    }
+}
+
+/// Formats one event as a line of the JSON array, comma included.
+pub(super) fn format_event(
+    event: ChromeEvent,
+    timestamp: Duration,
+    process_id: u32,
+    thread_id: u32,
+) -> String {
+    let mut line = String::new();
+    let _ = write!(
+        line,
+        "{{\"ts\":{:.3},\"pid\":{},\"tid\":{},",
+        timestamp.as_secs_f64() * 1_000_000.0,
+        process_id,
+        thread_id
+    );
+
+    match event {
+        ChromeEvent::Begin(name) => {
+            line.push_str("\"ph\":\"B\",\"name\":");
+            write_json_string(&mut line, name);
+        }
+        ChromeEvent::End(text) => {
+            line.push_str("\"ph\":\"E\"");
+            if let Some(text) = text {
+                line.push_str(",\"args\":{\"text\":");
+                write_json_string(&mut line, text);
+                line.push('}');
+            }
+        }
+        ChromeEvent::Frame(name) => {
+            line.push_str("\"ph\":\"i\",\"s\":\"g\",\"name\":");
+            write_json_string(&mut line, name.unwrap_or("Frame"));
+        }
+        // Counters are drawn as a track per name. JSON has no NaN or infinity, so those become 0
+        ChromeEvent::Counter(name, value) => {
+            line.push_str("\"ph\":\"C\",\"name\":");
+            write_json_string(&mut line, name);
+            let value = if value.is_finite() { value } else { 0.0 };
+            let _ = write!(line, ",\"args\":{{\"value\":{}}}", value);
+        }
+        ChromeEvent::Instant(name) => {
+            line.push_str("\"ph\":\"i\",\"s\":\"t\",\"name\":");
+            write_json_string(&mut line, name);
+        }
+    }
+
+    line.push_str("},\n");
+    line
+}
+
+fn write_json_string(out: &mut String, string: &str) {
+    out.push('"');
+    for character in string.chars() {
+        match character {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            character if character < ' ' => {
+                let _ = write!(out, "\\u{:04x}", character as u32);
+            }
+            character => out.push(character),
+        }
+    }
+    out.push('"');
+}
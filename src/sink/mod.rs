@@ -0,0 +1,150 @@
+//! Destinations for what the hooks observe. Every configured sink receives the same events, so Tracy
+//! and file outputs can run side by side.
+
+use std::sync::{
+    Arc,
+    atomic::{AtomicU32, Ordering},
+};
+
+mod chrome;
+mod recorder;
+mod tracy;
+
+pub(crate) use chrome::ChromeSink;
+pub(crate) use recorder::FlightRecorder;
+pub(crate) use tracy::TracySink;
+
+static NEXT_THREAD_ID: AtomicU32 = AtomicU32::new(1);
+
+thread_local! {
+    static THREAD_ID: u32 = NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed);
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum ZoneName<'a> {
+    /// A DM proc, by procdef index
+    Proc(usize),
+    ServerTick,
+    SendMaps,
+    /// Opened by DM through tracy_zone_begin
+    Dm(&'a str),
+}
+
+/// Receives events from the hooks. Zones nest per thread, zone_end always closes the innermost zone
+/// begun on the calling thread.
+pub(crate) trait ProfilerSink: Send + Sync {
+    /// A color of 0 leaves the zone with the sink's default color.
+    fn zone_begin(&self, name: ZoneName, color: u32);
+
+    /// Attaches text to the innermost zone on this thread.
+    fn zone_text(&self, text: &str);
+
+    fn zone_end(&self);
+
+    /// Marks the end of a frame. None is the main frame, one per server tick, anything else is a
+    /// secondary frame set named by DM.
+    fn frame_mark(&self, name: Option<&str>);
+
+    fn plot(&self, name: &str, value: f64);
+
+    /// A color of 0 leaves the message with the sink's default color.
+    fn message(&self, text: &str, color: u32);
+}
+
+/// Lets a sink be shared with code that needs more than the trait, like the flight recorder's dumps.
+impl<T: ProfilerSink + ?Sized> ProfilerSink for Arc<T> {
+    fn zone_begin(&self, name: ZoneName, color: u32) {
+        (**self).zone_begin(name, color)
+    }
+
+    fn zone_text(&self, text: &str) {
+        (**self).zone_text(text)
+    }
+
+    fn zone_end(&self) {
+        (**self).zone_end()
+    }
+
+    fn frame_mark(&self, name: Option<&str>) {
+        (**self).frame_mark(name)
+    }
+
+    fn plot(&self, name: &str, value: f64) {
+        (**self).plot(name, value)
+    }
+
+    fn message(&self, text: &str, color: u32) {
+        (**self).message(text, color)
+    }
+}
+
+pub(crate) struct Sinks(Vec<Box<dyn ProfilerSink>>);
+
+/// An open zone in every sink, ended when dropped.
+pub(crate) struct Zone<'a> {
+    sinks: &'a Sinks,
+}
+
+impl Sinks {
+    pub fn new(sinks: Vec<Box<dyn ProfilerSink>>) -> Self {
+        Self(sinks)
+    }
+
+    /// Begins a zone in every sink, ending it when the returned Zone is dropped.
+    pub fn zone(&self, name: ZoneName, color: u32) -> Zone<'_> {
+        self.zone_begin(name, color);
+
+        Zone { sinks: self }
+    }
+
+    /// Begins a zone the caller must end with zone_end on the same thread. Prefer zone where the
+    /// zone's extent is a Rust scope.
+    pub fn zone_begin(&self, name: ZoneName, color: u32) {
+        for sink in &self.0 {
+            sink.zone_begin(name, color);
+        }
+    }
+
+    pub fn zone_end(&self) {
+        for sink in self.0.iter().rev() {
+            sink.zone_end();
+        }
+    }
+
+    pub fn frame_mark(&self, name: Option<&str>) {
+        for sink in &self.0 {
+            sink.frame_mark(name);
+        }
+    }
+
+    pub fn plot(&self, name: &str, value: f64) {
+        for sink in &self.0 {
+            sink.plot(name, value);
+        }
+    }
+
+    pub fn message(&self, text: &str, color: u32) {
+        for sink in &self.0 {
+            sink.message(text, color);
+        }
+    }
+}
+
+impl Zone<'_> {
+    pub fn text(&self, text: &str) {
+        for sink in &self.sinks.0 {
+            sink.zone_text(text);
+        }
+    }
+}
+
+impl Drop for Zone<'_> {
+    fn drop(&mut self) {
+        self.sinks.zone_end();
+    }
+}
+
+/// A small number identifying the calling thread, for sinks with no thread ids of their own.
+fn thread_id() -> u32 {
+    THREAD_ID.with(|thread_id| *thread_id)
+}
@@ -0,0 +1,253 @@
+//! A flight recorder, keeping the most recent events in memory so a lag spike can be written out after
+//! it happened without leaving a full capture running.
+
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, VecDeque},
+    fs,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use super::{
+    ProfilerSink, ZoneName,
+    chrome::{ChromeEvent, format_event},
+    thread_id,
+};
+
+#[derive(Clone)]
+enum Label {
+    Proc(usize),
+    ServerTick,
+    SendMaps,
+    Dm(Box<str>),
+}
+
+#[derive(Clone)]
+enum EventKind {
+    Begin(Label),
+    Text(Box<str>),
+    End,
+    Frame(Option<Box<str>>),
+    Plot(Box<str>, f64),
+    Message(Box<str>),
+}
+
+#[derive(Clone)]
+struct Event {
+    /// Since the recorder was created. Taken under the lock, so events are in time order
+    time: Duration,
+    thread_id: u32,
+    kind: EventKind,
+}
+
+/// The events of a window, copied out so they can be written without holding up recording.
+pub(crate) struct Snapshot(Vec<Event>);
+
+pub(crate) struct FlightRecorder {
+    start: Instant,
+    process_id: u32,
+    capacity: usize,
+    /// Indexed by procdef
+    proc_names: Vec<String>,
+    events: Mutex<VecDeque<Event>>,
+}
+
+impl FlightRecorder {
+    /// Keeps up to capacity events, dropping the oldest past that.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            start: Instant::now(),
+            process_id: std::process::id(),
+            capacity,
+            proc_names: Vec::new(),
+            events: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn with_proc_names(self, proc_names: Vec<String>) -> Self {
+        Self { proc_names, ..self }
+    }
+
+    /// Writes the events of the last window, or everything held if None, as Chrome Trace Event JSON.
+    pub fn dump(&self, path: &str, window: Option<Duration>) -> Result<(), String> {
+        self.write(&self.snapshot(window), path)
+    }
+
+    /// Copies the events of the last window, or everything held if None.
+    pub fn snapshot(&self, window: Option<Duration>) -> Snapshot {
+        let events = self.lock();
+        let cutoff = window.map_or(Duration::ZERO, |window| {
+            self.start.elapsed().saturating_sub(window)
+        });
+        let first = events.partition_point(|event| event.time < cutoff);
+
+        Snapshot(events.range(first..).cloned().collect())
+    }
+
+    /// Writes a snapshot as Chrome Trace Event JSON. Zones that began before its window are left out,
+    /// and zones still open are written without an end.
+    pub fn write(&self, snapshot: &Snapshot, path: &str) -> Result<(), String> {
+        let mut json = String::from("[\n");
+        // Text of the zones open on each thread in what's been written so far
+        let mut open_zones: HashMap<u32, Vec<Option<String>>> = HashMap::new();
+
+        for event in &snapshot.0 {
+            let zones = open_zones.entry(event.thread_id).or_default();
+            let chrome_event = match &event.kind {
+                EventKind::Begin(label) => {
+                    zones.push(None);
+                    ChromeEvent::Begin(self.name(label))
+                }
+                EventKind::Text(text) => {
+                    if let Some(zone_text) = zones.last_mut() {
+                        zone_text.get_or_insert_default().push_str(text);
+                    }
+                    continue;
+                }
+                EventKind::End => match zones.pop() {
+                    Some(text) => {
+                        json.push_str(&format_event(
+                            ChromeEvent::End(text.as_deref()),
+                            event.time,
+                            self.process_id,
+                            event.thread_id,
+                        ));
+                        continue;
+                    }
+                    None => continue,
+                },
+                EventKind::Frame(name) => ChromeEvent::Frame(name.as_deref()),
+                EventKind::Plot(name, value) => ChromeEvent::Counter(name, *value),
+                EventKind::Message(text) => ChromeEvent::Instant(text),
+            };
+
+            json.push_str(&format_event(
+                chrome_event,
+                event.time,
+                self.process_id,
+                event.thread_id,
+            ));
+        }
+
+        if json.ends_with(",\n") {
+            json.truncate(json.len() - 2);
+        }
+        json.push_str("\n]\n");
+
+        fs::write(path, json).map_err(|error| format!("Unable to write {}: {}", path, error))
+    }
+
+    /// Self time of each proc zone recorded on the calling thread since the given instant, highest
+    /// first. Zones that began earlier or haven't ended yet aren't counted.
+    pub fn proc_self_times(&self, since: Instant) -> Vec<(usize, Duration)> {
+        let since = since.saturating_duration_since(self.start);
+        let thread_id = thread_id();
+
+        let mut self_times: HashMap<usize, Duration> = HashMap::new();
+        // Open zones as (procdef, begin time, time spent in children)
+        let mut zones: Vec<(Option<usize>, Duration, Duration)> = Vec::new();
+
+        let events = self.lock();
+        let first = events.partition_point(|event| event.time < since);
+        for event in events
+            .range(first..)
+            .filter(|event| event.thread_id == thread_id)
+        {
+            match &event.kind {
+                EventKind::Begin(label) => {
+                    let procdef = match label {
+                        Label::Proc(procdef) => Some(*procdef),
+                        _ => None,
+                    };
+                    zones.push((procdef, event.time, Duration::ZERO));
+                }
+                EventKind::End => {
+                    let Some((procdef, begin, child_time)) = zones.pop() else {
+                        continue;
+                    };
+                    let elapsed = event.time.saturating_sub(begin);
+                    if let Some(procdef) = procdef {
+                        *self_times.entry(procdef).or_default() +=
+                            elapsed.saturating_sub(child_time);
+                    }
+                    if let Some(parent) = zones.last_mut() {
+                        parent.2 += elapsed;
+                    }
+                }
+                _ => {}
+            }
+        }
+        drop(events);
+
+        let mut self_times: Vec<_> = self_times.into_iter().collect();
+        self_times.sort_unstable_by_key(|(procdef, self_time)| (Reverse(*self_time), *procdef));
+        self_times
+    }
+
+    fn name<'a>(&'a self, label: &'a Label) -> &'a str {
+        match label {
+            Label::Proc(procdef) => self
+                .proc_names
+                .get(*procdef)
+                .map_or("<unknown proc>", String::as_str),
+            Label::ServerTick => "ServerTick",
+            Label::SendMaps => "SendMaps",
+            Label::Dm(name) => name,
+        }
+    }
+
+    fn record(&self, kind: EventKind) {
+        let thread_id = thread_id();
+
+        let mut events = self.lock();
+        let event = Event {
+            time: self.start.elapsed(),
+            thread_id,
+            kind,
+        };
+        if events.len() >= self.capacity {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<Event>> {
+        self.events
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl ProfilerSink for FlightRecorder {
+    fn zone_begin(&self, name: ZoneName, _color: u32) {
+        let label = match name {
+            ZoneName::Proc(procdef) => Label::Proc(procdef),
+            ZoneName::ServerTick => Label::ServerTick,
+            ZoneName::SendMaps => Label::SendMaps,
+            ZoneName::Dm(name) => Label::Dm(name.into()),
+        };
+
+        self.record(EventKind::Begin(label));
+    }
+
+    fn zone_text(&self, text: &str) {
+        self.record(EventKind::Text(text.into()));
+    }
+
+    fn zone_end(&self) {
+        self.record(EventKind::End);
+    }
+
+    fn frame_mark(&self, name: Option<&str>) {
+        self.record(EventKind::Frame(name.map(Into::into)));
+    }
+
+    fn plot(&self, name: &str, value: f64) {
+        self.record(EventKind::Plot(name.into(), value));
+    }
+
+    fn message(&self, text: &str, _color: u32) {
+        self.record(EventKind::Message(text.into()));
+    }
+}
@@ -0,0 +1,133 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+};
+
+use tracy_client::{Client, FrameName, PlotName, Span, SpanLocation, internal::make_span_location};
+
+use super::{ProfilerSink, ZoneName};
+
+static PLOT_NAMES: Mutex<Option<HashMap<String, PlotName>>> = Mutex::new(None);
+
+static FRAME_NAMES: Mutex<Option<HashMap<String, FrameName>>> = Mutex::new(None);
+
+static SERVER_TICK_SOURCE_LOCATION: OnceLock<SpanLocation> = OnceLock::new();
+
+static SEND_MAPS_SOURCE_LOCATION: OnceLock<SpanLocation> = OnceLock::new();
+
+thread_local! {
+    // None stands in for procs without a source location, so zone_end stays balanced
+    static ZONES: RefCell<Vec<Option<Span>>> = const { RefCell::new(Vec::new()) };
+}
+
+pub(crate) struct TracySink {
+    client: Client,
+    /// Indexed by procdef
+    source_locations: &'static [Option<SpanLocation>],
+}
+
+impl TracySink {
+    /// Tracy refers to source locations for as long as it runs, so they are leaked.
+    pub fn new(client: Client, source_locations: Vec<Option<SpanLocation>>) -> Self {
+        Self {
+            client,
+            source_locations: source_locations.leak(),
+        }
+    }
+
+    /// None for DM zones, which have no source location of their own. Names are passed as the span name,
+    /// since make_span_location cuts the last three characters off the type name it expects first.
+    fn source_location(&self, name: ZoneName) -> Option<&'static SpanLocation> {
+        match name {
+            ZoneName::Proc(procdef) => self.source_locations.get(procdef)?.as_ref(),
+            ZoneName::Dm(_) => None,
+            ZoneName::ServerTick => Some(SERVER_TICK_SOURCE_LOCATION.get_or_init(|| {
+                make_span_location(
+                    "<?>",
+                    c"ServerTick".as_ptr().cast(),
+                    c"Unknown".as_ptr().cast(),
+                    1,
+                )
+            })),
+            ZoneName::SendMaps => Some(SEND_MAPS_SOURCE_LOCATION.get_or_init(|| {
+                make_span_location(
+                    "<?>",
+                    c"SendMaps".as_ptr().cast(),
+                    c"Unknown".as_ptr().cast(),
+                    2,
+                )
+            })),
+        }
+    }
+}
+
+impl ProfilerSink for TracySink {
+    fn zone_begin(&self, name: ZoneName, color: u32) {
+        let zone = match name {
+            ZoneName::Dm(name) => Some(self.client.clone().span_alloc(Some(name), "", "", 0, 0)),
+            name => self
+                .source_location(name)
+                .map(|source_location| self.client.clone().span(source_location, 0)),
+        };
+        if let Some(zone) = &zone
+            && color != 0
+        {
+            zone.emit_color(color);
+        }
+
+        ZONES.with_borrow_mut(|zones| zones.push(zone));
+    }
+
+    fn zone_text(&self, text: &str) {
+        ZONES.with_borrow(|zones| {
+            if let Some(Some(zone)) = zones.last() {
+                zone.emit_text(text);
+            }
+        });
+    }
+
+    fn zone_end(&self) {
+        let zone = ZONES.with_borrow_mut(|zones| zones.pop());
+        drop(zone);
+    }
+
+    fn frame_mark(&self, name: Option<&str>) {
+        match name {
+            Some(name) => {
+                self.client
+                    .secondary_frame_mark(interned(&FRAME_NAMES, name, FrameName::new_leak))
+            }
+            None => self.client.frame_mark(),
+        }
+    }
+
+    fn plot(&self, name: &str, value: f64) {
+        self.client
+            .plot(interned(&PLOT_NAMES, name, PlotName::new_leak), value);
+    }
+
+    fn message(&self, text: &str, color: u32) {
+        if color == 0 {
+            self.client.message(text, 0);
+        } else {
+            // Takes RGBA rather than the RGB zones do, and drops the alpha
+            self.client.color_message(text, color << 8, 0);
+        }
+    }
+}
+
+// Tracy identifies plots and frames by the address of their name, so each name is leaked exactly once
+fn interned<T: Copy>(
+    cache: &Mutex<Option<HashMap<String, T>>>,
+    name: &str,
+    create: fn(String) -> T,
+) -> T {
+    let mut cache = cache
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    *cache
+        .get_or_insert_with(HashMap::new)
+        .entry(name.to_string())
+        .or_insert_with(|| create(name.to_string()))
+}
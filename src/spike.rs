@@ -0,0 +1,153 @@
+//! Automatic lag spike captures. When a server tick runs past the configured threshold, the flight
+//! recorder's last few seconds are written out along with a summary of the procs that took the tick,
+//! so spikes nobody was watching for still leave something to look at.
+
+use std::{
+    fmt::Write as _,
+    fs,
+    path::PathBuf,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{Instance, config::Config};
+
+/// How many procs a spike summary names.
+const SUMMARY_LENGTH: usize = 10;
+
+const SPIKE_MESSAGE_COLOR: u32 = 0xFF8800;
+
+pub(crate) struct SpikeCapture {
+    threshold: Duration,
+    window: Duration,
+    cooldown: Duration,
+    directory: PathBuf,
+    last_capture: Mutex<Option<Instant>>,
+}
+
+impl SpikeCapture {
+    /// None if no threshold is configured. Creates the capture directory, so this belongs before hooking.
+    pub fn new(config: &Config) -> Result<Option<Self>, String> {
+        let Some(threshold) = config.spike_threshold() else {
+            return Ok(None);
+        };
+
+        fs::create_dir_all(&config.spike_directory)
+            .map_err(|error| format!("Unable to create {}: {}", config.spike_directory, error))?;
+
+        Ok(Some(Self {
+            threshold,
+            window: Duration::from_secs_f64(config.spike_window),
+            cooldown: Duration::from_secs_f64(config.spike_cooldown),
+            directory: config.spike_directory.clone().into(),
+            last_capture: Mutex::new(None),
+        }))
+    }
+
+    /// Called at the end of every server tick. sampled_procs is the sampler's summary of the tick, if
+    /// any, otherwise the summary comes from the proc zones the recorder saw since tick_start.
+    ///
+    /// Only the summary and a copy of the recorder's window are taken on the tick's thread, the files
+    /// are written from another so a spike isn't made longer by capturing it.
+    pub fn check_tick(
+        &self,
+        instance: &'static Instance,
+        tick_start: Instant,
+        tick_duration: Duration,
+        sampled_procs: &[String],
+    ) {
+        let Some(recorder) = &instance.recorder else {
+            return;
+        };
+        if tick_duration <= self.threshold || !self.start_cooldown() {
+            return;
+        }
+
+        let top_procs = if sampled_procs.is_empty() {
+            recorder
+                .proc_self_times(tick_start)
+                .into_iter()
+                .take(SUMMARY_LENGTH)
+                .map(|(procdef, self_time)| {
+                    format!(
+                        "{:.1} ms {}",
+                        self_time.as_secs_f64() * 1000.0,
+                        instance.byond.proc_name(procdef)
+                    )
+                })
+                .collect()
+        } else {
+            sampled_procs.to_vec()
+        };
+
+        let mut summary = format!(
+            "Lag spike: tick took {:.1} ms, over the {:.1} ms threshold",
+            tick_duration.as_secs_f64() * 1000.0,
+            self.threshold.as_secs_f64() * 1000.0
+        );
+        if top_procs.is_empty() {
+            summary.push_str("\nNo procs recorded, use zones or sampling mode to see them");
+        } else {
+            summary.push_str("\nTop procs:");
+            for line in &top_procs {
+                let _ = write!(summary, "\n  {}", line);
+            }
+        }
+
+        // Sent before writing so the capture includes it
+        instance.sinks.message(&summary, SPIKE_MESSAGE_COLOR);
+
+        let name = format!(
+            "spike-{}",
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis()
+        );
+        let trace_path = self.directory.join(format!("{}.json", name));
+        let summary_path = self.directory.join(format!("{}.txt", name));
+
+        let snapshot = recorder.snapshot(Some(self.window));
+        let recorder = recorder.clone();
+        let result = thread::Builder::new()
+            .name("byond-tracy spike".to_string())
+            .spawn(move || {
+                let result = recorder
+                    .write(&snapshot, &trace_path.to_string_lossy())
+                    .and_then(|()| {
+                        fs::write(&summary_path, summary + "\n").map_err(|error| {
+                            format!("Unable to write {}: {}", summary_path.display(), error)
+                        })
+                    });
+                if let Err(error) = result {
+                    report_write_error(instance, &error);
+                }
+            })
+            .map_err(|error| format!("Unable to start the writer thread: {}", error));
+        if let Err(error) = result {
+            report_write_error(instance, &error);
+        }
+    }
+
+    /// Returns false if the previous capture was too recent, otherwise restarts the cooldown.
+    fn start_cooldown(&self) -> bool {
+        let mut last_capture = self
+            .last_capture
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if last_capture.is_some_and(|last_capture| last_capture.elapsed() < self.cooldown) {
+            return false;
+        }
+
+        *last_capture = Some(Instant::now());
+        true
+    }
+}
+
+fn report_write_error(instance: &Instance, error: &str) {
+    instance.sinks.message(
+        &format!("Unable to write spike capture: {}", error),
+        SPIKE_MESSAGE_COLOR,
+    );
+}
@@ -0,0 +1,73 @@
+use std::{
+    cell::Cell,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+thread_local! {
+    static PROC_DEPTH: Cell<u32> = const { Cell::new(0) };
+}
+
+/// Counters accumulated by the hooks between two server ticks.
+#[derive(Default)]
+pub(crate) struct TickStats {
+    proc_calls: AtomicU32,
+    proc_nanos: AtomicU64,
+    send_maps_nanos: AtomicU64,
+}
+
+pub(crate) struct TickSample {
+    pub proc_calls: u32,
+    pub proc_time: Duration,
+    pub send_maps_time: Duration,
+}
+
+/// Held for the duration of a proc call. Only the outermost call on a thread is timed so nested
+/// procs aren't counted twice.
+pub(crate) struct ProcScope<'a> {
+    stats: &'a TickStats,
+    start: Option<Instant>,
+}
+
+impl TickStats {
+    pub fn proc_scope(&self) -> ProcScope<'_> {
+        self.proc_calls.fetch_add(1, Ordering::Relaxed);
+
+        let outermost = PROC_DEPTH.with(|depth| {
+            let current = depth.get();
+            depth.set(current + 1);
+            current == 0
+        });
+
+        ProcScope {
+            stats: self,
+            start: outermost.then(Instant::now),
+        }
+    }
+
+    pub fn record_send_maps(&self, elapsed: Duration) {
+        self.send_maps_nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Returns everything recorded since the previous call and starts counting again.
+    pub fn take(&self) -> TickSample {
+        TickSample {
+            proc_calls: self.proc_calls.swap(0, Ordering::Relaxed),
+            proc_time: Duration::from_nanos(self.proc_nanos.swap(0, Ordering::Relaxed)),
+            send_maps_time: Duration::from_nanos(self.send_maps_nanos.swap(0, Ordering::Relaxed)),
+        }
+    }
+}
+
+impl Drop for ProcScope<'_> {
+    fn drop(&mut self) {
+        PROC_DEPTH.with(|depth| depth.set(depth.get().saturating_sub(1)));
+
+        if let Some(start) = self.start {
+            self.stats
+                .proc_nanos
+                .fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+        }
+    }
+}
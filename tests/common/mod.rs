@@ -0,0 +1,250 @@
+//! Loads the fake libbyond.so from tests/fake-byond and byond-tracy into the test process. Both stay
+//! loaded until the process exits, since the hooks point into byond-tracy and it only initializes once.
+
+#![allow(dead_code)]
+
+pub mod pprof;
+pub mod tracy;
+
+use std::{
+    ffi::{CStr, CString, c_char, c_int, c_void},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard, OnceLock},
+};
+
+use libloading::os::unix::{Library, RTLD_GLOBAL, RTLD_NOW};
+
+/// Procdefs of the fake procs, see PROCS in tests/fake-byond/src/fake.rs.
+pub const FAKE_TICK: u32 = 0;
+pub const FAKE_WORK: u32 = 1;
+pub const FAKE_LEAF: u32 = 2;
+/// Calls fail, which raises a runtime error.
+pub const FAKE_CARELESS: u32 = 3;
+pub const FAKE_FAIL: u32 = 4;
+/// Calls inspect, which calls get_callstack.
+pub const FAKE_CURIOUS: u32 = 5;
+pub const FAKE_INSPECT: u32 = 6;
+
+pub const PROC_PATHS: [&str; 7] = [
+    "/proc/fake_tick",
+    "/datum/fake/proc/work",
+    "/datum/fake/proc/leaf",
+    "/datum/fake/proc/careless",
+    "/datum/fake/proc/fail",
+    "/datum/fake/proc/curious",
+    "/datum/fake/proc/inspect",
+];
+
+/// fake_tick calls work twice and leaf once, and work calls leaf.
+pub const EXEC_PROC_CALLS_PER_TICK: u32 = 6;
+
+/// How often each fake function's original ran.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FakeCalls {
+    pub exec_proc: u32,
+    pub server_tick: u32,
+    pub send_maps: u32,
+    pub runtime: u32,
+}
+
+/// CByondValue from byondapi.h, as the _ext exports take and return it.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ByondValue {
+    pub value_type: u8,
+    pub junk: [u8; 3],
+    /// A reference, or the bits of a number
+    pub data: u32,
+}
+
+pub const BYOND_NUMBER: u8 = 0x2A;
+
+pub struct Harness {
+    byond: Library,
+    tracy: Library,
+}
+
+static HARNESS: OnceLock<Mutex<Harness>> = OnceLock::new();
+
+/// Loads both libraries and runs init with config the first time it's called. Every later call shares
+/// that instance whatever config it passes, so a test binary should stick to one. The guard keeps tests
+/// running in parallel from interleaving their ticks.
+pub fn harness(config: &str) -> MutexGuard<'static, Harness> {
+    HARNESS
+        .get_or_init(|| Mutex::new(Harness::load(config)))
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// A path in the temporary directory unique to this test process.
+pub fn temp_path(name: &str) -> String {
+    std::env::temp_dir()
+        .join(format!("byond-tracy-{}-{}", std::process::id(), name))
+        .to_string_lossy()
+        .into_owned()
+}
+
+impl Harness {
+    fn load(config: &str) -> Self {
+        let harness = Self::open();
+        assert_eq!(harness.call("init", &[config]), "ok");
+        harness
+    }
+
+    /// Loads both libraries without running init, for tests that need the fake running first. Use
+    /// harness instead unless the test binary has nothing else sharing the instance.
+    pub fn open() -> Self {
+        let target_dir = target_dir();
+
+        // SAFETY: Loading the fake only fills in its tables. It must be global for byond-tracy to find
+        // it by name, as it finds the real libbyond.so in DreamDaemon
+        let byond = unsafe {
+            Library::open(Some(target_dir.join("libbyond.so")), RTLD_NOW | RTLD_GLOBAL)
+        }
+        .unwrap_or_else(|error| {
+            panic!(
+                "Unable to load the fake libbyond.so, build it with cargo build --workspace: {}",
+                error
+            )
+        });
+        // SAFETY: byond-tracy has no load time side effects
+        let tracy =
+            unsafe { Library::open(Some(target_dir.join("libbyond_tracy_rs.so")), RTLD_NOW) }
+                .unwrap_or_else(|error| panic!("Unable to load byond-tracy: {}", error));
+
+        Self { byond, tracy }
+    }
+
+    /// Calls one of byond-tracy's legacy exports, as call_ext()() would.
+    pub fn call(&self, name: &str, args: &[&str]) -> String {
+        let args: Vec<CString> = args
+            .iter()
+            .map(|arg| CString::new(*arg).expect("Arguments can't contain NUL"))
+            .collect();
+        let argv: Vec<*const c_char> = args.iter().map(|arg| arg.as_ptr()).collect();
+
+        // SAFETY: Every legacy export has this signature, and argv holds argc valid strings
+        unsafe {
+            let export = self
+                .tracy
+                .get::<unsafe extern "C" fn(c_int, *const *const c_char) -> *const c_char>(
+                    name.as_bytes(),
+                )
+                .unwrap_or_else(|error| panic!("Missing export {}: {}", name, error));
+            CStr::from_ptr(export(argv.len() as c_int, argv.as_ptr()))
+                .to_string_lossy()
+                .into_owned()
+        }
+    }
+
+    /// Calls one of byond-tracy's byondapi exports with no arguments, as call_ext()() would.
+    pub fn call_ext(&self, name: &str) -> ByondValue {
+        // SAFETY: Every byondapi export has this signature, and no arguments need no argv
+        unsafe {
+            let export = self
+                .tracy
+                .get::<unsafe extern "C" fn(u32, *const ByondValue) -> ByondValue>(name.as_bytes())
+                .unwrap_or_else(|error| panic!("Missing export {}: {}", name, error));
+            export(0, std::ptr::null())
+        }
+    }
+
+    /// Runs a server tick through the hooked entry point, returning the tick interval.
+    pub fn server_tick(&self) -> i32 {
+        // SAFETY: Declared as in fake.rs
+        unsafe { self.byond_function::<unsafe extern "C" fn() -> i32>(b"fake_byond_server_tick")() }
+    }
+
+    /// Runs a fake proc, and everything it calls, outside of a tick.
+    pub fn exec_proc(&self, procdef: u32) {
+        // SAFETY: Declared as in fake.rs
+        unsafe {
+            self.byond_function::<unsafe extern "C" fn(u32)>(b"fake_byond_exec_proc")(procdef)
+        }
+    }
+
+    /// Runs a fake proc outside of a tick and returns what get_callstack returned inside it, from the
+    /// last proc in its calls to ask. If resumed, the proc runs as if waking from a sleep.
+    pub fn callstack_inside(&self, procdef: u32, resumed: bool) -> String {
+        type LegacyExport = unsafe extern "C" fn(c_int, *const *const c_char) -> *const c_char;
+
+        // SAFETY: Declared as in fake.rs, and get_callstack is a legacy export
+        unsafe {
+            let get_callstack = *self
+                .tracy
+                .get::<LegacyExport>(b"get_callstack")
+                .unwrap_or_else(|error| panic!("Missing export get_callstack: {}", error));
+            self.byond_function::<unsafe extern "C" fn(LegacyExport)>(
+                b"fake_byond_set_get_callstack",
+            )(get_callstack);
+
+            let run: &[u8] = if resumed {
+                b"fake_byond_resume_proc"
+            } else {
+                b"fake_byond_exec_proc"
+            };
+            self.byond_function::<unsafe extern "C" fn(u32)>(run)(procdef);
+
+            let callstack = self.byond_function::<unsafe extern "C" fn() -> *const c_char>(
+                b"fake_byond_last_callstack",
+            )();
+            assert!(!callstack.is_null(), "Nothing called get_callstack");
+            CStr::from_ptr(callstack).to_string_lossy().into_owned()
+        }
+    }
+
+    /// Allocates through the hooked entry point, as BYOND does for its own objects.
+    pub fn malloc(&self, size: usize) -> *mut c_void {
+        // SAFETY: Declared as in fake.rs
+        unsafe {
+            self.byond_function::<unsafe extern "C" fn(usize) -> *mut c_void>(b"fake_byond_malloc")(
+                size,
+            )
+        }
+    }
+
+    /// SAFETY: pointer must be null or from the C heap, and not yet freed
+    pub unsafe fn free(&self, pointer: *mut c_void) {
+        // SAFETY: Declared as in fake.rs, and guaranteed by our caller
+        unsafe {
+            self.byond_function::<unsafe extern "C" fn(*mut c_void)>(b"fake_byond_free")(pointer)
+        }
+    }
+
+    /// Sets the lengths of the datum and list tables, which server ticks plot.
+    pub fn set_object_counts(&self, datums: usize, lists: usize) {
+        // SAFETY: Declared as in fake.rs
+        unsafe {
+            self.byond_function::<unsafe extern "C" fn(usize, usize)>(
+                b"fake_byond_set_object_counts",
+            )(datums, lists)
+        }
+    }
+
+    pub fn calls(&self) -> FakeCalls {
+        // SAFETY: Declared as in fake.rs
+        unsafe { self.byond_function::<unsafe extern "C" fn() -> FakeCalls>(b"fake_byond_calls")() }
+    }
+
+    /// SAFETY: T must match the export's declaration
+    unsafe fn byond_function<T: Copy>(&self, name: &[u8]) -> T {
+        // SAFETY: Guaranteed by our caller
+        unsafe {
+            *self
+                .byond
+                .get::<T>(name)
+                .expect("Missing fake libbyond.so export")
+        }
+    }
+}
+
+/// Test binaries live in target/<profile>/deps, next to where the libraries end up.
+fn target_dir() -> PathBuf {
+    let executable = std::env::current_exe().expect("Unable to find the test executable");
+    executable
+        .parent()
+        .and_then(Path::parent)
+        .expect("Test executable outside of a target directory")
+        .to_path_buf()
+}
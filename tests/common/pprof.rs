@@ -0,0 +1,138 @@
+//! Reads back the gzipped profile.proto write_pprof produces, as far as the tests look into it.
+//!
+//! Field numbers follow profile.proto, as src/pprof.rs writes them.
+
+use std::{fs, io::Read};
+
+use flate2::read::GzDecoder;
+
+pub struct Profile {
+    pub samples: Vec<Sample>,
+    pub location_ids: Vec<u64>,
+    pub functions: Vec<Function>,
+}
+
+pub struct Sample {
+    /// Innermost first
+    pub location_ids: Vec<u64>,
+    /// Calls, then self time in nanoseconds
+    pub values: Vec<u64>,
+}
+
+pub struct Function {
+    pub id: u64,
+    pub name: String,
+}
+
+enum Field<'a> {
+    Varint(u64),
+    Bytes(&'a [u8]),
+}
+
+pub fn read(path: &str) -> Profile {
+    let mut profile = Vec::new();
+    GzDecoder::new(fs::File::open(path).unwrap())
+        .read_to_end(&mut profile)
+        .unwrap();
+
+    let mut samples = Vec::new();
+    let mut location_ids = Vec::new();
+    // Names are string table indices until the table has been read
+    let mut functions = Vec::new();
+    let mut strings = Vec::new();
+    for (number, field) in fields(&profile) {
+        match (number, field) {
+            (2, Field::Bytes(sample)) => {
+                let mut location_ids = Vec::new();
+                let mut values = Vec::new();
+                for (number, field) in fields(sample) {
+                    match (number, field) {
+                        (1, Field::Bytes(packed)) => location_ids = packed_varints(packed),
+                        (2, Field::Bytes(packed)) => values = packed_varints(packed),
+                        _ => {}
+                    }
+                }
+                samples.push(Sample {
+                    location_ids,
+                    values,
+                });
+            }
+            (4, Field::Bytes(location)) => location_ids.push(varint_field(location, 1)),
+            (5, Field::Bytes(function)) => {
+                functions.push((varint_field(function, 1), varint_field(function, 2)))
+            }
+            (6, Field::Bytes(string)) => strings.push(String::from_utf8(string.to_vec()).unwrap()),
+            _ => {}
+        }
+    }
+
+    assert_eq!(
+        strings.first().map(String::as_str),
+        Some(""),
+        "The string table must start with \"\""
+    );
+    let functions = functions
+        .into_iter()
+        .map(|(id, name)| Function {
+            id,
+            name: strings[name as usize].clone(),
+        })
+        .collect();
+
+    Profile {
+        samples,
+        location_ids,
+        functions,
+    }
+}
+
+fn fields(mut bytes: &[u8]) -> Vec<(u32, Field<'_>)> {
+    let mut fields = Vec::new();
+    while !bytes.is_empty() {
+        let key = varint(&mut bytes);
+        let field = match key & 7 {
+            0 => Field::Varint(varint(&mut bytes)),
+            2 => {
+                let length = varint(&mut bytes) as usize;
+                let (field, rest) = bytes.split_at(length);
+                bytes = rest;
+                Field::Bytes(field)
+            }
+            wire_type => panic!("Unexpected wire type {}", wire_type),
+        };
+        fields.push(((key >> 3) as u32, field));
+    }
+    fields
+}
+
+/// A varint field of a message, 0 if it's left out as protobuf does for defaults.
+fn varint_field(message: &[u8], number: u32) -> u64 {
+    fields(message)
+        .into_iter()
+        .find_map(|field| match field {
+            (found, Field::Varint(value)) if found == number => Some(value),
+            _ => None,
+        })
+        .unwrap_or(0)
+}
+
+fn packed_varints(mut bytes: &[u8]) -> Vec<u64> {
+    let mut values = Vec::new();
+    while !bytes.is_empty() {
+        values.push(varint(&mut bytes));
+    }
+    values
+}
+
+fn varint(bytes: &mut &[u8]) -> u64 {
+    let mut value = 0;
+    for shift in (0..64).step_by(7) {
+        let (byte, rest) = bytes.split_first().expect("Truncated varint");
+        *bytes = rest;
+        value |= u64::from(byte & 0x7F) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+    }
+    panic!("Varint longer than 64 bits")
+}
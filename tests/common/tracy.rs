@@ -0,0 +1,671 @@
+//! Just enough of a Tracy server to check what byond-tracy sends: the handshake, the LZ4 stream, and
+//! the queries that resolve zone, frame and plot names. Every other item is skipped by its size.
+//!
+//! Layouts follow TracyProtocol.hpp and TracyQueue.hpp in the tracy-client-sys we build against.
+
+use std::{
+    collections::{HashMap, VecDeque, hash_map::Entry},
+    io::{Read, Write},
+    net::TcpStream,
+    path::PathBuf,
+    process::Command,
+    time::{Duration, Instant},
+};
+
+/// The version the layouts here follow. Tracy refuses servers speaking any other, and connecting
+/// checks it against TracyProtocol.hpp.
+const PROTOCOL_VERSION: u32 = 74;
+
+const HANDSHAKE_WELCOME: u8 = 1;
+
+/// How far back LZ4 matches reach, including into earlier frames.
+const LZ4_WINDOW: usize = 64 * 1024;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// ServerQuery
+const QUERY_STRING: u8 = 1;
+const QUERY_SOURCE_LOCATION: u8 = 3;
+const QUERY_PLOT_NAME: u8 = 4;
+const QUERY_FRAME_NAME: u8 = 5;
+
+// QueueType, the ones we look inside
+const ZONE_TEXT: u8 = 0;
+const MESSAGE: u8 = 2;
+const MESSAGE_COLOR: u8 = 3;
+const MESSAGE_CALLSTACK: u8 = 4;
+const MESSAGE_COLOR_CALLSTACK: u8 = 5;
+const ZONE_BEGIN_ALLOC_SRC_LOC: u8 = 7;
+const ZONE_BEGIN_ALLOC_SRC_LOC_CALLSTACK: u8 = 8;
+const ZONE_BEGIN: u8 = 15;
+const ZONE_BEGIN_CALLSTACK: u8 = 16;
+const ZONE_END: u8 = 17;
+const MEM_ALLOC: u8 = 25;
+const MEM_FREE: u8 = 27;
+const MEM_ALLOC_CALLSTACK: u8 = 29;
+const MEM_FREE_CALLSTACK: u8 = 31;
+const PLOT_DATA_DOUBLE: u8 = 47;
+const THREAD_CONTEXT: u8 = 61;
+const ZONE_COLOR: u8 = 67;
+const FRAME_MARK_MSG: u8 = 69;
+const SOURCE_LOCATION: u8 = 73;
+const SINGLE_STRING_DATA: u8 = 98;
+const SECOND_STRING_DATA: u8 = 99;
+const STRING_DATA: u8 = 102;
+const PLOT_NAME: u8 = 104;
+const SOURCE_LOCATION_PAYLOAD: u8 = 105;
+const FRAME_NAME: u8 = 108;
+const FRAME_IMAGE_DATA: u8 = 109;
+const SYMBOL_CODE: u8 = 112;
+const SOURCE_CODE: u8 = 113;
+const NUM_TYPES: u8 = 115;
+
+/// QueueDataSize up to StringData, type byte included. Items from StringData on are a pointer followed
+/// by a length prefixed payload instead.
+const ITEM_SIZES: [usize; STRING_DATA as usize] = [
+    1, 1, 9, 12, 9, 12, 9, 9, 9, 1, 1, 1, 13, 13, 10, 17, 17, 9, 17, 17, 13, 17, 17, 17, 5, 27, 27,
+    21, 21, 27, 27, 21, 21, 21, 21, 24, 24, 16, 16, 16, 24, 24, 16, 16, 16, 25, 21, 25, 23, 16, 12,
+    2, 10, 13, 1, 1, 1, 25, 13, 1, 1, 5, 26, 18, 1, 17, 5, 4, 9, 17, 17, 17, 13, 32, 22, 13, 17,
+    17, 20, 17, 20, 28, 17, 13, 25, 17, 17, 17, 17, 17, 17, 17, 16, 18, 1, 5, 1, 17, 1, 1, 9, 9,
+];
+
+/// A zone as the Tracy viewer would show it.
+#[derive(Debug)]
+pub struct Zone {
+    pub name: String,
+    /// 0xRRGGBB, 0 if uncoloured
+    pub color: u32,
+    /// Each zone_text call on its own line
+    pub text: String,
+    pub children: Vec<Zone>,
+}
+
+/// Everything that arrived between two markers.
+#[derive(Debug, Default)]
+pub struct Captured {
+    /// Outermost zones that ended, in the order they began
+    pub zones: Vec<Zone>,
+    /// None for the main frame
+    pub frames: Vec<Option<String>>,
+    /// Address and size of each block, in the order they were sent
+    pub allocations: Vec<(u64, u64)>,
+    /// Address of each block
+    pub frees: Vec<u64>,
+    /// Name and value of each plot point, in the order they were sent
+    pub plots: Vec<(String, f64)>,
+    /// Text and 0xRRGGBB color of each message but the marker, 0 if uncoloured
+    pub messages: Vec<(String, u32)>,
+}
+
+impl Zone {
+    /// The names of this zone and everything in it, one per line and indented by depth.
+    pub fn outline(&self) -> String {
+        let mut outline = String::new();
+        self.write_outline(&mut outline, 0);
+        outline
+    }
+
+    fn write_outline(&self, outline: &mut String, depth: usize) {
+        outline.push_str(&"  ".repeat(depth));
+        outline.push_str(&self.name);
+        outline.push('\n');
+        for child in &self.children {
+            child.write_outline(outline, depth + 1);
+        }
+    }
+
+    /// Searches depth first, starting with this zone.
+    pub fn find(&self, name: &str) -> Option<&Zone> {
+        if self.name == name {
+            return Some(self);
+        }
+        self.children.iter().find_map(|child| child.find(name))
+    }
+}
+
+/// A zone whose name may still be waiting on a query.
+struct OpenZone {
+    source_location: ZoneSource,
+    color: Option<u32>,
+    text: Vec<String>,
+    children: Vec<OpenZone>,
+}
+
+enum ZoneSource {
+    /// Address of a static source location, resolved by query
+    Static(u64),
+    /// Sent just before the zone, as DM zones are
+    Allocated { name: String, color: u32 },
+}
+
+struct SourceLocation {
+    name: u64,
+    function: u64,
+    color: u32,
+}
+
+pub struct Capture {
+    stream: TcpStream,
+    /// What's been decompressed, cut back to the LZ4 window between frames
+    history: Vec<u8>,
+    thread: u32,
+    /// Innermost last
+    open_zones: HashMap<u32, Vec<OpenZone>>,
+    zones: Vec<OpenZone>,
+    /// Name addresses, 0 for the main frame
+    frames: Vec<u64>,
+    allocations: Vec<(u64, u64)>,
+    frees: Vec<u64>,
+    /// Name addresses and values
+    plots: Vec<(u64, f64)>,
+    /// Text and color
+    messages: Vec<(String, u32)>,
+    /// The last SingleStringData, which belongs to the item after it
+    single_string: Option<String>,
+    /// The last SourceLocationPayload, which belongs to the zone after it
+    allocated_source: Option<ZoneSource>,
+    /// None until answered
+    source_locations: HashMap<u64, Option<SourceLocation>>,
+    /// Answers carry no address, they arrive in the order asked
+    pending_source_locations: VecDeque<u64>,
+    strings: HashMap<u64, Option<String>>,
+    frame_names: HashMap<u64, Option<String>>,
+    plot_names: HashMap<u64, Option<String>>,
+}
+
+impl Capture {
+    /// Connects as the Tracy viewer would. Tracy only serves the first one to connect.
+    pub fn connect(port: u16) -> Self {
+        let deadline = Instant::now() + TIMEOUT;
+        let mut stream = loop {
+            match TcpStream::connect(("127.0.0.1", port)) {
+                Ok(stream) => break stream,
+                Err(error) if Instant::now() > deadline => {
+                    panic!("Unable to connect to Tracy on port {}: {}", port, error)
+                }
+                Err(_) => std::thread::sleep(Duration::from_millis(50)),
+            }
+        };
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+
+        let welcome_message_size = read_protocol_header();
+        let mut handshake = b"TracyPrf".to_vec();
+        handshake.extend_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+        stream.write_all(&handshake).unwrap();
+
+        let mut status = [0];
+        stream.read_exact(&mut status).unwrap();
+        assert_eq!(
+            status[0], HANDSHAKE_WELCOME,
+            "Tracy refused the handshake for protocol version {}",
+            PROTOCOL_VERSION
+        );
+        // Sent uncompressed ahead of the stream, nothing in it is checked
+        stream
+            .read_exact(&mut vec![0; welcome_message_size])
+            .unwrap();
+
+        Self {
+            stream,
+            history: Vec::new(),
+            thread: 0,
+            open_zones: HashMap::new(),
+            zones: Vec::new(),
+            frames: Vec::new(),
+            allocations: Vec::new(),
+            frees: Vec::new(),
+            plots: Vec::new(),
+            messages: Vec::new(),
+            single_string: None,
+            allocated_source: None,
+            source_locations: HashMap::new(),
+            pending_source_locations: VecDeque::new(),
+            strings: HashMap::new(),
+            frame_names: HashMap::new(),
+            plot_names: HashMap::new(),
+        }
+    }
+
+    /// Reads until a message reading marker arrives and every name so far is resolved, then takes
+    /// what arrived since the last call.
+    pub fn read_until(&mut self, marker: &str) -> Captured {
+        while !self.messages.iter().any(|(text, _)| text == marker) || !self.resolved() {
+            self.read_frame();
+        }
+
+        let zones = std::mem::take(&mut self.zones);
+        let frames = std::mem::take(&mut self.frames);
+        let plots = std::mem::take(&mut self.plots);
+        let messages = std::mem::take(&mut self.messages);
+        Captured {
+            zones: zones.into_iter().map(|zone| self.finish(zone)).collect(),
+            frames: frames
+                .into_iter()
+                .map(|name| match name {
+                    0 => None,
+                    name => self.frame_names[&name].clone(),
+                })
+                .collect(),
+            allocations: std::mem::take(&mut self.allocations),
+            frees: std::mem::take(&mut self.frees),
+            plots: plots
+                .into_iter()
+                .map(|(name, value)| (self.plot_names[&name].clone().unwrap(), value))
+                .collect(),
+            messages: messages
+                .into_iter()
+                .filter(|(text, _)| text != marker)
+                .collect(),
+        }
+    }
+
+    fn resolved(&self) -> bool {
+        self.pending_source_locations.is_empty()
+            && self.strings.values().all(Option::is_some)
+            && self.frame_names.values().all(Option::is_some)
+            && self.plot_names.values().all(Option::is_some)
+    }
+
+    fn finish(&self, zone: OpenZone) -> Zone {
+        let (name, color) = match zone.source_location {
+            ZoneSource::Static(address) => {
+                let source_location = self.source_locations[&address].as_ref().unwrap();
+                (
+                    self.strings[&source_location.display_name()]
+                        .clone()
+                        .unwrap(),
+                    source_location.color,
+                )
+            }
+            ZoneSource::Allocated { name, color } => (name, color),
+        };
+
+        Zone {
+            name,
+            color: zone.color.unwrap_or(color),
+            text: zone.text.join("\n"),
+            children: zone
+                .children
+                .into_iter()
+                .map(|child| self.finish(child))
+                .collect(),
+        }
+    }
+
+    /// Reads one compressed frame and handles every item in it. Items never straddle frames.
+    fn read_frame(&mut self) {
+        let mut size = [0; 4];
+        self.stream
+            .read_exact(&mut size)
+            .expect("Tracy stopped sending");
+        let mut compressed = vec![0; u32::from_le_bytes(size) as usize];
+        self.stream.read_exact(&mut compressed).unwrap();
+
+        if self.history.len() > LZ4_WINDOW {
+            self.history.drain(..self.history.len() - LZ4_WINDOW);
+        }
+        let start = self.history.len();
+        lz4_decompress(&compressed, &mut self.history);
+        let frame = self.history[start..].to_vec();
+
+        let mut reader = Reader(&frame);
+        while !reader.0.is_empty() {
+            self.handle_item(&mut reader);
+        }
+    }
+
+    fn handle_item(&mut self, reader: &mut Reader) {
+        let item_type = reader.u8();
+        match item_type {
+            THREAD_CONTEXT => self.thread = reader.u32(),
+            ZONE_BEGIN | ZONE_BEGIN_CALLSTACK => {
+                reader.skip(8);
+                let address = reader.u64();
+                if let Entry::Vacant(entry) = self.source_locations.entry(address) {
+                    entry.insert(None);
+                    self.pending_source_locations.push_back(address);
+                    self.query(QUERY_SOURCE_LOCATION, address);
+                }
+                self.begin_zone(ZoneSource::Static(address));
+            }
+            ZONE_BEGIN_ALLOC_SRC_LOC | ZONE_BEGIN_ALLOC_SRC_LOC_CALLSTACK => {
+                reader.skip(8);
+                let source = self
+                    .allocated_source
+                    .take()
+                    .expect("Allocated zone without a source location");
+                self.begin_zone(source);
+            }
+            ZONE_END => {
+                reader.skip(8);
+                let zones = self.open_zones.entry(self.thread).or_default();
+                let zone = zones.pop().expect("Zone ended without beginning");
+                match zones.last_mut() {
+                    Some(parent) => parent.children.push(zone),
+                    None => self.zones.push(zone),
+                }
+            }
+            MEM_ALLOC | MEM_ALLOC_CALLSTACK => {
+                reader.skip(12);
+                let address = reader.u64();
+                let size = reader.bytes(6);
+                let size = u64::from_le_bytes([
+                    size[0], size[1], size[2], size[3], size[4], size[5], 0, 0,
+                ]);
+                self.allocations.push((address, size));
+            }
+            MEM_FREE | MEM_FREE_CALLSTACK => {
+                reader.skip(12);
+                self.frees.push(reader.u64());
+            }
+            PLOT_DATA_DOUBLE => {
+                let name = reader.u64();
+                reader.skip(8);
+                let value = f64::from_bits(reader.u64());
+                if let Entry::Vacant(entry) = self.plot_names.entry(name) {
+                    entry.insert(None);
+                    self.query(QUERY_PLOT_NAME, name);
+                }
+                self.plots.push((name, value));
+            }
+            ZONE_COLOR => {
+                let [blue, green, red] = [reader.u8(), reader.u8(), reader.u8()];
+                self.innermost_zone().color = Some(u32::from_be_bytes([0, red, green, blue]));
+            }
+            ZONE_TEXT => {
+                let text = self.single_string.take().unwrap();
+                self.innermost_zone().text.push(text);
+            }
+            MESSAGE | MESSAGE_CALLSTACK => {
+                reader.skip(8);
+                let text = self.single_string.take().unwrap();
+                self.messages.push((text, 0));
+            }
+            MESSAGE_COLOR | MESSAGE_COLOR_CALLSTACK => {
+                reader.skip(8);
+                let [blue, green, red] = [reader.u8(), reader.u8(), reader.u8()];
+                let text = self.single_string.take().unwrap();
+                self.messages
+                    .push((text, u32::from_be_bytes([0, red, green, blue])));
+            }
+            FRAME_MARK_MSG => {
+                reader.skip(8);
+                let name = reader.u64();
+                if name != 0
+                    && let Entry::Vacant(entry) = self.frame_names.entry(name)
+                {
+                    entry.insert(None);
+                    self.query(QUERY_FRAME_NAME, name);
+                }
+                self.frames.push(name);
+            }
+            SOURCE_LOCATION => {
+                let name = reader.u64();
+                let function = reader.u64();
+                reader.skip(12);
+                let [blue, green, red] = [reader.u8(), reader.u8(), reader.u8()];
+                let source_location = SourceLocation {
+                    name,
+                    function,
+                    color: u32::from_be_bytes([0, red, green, blue]),
+                };
+
+                let display_name = source_location.display_name();
+                if let Entry::Vacant(entry) = self.strings.entry(display_name) {
+                    entry.insert(None);
+                    self.query(QUERY_STRING, display_name);
+                }
+                let address = self
+                    .pending_source_locations
+                    .pop_front()
+                    .expect("Source location nobody asked for");
+                self.source_locations.insert(address, Some(source_location));
+            }
+            SINGLE_STRING_DATA | SECOND_STRING_DATA => {
+                let length = reader.u16() as usize;
+                let text = reader.string(length);
+                if item_type == SINGLE_STRING_DATA {
+                    self.single_string = Some(text);
+                }
+            }
+            SOURCE_LOCATION_PAYLOAD => {
+                reader.skip(8);
+                let length = reader.u16() as usize;
+                let mut payload = Reader(reader.bytes(length));
+                let color = payload.u32();
+                payload.skip(4);
+                let function = payload.c_string();
+                let _file = payload.c_string();
+                // Whatever follows the file is the name, without a terminator
+                let name = match payload.0 {
+                    [] => function,
+                    name => String::from_utf8_lossy(name).into_owned(),
+                };
+                self.allocated_source = Some(ZoneSource::Allocated { name, color });
+            }
+            STRING_DATA | FRAME_NAME | PLOT_NAME => {
+                let address = reader.u64();
+                let length = reader.u16() as usize;
+                let text = reader.string(length);
+                let names = match item_type {
+                    STRING_DATA => &mut self.strings,
+                    FRAME_NAME => &mut self.frame_names,
+                    _ => &mut self.plot_names,
+                };
+                names.insert(address, Some(text));
+            }
+            FRAME_IMAGE_DATA | SYMBOL_CODE | SOURCE_CODE => {
+                reader.skip(8);
+                let length = reader.u32() as usize;
+                reader.skip(length);
+            }
+            STRING_DATA..NUM_TYPES => {
+                reader.skip(8);
+                let length = reader.u16() as usize;
+                reader.skip(length);
+            }
+            _ if item_type < STRING_DATA => reader.skip(ITEM_SIZES[item_type as usize] - 1),
+            _ => panic!("Unknown item type {}", item_type),
+        }
+    }
+
+    fn begin_zone(&mut self, source_location: ZoneSource) {
+        self.open_zones
+            .entry(self.thread)
+            .or_default()
+            .push(OpenZone {
+                source_location,
+                color: None,
+                text: Vec::new(),
+                children: Vec::new(),
+            });
+    }
+
+    fn innermost_zone(&mut self) -> &mut OpenZone {
+        self.open_zones
+            .get_mut(&self.thread)
+            .and_then(|zones| zones.last_mut())
+            .expect("No zone open")
+    }
+
+    fn query(&mut self, query_type: u8, address: u64) {
+        let mut packet = vec![query_type];
+        packet.extend_from_slice(&address.to_le_bytes());
+        packet.extend_from_slice(&0u32.to_le_bytes());
+        self.stream.write_all(&packet).unwrap();
+    }
+}
+
+impl SourceLocation {
+    /// Tracy shows the function when there's no name.
+    fn display_name(&self) -> u64 {
+        match self.name {
+            0 => self.function,
+            name => name,
+        }
+    }
+}
+
+/// Checks the protocol version against TracyProtocol.hpp from the tracy-client-sys in Cargo.lock, and
+/// returns the size of the welcome message it declares.
+fn read_protocol_header() -> usize {
+    let header_path = tracy_client_sys_dir().join("tracy/common/TracyProtocol.hpp");
+    let header = std::fs::read_to_string(&header_path)
+        .unwrap_or_else(|error| panic!("Unable to read {}: {}", header_path.display(), error));
+
+    assert_eq!(
+        enum_value(&header, "ProtocolVersion"),
+        Some(PROTOCOL_VERSION as usize),
+        "{} doesn't match PROTOCOL_VERSION, check the layouts here against it",
+        header_path.display()
+    );
+
+    // WelcomeMessage is inside #pragma pack(1), so its size is the sum of its fields
+    let fields = header
+        .split_once("struct WelcomeMessage\n{")
+        .and_then(|(_, rest)| rest.split_once("};"))
+        .unwrap_or_else(|| panic!("No WelcomeMessage in {}", header_path.display()))
+        .0;
+    fields
+        .split(';')
+        .map(str::trim)
+        .filter(|field| !field.is_empty())
+        .map(|field| {
+            let (field_type, name) = field.split_once(' ').unwrap();
+            let size = match field_type {
+                "char" | "int8_t" | "uint8_t" => 1,
+                "int16_t" | "uint16_t" => 2,
+                "int32_t" | "uint32_t" | "float" => 4,
+                "int64_t" | "uint64_t" | "double" => 8,
+                _ => panic!("Unknown type in WelcomeMessage: {}", field),
+            };
+            let count = match name.split_once('[') {
+                Some((_, length)) => {
+                    let length = length.trim_end_matches(']');
+                    length
+                        .parse()
+                        .ok()
+                        .or_else(|| enum_value(&header, length))
+                        .unwrap_or_else(|| panic!("Unknown length in WelcomeMessage: {}", field))
+                }
+                None => 1,
+            };
+            size * count
+        })
+        .sum()
+}
+
+fn tracy_client_sys_dir() -> PathBuf {
+    let output = Command::new(env!("CARGO"))
+        .args(["metadata", "--format-version", "1", "--locked"])
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "cargo metadata failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    // Just enough JSON to find one manifest path, which cargo doesn't escape slashes in
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .split("\"manifest_path\":\"")
+        .skip(1)
+        .filter_map(|rest| rest.split_once('"'))
+        .map(|(manifest_path, _)| PathBuf::from(manifest_path))
+        .find(|manifest_path| {
+            manifest_path
+                .parent()
+                .and_then(|dir| dir.file_name())
+                .is_some_and(|dir| dir.to_string_lossy().starts_with("tracy-client-sys-"))
+        })
+        .and_then(|manifest_path| manifest_path.parent().map(PathBuf::from))
+        .expect("tracy-client-sys isn't in cargo metadata")
+}
+
+/// The value of a C++ enum constant, as in `enum : uint32_t { ProtocolVersion = 74 };`.
+fn enum_value(header: &str, name: &str) -> Option<usize> {
+    let (_, rest) = header.split_once(&format!(" {} = ", name))?;
+    let end = rest.find(|character: char| !character.is_ascii_digit())?;
+    rest[..end].parse().ok()
+}
+
+/// Decompresses one LZ4 block onto the end of output, whose earlier contents matches may refer to.
+fn lz4_decompress(input: &[u8], output: &mut Vec<u8>) {
+    let mut reader = Reader(input);
+    loop {
+        let token = reader.u8();
+
+        let literals = reader.lz4_length(token >> 4);
+        output.extend_from_slice(reader.bytes(literals));
+        // The last sequence is only literals
+        if reader.0.is_empty() {
+            return;
+        }
+
+        let offset = reader.u16() as usize;
+        let length = reader.lz4_length(token & 0xF) + 4;
+        // Byte by byte, since a match may overlap what it copies
+        let start = output.len() - offset;
+        for index in start..start + length {
+            output.push(output[index]);
+        }
+    }
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, length: usize) -> &'a [u8] {
+        let (bytes, rest) = self.0.split_at(length);
+        self.0 = rest;
+        bytes
+    }
+
+    fn skip(&mut self, length: usize) {
+        self.bytes(length);
+    }
+
+    fn u8(&mut self) -> u8 {
+        self.bytes(1)[0]
+    }
+
+    fn u16(&mut self) -> u16 {
+        u16::from_le_bytes(self.bytes(2).try_into().unwrap())
+    }
+
+    fn u32(&mut self) -> u32 {
+        u32::from_le_bytes(self.bytes(4).try_into().unwrap())
+    }
+
+    fn u64(&mut self) -> u64 {
+        u64::from_le_bytes(self.bytes(8).try_into().unwrap())
+    }
+
+    fn string(&mut self, length: usize) -> String {
+        String::from_utf8_lossy(self.bytes(length)).into_owned()
+    }
+
+    fn c_string(&mut self) -> String {
+        let length = self.0.iter().position(|byte| *byte == 0).unwrap();
+        let text = self.string(length);
+        self.skip(1);
+        text
+    }
+
+    /// A 4 bit length from a token, where 15 means more bytes follow until one isn't 255.
+    fn lz4_length(&mut self, nibble: u8) -> usize {
+        let mut length = nibble as usize;
+        if nibble == 0xF {
+            loop {
+                let byte = self.u8();
+                length += byte as usize;
+                if byte != 0xFF {
+                    break;
+                }
+            }
+        }
+        length
+    }
+}
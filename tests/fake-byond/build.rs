@@ -0,0 +1,16 @@
+fn main() {
+    if std::env::var("CARGO_CFG_TARGET_OS").as_deref() != Ok("linux")
+        || !matches!(
+            std::env::var("CARGO_CFG_TARGET_ARCH").as_deref(),
+            Ok("x86" | "x86_64")
+        )
+    {
+        return;
+    }
+
+    // byond-tracy finds the library by name with RTLD_NOLOAD, which matches the soname
+    println!("cargo:rustc-cdylib-link-arg=-Wl,-soname,libbyond.so");
+    // The offsets byond-tracy uses are relative to the library's base, so everything it reads lives
+    // in one section at a fixed address. Keep in sync with byond-tracy's OFFSETS_FAKE
+    println!("cargo:rustc-cdylib-link-arg=-Wl,--section-start=.fakebyond=0x01000000");
+}
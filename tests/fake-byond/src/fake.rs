@@ -0,0 +1,658 @@
+//! The fake runtime. Everything byond-tracy reads or hooks lives in the .fakebyond section, which
+//! build.rs places at 0x01000000:
+//!
+//! | Offset | Contents                |
+//! |--------|-------------------------|
+//! | 0x0000 | strings, strings_len    |
+//! | 0x0010 | miscs, miscs_len        |
+//! | 0x0020 | procdefs, procdefs_len  |
+//! | 0x0030 | datums_len, lists_len   |
+//! | 0x1000 | exec_proc               |
+//! | 0x1020 | server_tick             |
+//! | 0x1040 | send_maps               |
+//! | 0x1060 | malloc                  |
+//! | 0x1080 | free                    |
+//! | 0x10A0 | runtime                 |
+//!
+//! The functions start on their own page, since byond-tracy takes write access away from the pages it
+//! hooks once they are patched.
+//!
+//! Each function opens with exactly as many bytes of no-ops as byond-tracy's jmp takes, for it to
+//! relocate into its trampoline, then jumps to the Rust implementation, so they behave the same hooked
+//! or not.
+
+use std::{
+    arch::global_asm,
+    ffi::{CStr, CString, c_char, c_int, c_void},
+    ptr::null,
+    sync::{
+        Mutex,
+        atomic::{AtomicU32, Ordering},
+    },
+};
+#[cfg(target_arch = "x86")]
+use std::{arch::naked_asm, mem::MaybeUninit};
+
+/// Reported by GetByondBuild, must match byond-tracy's OFFSETS_FAKE.
+const FAKE_BYOND_BUILD: i32 = 1;
+
+/// The prologue byond-tracy overwrites, which OFFSETS_FAKE's signatures must match byte for byte.
+/// nop dword [eax + eax]
+#[cfg(target_arch = "x86")]
+macro_rules! prologue {
+    () => {
+        ".byte 0x0F, 0x1F, 0x44, 0x00, 0x00"
+    };
+}
+
+/// nop word [rax + rax], then nop dword [rax + rax]
+#[cfg(target_arch = "x86_64")]
+macro_rules! prologue {
+    () => {
+        ".byte 0x66, 0x0F, 0x1F, 0x84, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0F, 0x1F, 0x44, 0x00, 0x00"
+    };
+}
+
+/// Returned by server_tick, in place of BYOND's time until the next tick.
+const TICK_INTERVAL: i32 = 1;
+
+const FAKE_FILE: &CStr = c"code/fake.dm";
+
+const DBG_FILE_OPCODE: u32 = 0x84;
+
+const DBG_LINE_OPCODE: u32 = 0x85;
+
+struct FakeProc {
+    path: &'static CStr,
+    line: u32,
+    /// Procdefs this proc calls, in order, each time it runs
+    calls: &'static [u32],
+    /// Raised as a runtime error once its calls return
+    error: Option<&'static CStr>,
+    /// Calls the export set with fake_byond_set_get_callstack and keeps what it returns
+    gets_callstack: bool,
+}
+
+/// Indexed by procdef. server_tick runs procdef 0 once per tick, so a tick makes six exec_proc calls.
+/// Nothing in a tick calls the procs that runtime or get the callstack.
+const PROCS: [FakeProc; 7] = [
+    FakeProc {
+        path: c"/proc/fake_tick",
+        line: 1,
+        calls: &[1, 1, 2],
+        error: None,
+        gets_callstack: false,
+    },
+    FakeProc {
+        path: c"/datum/fake/proc/work",
+        line: 10,
+        calls: &[2],
+        error: None,
+        gets_callstack: false,
+    },
+    FakeProc {
+        path: c"/datum/fake/proc/leaf",
+        line: 20,
+        calls: &[],
+        error: None,
+        gets_callstack: false,
+    },
+    FakeProc {
+        path: c"/datum/fake/proc/careless",
+        line: 30,
+        calls: &[4],
+        error: None,
+        gets_callstack: false,
+    },
+    FakeProc {
+        path: c"/datum/fake/proc/fail",
+        line: 40,
+        calls: &[],
+        error: Some(c"Division by zero"),
+        gets_callstack: false,
+    },
+    FakeProc {
+        path: c"/datum/fake/proc/curious",
+        line: 50,
+        calls: &[6],
+        error: None,
+        gets_callstack: false,
+    },
+    FakeProc {
+        path: c"/datum/fake/proc/inspect",
+        line: 60,
+        calls: &[],
+        error: None,
+        gets_callstack: true,
+    },
+];
+
+global_asm!(
+    ".pushsection .fakebyond,\"awx\",@progbits",
+    ".globl byond_tables",
+    ".hidden byond_tables",
+    "byond_tables:",
+    ".zero 0x40",
+    ".org 0x1000",
+    ".globl byond_exec_proc",
+    ".hidden byond_exec_proc",
+    "byond_exec_proc:",
+    prologue!(),
+    "jmp {exec_proc}",
+    ".org 0x1020",
+    ".globl byond_server_tick",
+    ".hidden byond_server_tick",
+    "byond_server_tick:",
+    prologue!(),
+    "jmp {server_tick}",
+    ".org 0x1040",
+    ".globl byond_send_maps",
+    ".hidden byond_send_maps",
+    "byond_send_maps:",
+    prologue!(),
+    "jmp {send_maps}",
+    ".org 0x1060",
+    ".globl byond_malloc",
+    ".hidden byond_malloc",
+    "byond_malloc:",
+    prologue!(),
+    "jmp {malloc}",
+    ".org 0x1080",
+    ".globl byond_free",
+    ".hidden byond_free",
+    "byond_free:",
+    prologue!(),
+    "jmp {free}",
+    ".org 0x10A0",
+    ".globl byond_runtime",
+    ".hidden byond_runtime",
+    "byond_runtime:",
+    prologue!(),
+    "jmp {runtime}",
+    ".popsection",
+    exec_proc = sym exec_proc,
+    server_tick = sym server_tick,
+    send_maps = sym send_maps,
+    malloc = sym malloc,
+    free = sym free,
+    runtime = sym runtime,
+);
+
+// The layouts below mirror byond-tracy's, which reads every field of them
+
+#[repr(C)]
+struct Tables {
+    strings: Table<*const DreamString>,
+    miscs: Table<*const Misc>,
+    procdefs: Table<ProcDefinition>,
+    /// Only the lengths of these are read, so there's nothing behind them
+    datums_len: usize,
+    lists_len: usize,
+}
+
+/// Each table starts 16 bytes after the last.
+#[repr(C, align(16))]
+struct Table<T> {
+    entries: *const T,
+    len: usize,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+#[allow(dead_code)]
+struct DreamObject {
+    object_type: u32,
+    value: u32,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct DreamString {
+    data: *const c_char,
+    id: u32,
+    left: *const DreamString,
+    right: *const DreamString,
+    refcount: u32,
+    unknown_0: u32,
+    length: u32,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct ProcDefinition {
+    path: u32,
+    name: u32,
+    desc: u32,
+    category: u32,
+    flags: u32,
+    _unknown0: u32,
+    bytecode: u32,
+    locals: u32,
+    parameters: u32,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct Bytecode {
+    length: u16,
+    unknown_0: u32,
+    bytecode: *const u32,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct Misc {
+    bytecode: Bytecode,
+    locals: Bytecode,
+    params: Bytecode,
+}
+
+#[repr(C)]
+#[allow(dead_code)]
+struct Proc {
+    procdef: u32,
+    flags: u8,
+    supers: u8,
+    unused: u16,
+    usr: DreamObject,
+    src: DreamObject,
+    context: *const c_void,
+    sequence: u32,
+    callback: usize,
+    callback_arg: u32,
+    argc: u32,
+    argv: *const DreamObject,
+    unknown_0: u32,
+}
+
+/// How often each function's original ran, hooked or not.
+#[repr(C)]
+pub struct FakeCalls {
+    pub exec_proc: u32,
+    pub server_tick: u32,
+    pub send_maps: u32,
+    pub runtime: u32,
+}
+
+static EXEC_PROC_CALLS: AtomicU32 = AtomicU32::new(0);
+static SERVER_TICK_CALLS: AtomicU32 = AtomicU32::new(0);
+static SEND_MAPS_CALLS: AtomicU32 = AtomicU32::new(0);
+static RUNTIME_CALLS: AtomicU32 = AtomicU32::new(0);
+
+/// The signature of byond-tracy's legacy exports, as call_ext()() calls them.
+type LegacyExport = unsafe extern "C" fn(c_int, *const *const c_char) -> *const c_char;
+
+static GET_CALLSTACK: Mutex<Option<LegacyExport>> = Mutex::new(None);
+static LAST_CALLSTACK: Mutex<Option<CString>> = Mutex::new(None);
+
+unsafe extern "C" {
+    static mut byond_tables: Tables;
+}
+
+// The entry points in .fakebyond, which is what byond-tracy hooks
+
+// regparm(3) on x86, so only ever called through call_exec_proc
+#[cfg(target_arch = "x86")]
+unsafe extern "C" {
+    fn byond_exec_proc();
+}
+
+#[cfg(target_arch = "x86_64")]
+unsafe extern "C" {
+    fn byond_exec_proc(proc: *const Proc) -> DreamObject;
+}
+
+unsafe extern "C" {
+    fn byond_server_tick() -> i32;
+    fn byond_send_maps();
+    fn byond_malloc(size: usize) -> *mut c_void;
+    fn byond_free(pointer: *mut c_void);
+    fn byond_runtime(error: *const c_char);
+}
+
+unsafe extern "C" {
+    #[link_name = "malloc"]
+    fn libc_malloc(size: usize) -> *mut c_void;
+    #[link_name = "free"]
+    fn libc_free(pointer: *mut c_void);
+}
+
+// Fills in the tables as soon as the library is loaded, like BYOND has them before any DM runs
+#[used]
+#[unsafe(link_section = ".init_array")]
+static INSTALL: extern "C" fn() = install;
+
+extern "C" fn install() {
+    let mut strings = vec![leak(DreamString::new(c"", 0))];
+    let file = intern(&mut strings, FAKE_FILE);
+
+    let mut miscs = Vec::new();
+    let mut procdefs = Vec::new();
+    for fake_proc in &PROCS {
+        let bytecode = Vec::leak(vec![DBG_FILE_OPCODE, file, DBG_LINE_OPCODE, fake_proc.line]);
+        let misc = miscs.len() as u32;
+        miscs.push(leak(Misc {
+            bytecode: Bytecode {
+                length: bytecode.len() as u16,
+                unknown_0: 0,
+                bytecode: bytecode.as_ptr(),
+            },
+            locals: Bytecode::EMPTY,
+            params: Bytecode::EMPTY,
+        }));
+
+        procdefs.push(ProcDefinition {
+            path: intern(&mut strings, fake_proc.path),
+            name: 0,
+            desc: 0,
+            category: 0,
+            flags: 0,
+            _unknown0: 0,
+            bytecode: misc,
+            locals: 0,
+            parameters: 0,
+        });
+    }
+
+    // SAFETY: Nothing else runs until the library's constructors are done
+    unsafe {
+        let tables = &raw mut byond_tables;
+        (*tables).strings = Table::leak(strings);
+        (*tables).miscs = Table::leak(miscs);
+        (*tables).procdefs = Table::leak(procdefs);
+    }
+}
+
+fn intern(strings: &mut Vec<*const DreamString>, text: &'static CStr) -> u32 {
+    let id = strings.len() as u32;
+    strings.push(leak(DreamString::new(text, id)));
+    id
+}
+
+fn leak<T>(value: T) -> *const T {
+    Box::into_raw(Box::new(value))
+}
+
+impl<T> Table<T> {
+    fn leak(entries: Vec<T>) -> Self {
+        Self {
+            len: entries.len(),
+            entries: Vec::leak(entries).as_ptr(),
+        }
+    }
+}
+
+impl DreamString {
+    fn new(text: &'static CStr, id: u32) -> Self {
+        Self {
+            data: text.as_ptr(),
+            id,
+            left: null(),
+            right: null(),
+            refcount: 1,
+            unknown_0: 0,
+            length: text.count_bytes() as u32,
+        }
+    }
+}
+
+impl DreamObject {
+    const NULL: Self = Self {
+        object_type: 0,
+        value: 0,
+    };
+}
+
+impl Bytecode {
+    const EMPTY: Self = Self {
+        length: 0,
+        unknown_0: 0,
+        bytecode: null(),
+    };
+}
+
+impl Proc {
+    fn new(procdef: u32) -> Self {
+        Self {
+            procdef,
+            flags: 0,
+            supers: 0,
+            unused: 0,
+            usr: DreamObject::NULL,
+            src: DreamObject::NULL,
+            context: null(),
+            sequence: 0,
+            callback: 0,
+            callback_arg: 0,
+            argc: 0,
+            argv: null(),
+            unknown_0: 0,
+        }
+    }
+}
+
+/// Runs a proc through the public entry point, so the call is seen by any hook.
+fn call_proc(procdef: u32) -> DreamObject {
+    let proc = Proc::new(procdef);
+    // SAFETY: proc is a valid Proc for the duration of the call
+    unsafe { call_exec_proc(&proc) }
+}
+
+/// Runs a proc as the scheduler does when it wakes from a sleep, with the context it slept in.
+fn resume_proc(procdef: u32) -> DreamObject {
+    // Only ever compared against null, so any address stands in for the context
+    let context = 1u8;
+    let proc = Proc {
+        context: (&raw const context).cast(),
+        ..Proc::new(procdef)
+    };
+    // SAFETY: proc is a valid Proc for the duration of the call
+    unsafe { call_exec_proc(&proc) }
+}
+
+/// Calls byond_exec_proc the way BYOND's Linux build does, with GCC's regparm(3).
+///
+/// SAFETY: proc must be valid
+#[cfg(target_arch = "x86")]
+unsafe fn call_exec_proc(proc: *const Proc) -> DreamObject {
+    let mut result = MaybeUninit::uninit();
+    // SAFETY: Guaranteed by our caller, and exec_proc writes the result
+    unsafe {
+        call_regparm_exec_proc(result.as_mut_ptr(), proc);
+        result.assume_init()
+    }
+}
+
+/// exec_proc returns a DreamObject, so the pointer to write it to goes in eax and the proc in edx.
+#[cfg(target_arch = "x86")]
+#[unsafe(naked)]
+unsafe extern "C" fn call_regparm_exec_proc(result: *mut DreamObject, proc: *const Proc) {
+    naked_asm!(
+        "mov eax, [esp + 4]",
+        "mov edx, [esp + 8]",
+        // Keep the stack 16 byte aligned for the call
+        "sub esp, 12",
+        "call {exec_proc}",
+        "add esp, 12",
+        "ret",
+        exec_proc = sym byond_exec_proc,
+    )
+}
+
+/// SAFETY: proc must be valid
+#[cfg(target_arch = "x86_64")]
+unsafe fn call_exec_proc(proc: *const Proc) -> DreamObject {
+    // SAFETY: Guaranteed by our caller
+    unsafe { byond_exec_proc(proc) }
+}
+
+/// exec_proc's regparm(3) side, which hands the result pointer back in eax.
+#[cfg(target_arch = "x86")]
+#[unsafe(naked)]
+unsafe extern "C" fn exec_proc() {
+    naked_asm!(
+        // Passing eax, edx and ecx on the stack also keeps it 16 byte aligned for the call
+        "push ecx",
+        "push edx",
+        "push eax",
+        "call {exec_proc}",
+        "pop eax",
+        "add esp, 8",
+        "ret",
+        exec_proc = sym exec_proc_regparm,
+    )
+}
+
+#[cfg(target_arch = "x86")]
+unsafe extern "C" fn exec_proc_regparm(result: *mut DreamObject, proc: *const Proc) {
+    // SAFETY: exec_proc's caller passes a valid Proc and a pointer to write the result to
+    unsafe { result.write(run_proc(proc)) }
+}
+
+#[cfg(target_arch = "x86_64")]
+unsafe extern "C" fn exec_proc(proc: *const Proc) -> DreamObject {
+    // SAFETY: Forwarded from our caller
+    unsafe { run_proc(proc) }
+}
+
+/// SAFETY: proc must be valid
+unsafe fn run_proc(proc: *const Proc) -> DreamObject {
+    EXEC_PROC_CALLS.fetch_add(1, Ordering::Relaxed);
+
+    // SAFETY: Guaranteed by our caller
+    let procdef = unsafe { (*proc).procdef };
+    if let Some(fake_proc) = PROCS.get(procdef as usize) {
+        for callee in fake_proc.calls {
+            call_proc(*callee);
+        }
+        if fake_proc.gets_callstack {
+            get_callstack();
+        }
+        if let Some(error) = fake_proc.error {
+            // SAFETY: error is a valid C string
+            unsafe { byond_runtime(error.as_ptr()) };
+        }
+    }
+
+    DreamObject::NULL
+}
+
+/// Calls get_callstack as DM's call_ext()() would, from the proc running it.
+fn get_callstack() {
+    let get_callstack = GET_CALLSTACK
+        .lock()
+        .unwrap()
+        .expect("fake_byond_set_get_callstack wasn't called");
+    // SAFETY: Legacy exports take no arguments as argc 0, and return a string valid until the next call
+    let callstack = unsafe { CStr::from_ptr(get_callstack(0, null())) };
+    *LAST_CALLSTACK.lock().unwrap() = Some(callstack.to_owned());
+}
+
+unsafe extern "C" fn server_tick() -> i32 {
+    SERVER_TICK_CALLS.fetch_add(1, Ordering::Relaxed);
+
+    call_proc(0);
+    // SAFETY: send_maps takes no arguments
+    unsafe { byond_send_maps() };
+
+    TICK_INTERVAL
+}
+
+unsafe extern "C" fn send_maps() {
+    SEND_MAPS_CALLS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// BYOND's wrapper around the C heap.
+unsafe extern "C" fn malloc(size: usize) -> *mut c_void {
+    // SAFETY: Any size may be asked for
+    unsafe { libc_malloc(size) }
+}
+
+/// SAFETY: pointer must be null or from malloc, and not yet freed
+unsafe extern "C" fn free(pointer: *mut c_void) {
+    // SAFETY: Guaranteed by our caller
+    unsafe { libc_free(pointer) }
+}
+
+/// Reports a runtime error in the running proc. BYOND's also prints it to world.log.
+unsafe extern "C" fn runtime(_error: *const c_char) {
+    RUNTIME_CALLS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// ByondLib::GetByondBuild, a member function that ignores this. With no other arguments the calling
+/// conventions agree.
+#[unsafe(export_name = "_ZN8ByondLib13GetByondBuildEv")]
+pub extern "C" fn get_byond_build() -> i32 {
+    FAKE_BYOND_BUILD
+}
+
+/// Runs one server tick through the public entry point, as BYOND's main loop would.
+#[unsafe(no_mangle)]
+pub extern "C" fn fake_byond_server_tick() -> i32 {
+    // SAFETY: server_tick takes no arguments
+    unsafe { byond_server_tick() }
+}
+
+/// Runs a proc outside of any tick, like a proc called by a timer or a client.
+#[unsafe(no_mangle)]
+pub extern "C" fn fake_byond_exec_proc(procdef: u32) {
+    call_proc(procdef);
+}
+
+/// Runs a proc that resumed from a sleep, outside of any tick.
+#[unsafe(no_mangle)]
+pub extern "C" fn fake_byond_resume_proc(procdef: u32) {
+    resume_proc(procdef);
+}
+
+/// Sets byond-tracy's get_callstack export, for the procs that call it.
+#[unsafe(no_mangle)]
+pub extern "C" fn fake_byond_set_get_callstack(get_callstack: LegacyExport) {
+    *GET_CALLSTACK.lock().unwrap() = Some(get_callstack);
+}
+
+/// What get_callstack returned the last time a proc called it, or null if none has. Valid until a proc
+/// calls it again.
+#[unsafe(no_mangle)]
+pub extern "C" fn fake_byond_last_callstack() -> *const c_char {
+    LAST_CALLSTACK
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map_or(null(), |callstack| callstack.as_ptr())
+}
+
+/// Allocates through the public entry point, as BYOND does for its own objects.
+#[unsafe(no_mangle)]
+pub extern "C" fn fake_byond_malloc(size: usize) -> *mut c_void {
+    // SAFETY: Any size may be asked for
+    unsafe { byond_malloc(size) }
+}
+
+/// SAFETY: pointer must be null or from the C heap, and not yet freed
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn fake_byond_free(pointer: *mut c_void) {
+    // SAFETY: Guaranteed by our caller
+    unsafe { byond_free(pointer) }
+}
+
+/// Sets the lengths of the datum and list tables, which BYOND changes as objects come and go.
+#[unsafe(no_mangle)]
+pub extern "C" fn fake_byond_set_object_counts(datums: usize, lists: usize) {
+    // SAFETY: byond-tracy only reads these, from the thread running ticks
+    unsafe {
+        let tables = &raw mut byond_tables;
+        (&raw mut (*tables).datums_len).write_volatile(datums);
+        (&raw mut (*tables).lists_len).write_volatile(lists);
+    }
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn fake_byond_calls() -> FakeCalls {
+    FakeCalls {
+        exec_proc: EXEC_PROC_CALLS.load(Ordering::Relaxed),
+        server_tick: SERVER_TICK_CALLS.load(Ordering::Relaxed),
+        send_maps: SEND_MAPS_CALLS.load(Ordering::Relaxed),
+        runtime: RUNTIME_CALLS.load(Ordering::Relaxed),
+    }
+}
@@ -0,0 +1,7 @@
+//! A stand-in for BYOND's libbyond.so, just enough of it for byond-tracy's init to find its tables and
+//! hook its functions. The matching offsets are behind byond-tracy's fake-byond feature.
+//!
+//! Only x86 and x86_64 Linux are supported, other targets build an empty library.
+
+#[cfg(all(target_os = "linux", any(target_arch = "x86", target_arch = "x86_64")))]
+mod fake;
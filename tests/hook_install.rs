@@ -0,0 +1,76 @@
+//! Installs the hooks while other threads keep calling the function being patched, as BYOND's own
+//! threads do while init runs. Any thread that ran a half written patch would crash or skip the
+//! original.
+//!
+//! init only runs once per process, so this is its own test binary. It needs the same setup as
+//! hooks.rs, building the libraries before testing with the fake-byond feature.
+
+#![cfg(all(
+    feature = "fake-byond",
+    target_os = "linux",
+    any(target_arch = "x86", target_arch = "x86_64")
+))]
+
+mod common;
+
+use std::{
+    fs,
+    sync::atomic::{AtomicBool, AtomicU32, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use common::{FAKE_LEAF, Harness, PROC_PATHS, temp_path};
+
+const SPINNING_THREADS: usize = 4;
+
+/// How many calls each side of init needs to see, so the patch lands in the middle of a busy stretch.
+const CALLS_AROUND_INIT: u32 = 10_000;
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+#[test]
+fn exec_proc_keeps_running_while_its_hook_installs() {
+    let harness = Harness::open();
+    let stop = AtomicBool::new(false);
+    let calls = AtomicU32::new(0);
+    let before = harness.calls().exec_proc;
+
+    thread::scope(|scope| {
+        for _ in 0..SPINNING_THREADS {
+            scope.spawn(|| {
+                while !stop.load(Ordering::Relaxed) {
+                    harness.exec_proc(FAKE_LEAF);
+                    calls.fetch_add(1, Ordering::Relaxed);
+                }
+            });
+        }
+
+        let wait_for_calls = |target: u32| {
+            let deadline = Instant::now() + TIMEOUT;
+            while calls.load(Ordering::Relaxed) < target {
+                assert!(Instant::now() < deadline, "The spinning threads stalled");
+                thread::yield_now();
+            }
+        };
+
+        wait_for_calls(CALLS_AROUND_INIT);
+        assert_eq!(harness.call("init", &["sinks=recorder"]), "ok");
+        wait_for_calls(calls.load(Ordering::Relaxed) + CALLS_AROUND_INIT);
+        stop.store(true, Ordering::Relaxed);
+    });
+
+    // Every call ran the original exactly once, whether it went through the hook or not
+    assert_eq!(
+        harness.calls().exec_proc - before,
+        calls.load(Ordering::Relaxed)
+    );
+    // And the calls after init went through the hook
+    let path = temp_path("hook_install.json");
+    assert_eq!(harness.call("dump_flight_recorder", &[&path]), "ok");
+    let trace = fs::read_to_string(&path).unwrap();
+    assert!(trace.contains(&format!(
+        "\"ph\":\"B\",\"name\":\"{}\"",
+        PROC_PATHS[FAKE_LEAF as usize]
+    )));
+}
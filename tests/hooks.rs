@@ -0,0 +1,209 @@
+//! init end to end against the fake libbyond.so in tests/fake-byond, with no BYOND install.
+//!
+//! Needs an x86 or x86_64 Linux target and the fake-byond feature, which adds the fake's offsets. cargo
+//! test doesn't build the libraries it loads, so build them first:
+//! `cargo build --workspace --features fake-byond && cargo test --workspace --features fake-byond`
+
+#![cfg(all(
+    feature = "fake-byond",
+    target_os = "linux",
+    any(target_arch = "x86", target_arch = "x86_64")
+))]
+
+mod common;
+
+use std::{collections::BTreeSet, fs, sync::MutexGuard};
+
+use common::{
+    BYOND_NUMBER, EXEC_PROC_CALLS_PER_TICK, FAKE_CARELESS, FAKE_CURIOUS, FAKE_INSPECT, FAKE_LEAF,
+    FAKE_TICK, FAKE_WORK, FakeCalls, Harness, PROC_PATHS, pprof, temp_path,
+};
+
+fn harness() -> MutexGuard<'static, Harness> {
+    common::harness(&format!(
+        "sinks=chrome,recorder&chrome_trace={}&call_tree=true",
+        temp_path("hooks.json")
+    ))
+}
+
+#[test]
+fn init_is_only_performed_once() {
+    let harness = harness();
+
+    assert_eq!(harness.call("init", &[""]), "already initialized");
+}
+
+#[test]
+fn hooked_functions_still_run_their_originals() {
+    let harness = harness();
+
+    let before = harness.calls();
+    assert_eq!(harness.server_tick(), 1);
+    let after = harness.calls();
+
+    assert_eq!(
+        after,
+        FakeCalls {
+            exec_proc: before.exec_proc + EXEC_PROC_CALLS_PER_TICK,
+            server_tick: before.server_tick + 1,
+            send_maps: before.send_maps + 1,
+            runtime: before.runtime,
+        }
+    );
+}
+
+#[test]
+fn procs_outside_ticks_are_hooked() {
+    let harness = harness();
+
+    let before = harness.calls();
+    harness.exec_proc(FAKE_LEAF);
+
+    assert_eq!(harness.calls().exec_proc, before.exec_proc + 1);
+    assert_eq!(harness.call("get_callstack", &[]), "");
+}
+
+#[test]
+fn runtimes_still_reach_byond() {
+    let harness = harness();
+
+    let before = harness.calls();
+    harness.exec_proc(FAKE_CARELESS);
+
+    assert_eq!(harness.calls().runtime, before.runtime + 1);
+}
+
+#[test]
+fn call_tree_follows_nested_procs() {
+    let harness = harness();
+    // Stacks with under a microsecond of self time are left out, and the fake procs do next to nothing
+    for _ in 0..1000 {
+        harness.server_tick();
+    }
+
+    let path = temp_path("hooks.folded");
+    assert_eq!(harness.call("write_call_tree", &[&path]), "ok");
+    let folded = fs::read_to_string(&path).unwrap();
+
+    let stacks: Vec<&str> = folded
+        .lines()
+        .filter_map(|line| line.rsplit_once(' '))
+        .map(|(stack, _)| stack)
+        .collect();
+    for stack in [
+        PROC_PATHS[0].to_string(),
+        format!("{};{}", PROC_PATHS[0], PROC_PATHS[1]),
+        format!("{};{};{}", PROC_PATHS[0], PROC_PATHS[1], PROC_PATHS[2]),
+        format!("{};{}", PROC_PATHS[0], PROC_PATHS[2]),
+    ] {
+        assert!(
+            stacks.contains(&stack.as_str()),
+            "{} missing from {}",
+            stack,
+            folded
+        );
+    }
+}
+
+#[test]
+fn callstacks_are_innermost_first() {
+    let harness = harness();
+    let curious = PROC_PATHS[FAKE_CURIOUS as usize];
+    let inspect = PROC_PATHS[FAKE_INSPECT as usize];
+
+    assert_eq!(
+        harness.callstack_inside(FAKE_CURIOUS, false),
+        format!("{}\n{}", inspect, curious)
+    );
+    // A resumed proc has lost the callers it slept under, so it's the outermost
+    assert_eq!(
+        harness.callstack_inside(FAKE_CURIOUS, true),
+        format!("{}\n{} (resumed)", inspect, curious)
+    );
+    // And nothing is left over once the procs return
+    assert_eq!(harness.call("get_callstack", &[]), "");
+}
+
+#[test]
+fn pprof_profiles_hold_the_call_tree() {
+    let harness = harness();
+    harness.server_tick();
+
+    let path = temp_path("hooks.pb.gz");
+    assert_eq!(harness.call("write_pprof", &[&path]), "ok");
+    let profile = pprof::read(&path);
+
+    // One sample per call tree node, each naming its stack once
+    let stacks: BTreeSet<&[u64]> = profile
+        .samples
+        .iter()
+        .map(|sample| sample.location_ids.as_slice())
+        .collect();
+    assert_eq!(stacks.len(), profile.samples.len());
+    for sample in &profile.samples {
+        assert_eq!(sample.values.len(), 2);
+        assert!(sample.values[0] >= 1, "A node without calls");
+    }
+
+    // And one function and location per proc the samples reach, sharing the procdef + 1 as their ID
+    let reached: BTreeSet<u64> = stacks.iter().copied().flatten().copied().collect();
+    let location_ids: BTreeSet<u64> = profile.location_ids.iter().copied().collect();
+    let function_ids: BTreeSet<u64> = profile
+        .functions
+        .iter()
+        .map(|function| function.id)
+        .collect();
+    assert_eq!(profile.location_ids.len(), reached.len());
+    assert_eq!(location_ids, reached);
+    assert_eq!(profile.functions.len(), reached.len());
+    assert_eq!(function_ids, reached);
+
+    let id = |procdef: u32| u64::from(procdef) + 1;
+    for stack in [
+        vec![id(FAKE_TICK)],
+        vec![id(FAKE_WORK), id(FAKE_TICK)],
+        vec![id(FAKE_LEAF), id(FAKE_WORK), id(FAKE_TICK)],
+        vec![id(FAKE_LEAF), id(FAKE_TICK)],
+    ] {
+        assert!(
+            stacks.contains(stack.as_slice()),
+            "No sample for {:?}",
+            stack
+        );
+    }
+    for function in &profile.functions {
+        assert_eq!(function.name, PROC_PATHS[function.id as usize - 1]);
+    }
+}
+
+#[test]
+fn flight_recorder_names_proc_zones() {
+    let harness = harness();
+    harness.server_tick();
+
+    let path = temp_path("recorder.json");
+    assert_eq!(harness.call("dump_flight_recorder", &[&path, "60"]), "ok");
+    let trace = fs::read_to_string(&path).unwrap();
+
+    assert!(trace.starts_with('[') && trace.trim_end().ends_with(']'));
+    let tick_procs = &PROC_PATHS[..=FAKE_LEAF as usize];
+    for name in tick_procs.iter().chain(&["ServerTick", "SendMaps"]) {
+        assert!(
+            trace.contains(&format!("\"ph\":\"B\",\"name\":\"{}\"", name)),
+            "No zone named {} in {}",
+            name,
+            trace
+        );
+    }
+}
+
+#[test]
+fn native_errors_without_byondapi_are_not_null() {
+    let harness = harness();
+
+    // The fake exports no byondapi, so there's no making the error text
+    let result = harness.call_ext("tracy_zone_end_ext");
+
+    assert_eq!(result.value_type, BYOND_NUMBER);
+    assert_eq!(f32::from_bits(result.data), -1.0);
+}
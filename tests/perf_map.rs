@@ -0,0 +1,64 @@
+//! perf_map=true end to end against the fake libbyond.so. init only runs once per process, so this is
+//! its own test binary. It needs the same setup as hooks.rs, building the libraries before testing with
+//! the fake-byond feature.
+
+#![cfg(all(
+    feature = "fake-byond",
+    target_os = "linux",
+    any(target_arch = "x86", target_arch = "x86_64")
+))]
+
+mod common;
+
+use std::fs;
+
+use common::{EXEC_PROC_CALLS_PER_TICK, PROC_PATHS};
+
+#[test]
+fn perf_map_names_a_stub_per_proc() {
+    let harness = common::harness("perf_map=true&sinks=recorder");
+
+    // Procs are called through their stubs, so they have to still run
+    let before = harness.calls();
+    harness.server_tick();
+    assert_eq!(
+        harness.calls().exec_proc - before.exec_proc,
+        EXEC_PROC_CALLS_PER_TICK
+    );
+
+    let path = format!("/tmp/perf-{}.map", std::process::id());
+    let map = fs::read_to_string(&path).unwrap();
+    // Nothing needs it past the read, and perf would otherwise find it for an unrelated process
+    let _ = fs::remove_file(&path);
+    let stubs: Vec<(usize, usize, &str)> = map
+        .lines()
+        .map(|line| {
+            let mut fields = line.splitn(3, ' ');
+            let mut hex = || usize::from_str_radix(fields.next().unwrap(), 16).unwrap();
+            let (address, size) = (hex(), hex());
+            (address, size, fields.next().unwrap())
+        })
+        .collect();
+
+    let names: Vec<&str> = stubs.iter().map(|(_, _, name)| *name).collect();
+    assert_eq!(names, PROC_PATHS);
+
+    // Laid out back to back, one per procdef
+    let (first, size, _) = stubs[0];
+    assert!(size > 0);
+    for (procdef, (address, stub_size, _)) in stubs.iter().enumerate() {
+        assert_eq!((*address, *stub_size), (first + procdef * size, size));
+    }
+
+    // And in memory that's mapped executable
+    let maps = fs::read_to_string("/proc/self/maps").unwrap();
+    let executable = maps.lines().any(|line| {
+        let mut fields = line.split(' ');
+        let (range, permissions) = (fields.next().unwrap(), fields.next().unwrap());
+        let (start, end) = range.split_once('-').unwrap();
+        let start = usize::from_str_radix(start, 16).unwrap();
+        let end = usize::from_str_radix(end, 16).unwrap();
+        permissions.contains('x') && start <= first && first + stubs.len() * size <= end
+    });
+    assert!(executable, "The stubs at {:x} aren't executable", first);
+}
@@ -0,0 +1,81 @@
+//! Sampling mode end to end against the fake libbyond.so. init only runs once per process, so this is
+//! its own test binary. It needs the same setup as hooks.rs, building the libraries before testing with
+//! the fake-byond feature.
+
+#![cfg(all(
+    feature = "fake-byond",
+    target_os = "linux",
+    any(target_arch = "x86", target_arch = "x86_64")
+))]
+
+mod common;
+
+use std::{
+    fs,
+    sync::MutexGuard,
+    time::{Duration, Instant},
+};
+
+use common::{EXEC_PROC_CALLS_PER_TICK, Harness, PROC_PATHS, temp_path};
+
+/// The fake procs return almost immediately, so ticks keep running until the sampler catches one.
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+fn harness() -> MutexGuard<'static, Harness> {
+    common::harness("mode=sampling&sample_rate=100000&sinks=recorder")
+}
+
+#[test]
+fn samples_are_written_as_folded_stacks() {
+    let harness = harness();
+    let path = temp_path("samples.folded");
+    let [tick, work, leaf, ..] = PROC_PATHS;
+    let tick_stacks = [
+        tick.to_string(),
+        format!("{};{}", tick, work),
+        format!("{};{}", tick, leaf),
+        format!("{};{};{}", tick, work, leaf),
+    ];
+
+    let deadline = Instant::now() + TIMEOUT;
+    let samples = loop {
+        for _ in 0..100 {
+            harness.server_tick();
+        }
+        assert_eq!(harness.call("write_samples", &[&path]), "ok");
+        let samples = fs::read_to_string(&path).unwrap();
+        if !samples.is_empty() {
+            break samples;
+        }
+        assert!(Instant::now() < deadline, "Nothing was sampled");
+    };
+
+    for line in samples.lines() {
+        let (stack, count) = line.rsplit_once(' ').expect("No count on the line");
+        assert!(
+            tick_stacks.iter().any(|tick_stack| tick_stack == stack),
+            "{}",
+            line
+        );
+        assert!(count.parse::<u64>().unwrap() > 0, "{}", line);
+    }
+}
+
+#[test]
+fn proc_calls_are_still_counted() {
+    let harness = harness();
+    harness.server_tick();
+
+    let path = temp_path("sampling.json");
+    assert_eq!(harness.call("dump_flight_recorder", &[&path, "60"]), "ok");
+    let trace = fs::read_to_string(&path).unwrap();
+
+    assert!(
+        trace.contains(&format!(
+            "\"ph\":\"C\",\"name\":\"Proc Calls\",\"args\":{{\"value\":{}}}",
+            EXEC_PROC_CALLS_PER_TICK
+        )),
+        "{}",
+        trace
+    );
+}
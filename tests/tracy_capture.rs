@@ -0,0 +1,215 @@
+//! Checks what the tracy sink sends by capturing it with the minimal Tracy server in common/tracy.rs,
+//! driving the fake libbyond.so as hooks.rs does.
+//!
+//! Tracy serves one capture per process, so this is its own test binary. It needs the same setup as
+//! hooks.rs, building the libraries before testing with the fake-byond feature.
+
+#![cfg(all(
+    feature = "fake-byond",
+    target_os = "linux",
+    any(target_arch = "x86", target_arch = "x86_64")
+))]
+
+mod common;
+
+use std::{
+    ffi::c_void,
+    ptr::null_mut,
+    sync::{
+        Mutex, OnceLock,
+        atomic::{AtomicU32, Ordering},
+    },
+};
+
+use common::{
+    FAKE_CARELESS, FAKE_FAIL, FAKE_LEAF, FAKE_TICK, FAKE_WORK, Harness, PROC_PATHS,
+    tracy::{Capture, Captured, Zone},
+};
+
+/// Away from Tracy's default, so a profiled DreamDaemon on the same machine doesn't get in the way.
+const TRACY_PORT: u16 = 18086;
+
+const SERVER_TICK_COLOR: u32 = 0x112233;
+const SEND_MAPS_COLOR: u32 = 0x445566;
+/// Applies to work and leaf, fake_tick is left uncoloured.
+const FAKE_DATUM_COLOR: u32 = 0x336699;
+
+static CAPTURE: OnceLock<Mutex<Capture>> = OnceLock::new();
+
+unsafe extern "C" {
+    fn malloc(size: usize) -> *mut c_void;
+}
+
+/// Runs action and returns what Tracy sent while it ran.
+fn capture(action: impl FnOnce(&Harness)) -> Captured {
+    let harness = common::harness(&format!(
+        "sinks=tracy&port={}&color_by_type=false&colors=/datum/fake=#{:06X}&server_tick_color=#{:06X}&send_maps_color=#{:06X}",
+        TRACY_PORT, FAKE_DATUM_COLOR, SERVER_TICK_COLOR, SEND_MAPS_COLOR
+    ));
+    let mut capture = CAPTURE
+        .get_or_init(|| Mutex::new(Capture::connect(TRACY_PORT)))
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+
+    // Throws away anything an earlier test left behind
+    capture.read_until(&send_marker(&harness));
+    action(&harness);
+    capture.read_until(&send_marker(&harness))
+}
+
+fn send_marker(harness: &Harness) -> String {
+    static MARKERS: AtomicU32 = AtomicU32::new(0);
+
+    let marker = format!("Marker {}", MARKERS.fetch_add(1, Ordering::Relaxed));
+    assert_eq!(harness.call("tracy_message", &[&marker]), "ok");
+    marker
+}
+
+fn proc_zone(zone: &Zone, procdef: u32) -> &Zone {
+    let path = PROC_PATHS[procdef as usize];
+    zone.find(path)
+        .unwrap_or_else(|| panic!("No zone for {} in\n{}", path, zone.outline()))
+}
+
+#[test]
+fn ticks_nest_procs_in_call_order() {
+    let captured = capture(|harness| {
+        harness.server_tick();
+    });
+
+    let outlines: Vec<String> = captured.zones.iter().map(Zone::outline).collect();
+    assert_eq!(
+        outlines,
+        [concat!(
+            "ServerTick\n",
+            "  /proc/fake_tick\n",
+            "    /datum/fake/proc/work\n",
+            "      /datum/fake/proc/leaf\n",
+            "    /datum/fake/proc/work\n",
+            "      /datum/fake/proc/leaf\n",
+            "    /datum/fake/proc/leaf\n",
+            "  SendMaps\n",
+        )]
+    );
+}
+
+#[test]
+fn zones_take_their_configured_colors() {
+    let captured = capture(|harness| {
+        harness.server_tick();
+    });
+
+    let tick = &captured.zones[0];
+    assert_eq!(tick.color, SERVER_TICK_COLOR);
+    assert_eq!(tick.find("SendMaps").unwrap().color, SEND_MAPS_COLOR);
+    assert_eq!(proc_zone(tick, FAKE_TICK).color, 0);
+    assert_eq!(proc_zone(tick, FAKE_WORK).color, FAKE_DATUM_COLOR);
+    assert_eq!(proc_zone(tick, FAKE_LEAF).color, FAKE_DATUM_COLOR);
+}
+
+#[test]
+fn every_tick_marks_a_frame() {
+    let captured = capture(|harness| {
+        for _ in 0..3 {
+            harness.server_tick();
+        }
+        assert_eq!(harness.call("tracy_frame_mark", &["Fake frames"]), "ok");
+    });
+
+    assert_eq!(
+        captured.frames,
+        [None, None, None, Some("Fake frames".to_string())]
+    );
+    assert_eq!(captured.zones.len(), 3);
+}
+
+#[test]
+fn procs_outside_ticks_are_outermost_zones() {
+    let captured = capture(|harness| harness.exec_proc(FAKE_WORK));
+
+    let outlines: Vec<String> = captured.zones.iter().map(Zone::outline).collect();
+    assert_eq!(
+        outlines,
+        [concat!(
+            "/datum/fake/proc/work\n",
+            "  /datum/fake/proc/leaf\n",
+        )]
+    );
+}
+
+#[test]
+fn dm_zones_are_named_and_nest() {
+    let captured = capture(|harness| {
+        assert_eq!(harness.call("tracy_zone_begin", &["Outer"]), "ok");
+        harness.exec_proc(FAKE_LEAF);
+        assert_eq!(harness.call("tracy_zone_begin", &["Inner"]), "ok");
+        assert_eq!(harness.call("tracy_zone_end", &[]), "ok");
+        assert_eq!(harness.call("tracy_zone_end", &[]), "ok");
+    });
+
+    let outlines: Vec<String> = captured.zones.iter().map(Zone::outline).collect();
+    assert_eq!(
+        outlines,
+        [concat!("Outer\n", "  /datum/fake/proc/leaf\n", "  Inner\n")]
+    );
+}
+
+#[test]
+fn byond_allocations_reach_the_memory_profiler() {
+    let mut block = null_mut();
+    let captured = capture(|harness| {
+        block = harness.malloc(48);
+        // SAFETY: Allocated just above
+        unsafe { harness.free(block) };
+    });
+
+    assert_eq!(captured.allocations, [(block as u64, 48)]);
+    assert_eq!(captured.frees, [block as u64]);
+}
+
+#[test]
+fn blocks_allocated_before_init_are_freed_silently() {
+    // Never seen by the malloc hook, like everything BYOND allocated before init
+    // SAFETY: Any size may be asked for
+    let block = unsafe { malloc(48) };
+    assert!(!block.is_null());
+
+    // SAFETY: From the C heap, and not yet freed
+    let captured = capture(|harness| unsafe { harness.free(block) });
+
+    assert_eq!(captured.frees, []);
+}
+
+#[test]
+fn ticks_plot_the_object_tables() {
+    let captured = capture(|harness| {
+        harness.set_object_counts(12, 34);
+        harness.server_tick();
+    });
+
+    let plot = |name: &str| {
+        captured
+            .plots
+            .iter()
+            .find(|(plot, _)| plot == name)
+            .map(|(_, value)| *value)
+    };
+    assert_eq!(plot("Datums"), Some(12.0));
+    assert_eq!(plot("Lists"), Some(34.0));
+}
+
+#[test]
+fn runtimes_are_red_messages_naming_their_callers() {
+    let captured = capture(|harness| harness.exec_proc(FAKE_CARELESS));
+
+    assert_eq!(
+        captured.messages,
+        [(
+            format!(
+                "Runtime in {}: Division by zero\n  called by {}",
+                PROC_PATHS[FAKE_FAIL as usize], PROC_PATHS[FAKE_CARELESS as usize]
+            ),
+            0xFF0000
+        )]
+    );
+}